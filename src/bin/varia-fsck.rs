@@ -0,0 +1,279 @@
+use std::{env, fs::{self, File, OpenOptions}, io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, process::ExitCode, collections::HashSet};
+
+use varia_db::store::{Cipher, Corruption, FileHeader, lock_data, frame::{self, Frame, FrameKind, FrameReader}};
+
+const USAGE: &str = "Usage: varia-fsck <file> [--key-file <key>] [--salvage <output>]";
+
+#[derive(Default)]
+struct Report {
+    entries: u64,
+    live_bytes: u64,
+    gap_frames: u64,
+    gap_bytes: u64,
+    errors: u64,
+    skipped_bytes: u64,
+    compressed: u64,
+    saved_bytes: u64,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (path, options) = match args.split_first() {
+        Some((path, options)) if options.len() % 2 == 0 => (PathBuf::from(path), options),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut salvage: Option<PathBuf> = None;
+    let mut cipher: Option<Cipher> = None;
+    for option in options.chunks(2) {
+        match option[0].as_str() {
+            "--salvage" => salvage = Some(PathBuf::from(&option[1])),
+            "--key-file" => match Cipher::from_key_file(Path::new(&option[1])) {
+                Ok(key) => cipher = Some(key),
+                Err(e) => {
+                    eprintln!("Failed to read key file {:?}: {}", option[1], e);
+                    return ExitCode::from(2);
+                }
+            },
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    match run(&path, salvage.as_deref(), cipher.as_ref()) {
+        Ok(report) if report.errors == 0 => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Failed to check {:?}: {}", path, e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(path: &Path, salvage: Option<&Path>, cipher: Option<&Cipher>) -> Result<Report, Error> {
+    let mut wal_name = path.file_name().unwrap_or_default().to_os_string();
+    wal_name.push(".wal");
+    if fs::metadata(path.with_file_name(wal_name)).map(|m| m.len() > 0).unwrap_or(false) {
+        println!("WARNING: write-ahead log is not empty, open the file with varia-db first to replay it");
+    }
+
+    let mut file = File::open(path)?;
+    // A salvage has to see the data file as a whole, not while the server
+    // writes to it.
+    let _lock = salvage.map(|_| lock_data(path)).transpose()?;
+    let file_len = file.metadata()?.len();
+
+    let mut buf: [u8; 16] = [0; 16];
+    file.read_exact(&mut buf)?;
+    let header = FileHeader::from_bytes(buf)?;
+    header.check_supported()?;
+    println!("Format version {} with flags {:#06x}", header.version, header.flags);
+
+    if header.is_encrypted() {
+        match cipher {
+            None => println!("WARNING: data file is encrypted, pass --key-file to check entries"),
+            Some(cipher) if cipher.key_check() != header.key_check => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Wrong encryption key for data file"));
+            },
+            Some(_) => {},
+        }
+    }
+    // Salvaged entries are only encrypted if the data file was.
+    let cipher = cipher.filter(|_| header.is_encrypted());
+    if salvage.is_some() && header.is_encrypted() && cipher.is_none() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Salvaging an encrypted data file needs its key"));
+    }
+
+    let mut writer = match salvage {
+        Some(output) => {
+            let file = OpenOptions::new().create_new(true).write(true).open(output)?;
+            let mut writer = BufWriter::new(file);
+            let header = match cipher {
+                Some(cipher) => FileHeader::encrypted(cipher.key_check()),
+                None => FileHeader::current(),
+            };
+            writer.write_all(&header.to_bytes())?;
+            Some(writer)
+        },
+        None => None,
+    };
+    let mut salvaged: HashSet<String> = HashSet::new();
+
+    let mut report = Report::default();
+    let mut frames = FrameReader::new(file, file_len, header.version)?;
+
+    println!("{:>12}  {:<14}  {:>10}  DETAIL", "OFFSET", "KIND", "SIZE");
+
+    loop {
+        let offset = frames.offset();
+        let frame = match frames.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                report.errors += 1;
+                print_error(offset, &e);
+
+                drop(frames);
+                let mut file = File::open(path)?;
+                let next = resync(&mut file, offset, file_len, header.version)?;
+                report.skipped_bytes += next - offset;
+                println!("{:>12}  {:<14}  {:>10}  skipped", offset, "unreadable", next - offset);
+
+                frames = FrameReader::new(file, file_len, header.version)?;
+                frames.seek(next)?;
+                continue;
+            }
+        };
+
+        match frame {
+            Frame::Gap(header) => {
+                report.gap_frames += 1;
+                report.gap_bytes += header.len;
+                let kind = if header.kind == FrameKind::BigGap { "big gap" } else { "short gap" };
+                println!("{:>12}  {:<14}  {:>10}", header.offset, kind, header.len);
+            },
+            Frame::Entry(entry) => {
+                let header = entry.header;
+                let kind = match header.kind {
+                    FrameKind::CompactEntry => "entry",
+                    FrameKind::CheckedEntry => "v1 entry",
+                    _ => "legacy entry",
+                };
+
+                report.entries += 1;
+                report.live_bytes += header.len;
+
+                if entry.is_encrypted() && cipher.is_none() {
+                    println!("{:>12}  {:<14}  {:>10}  key encrypted", header.offset, kind, header.len);
+                    if !entry.intact {
+                        report.errors += 1;
+                        print_error(header.offset, &Corruption::error(header.offset, "Checksum mismatch"));
+                    }
+                    continue;
+                }
+                let entry = if entry.intact {
+                    match entry.decrypt(cipher) {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            println!("{:>12}  {:<14}  {:>10}  key unreadable", header.offset, kind, header.len);
+                            report.errors += 1;
+                            print_error(header.offset, &e);
+                            continue;
+                        }
+                    }
+                } else {
+                    entry
+                };
+
+                if entry.is_compressed() {
+                    report.compressed += 1;
+                    report.saved_bytes += entry.saved_bytes();
+                }
+
+                let key = entry.key();
+                let mut detail = match &key {
+                    Ok(key) => format!("key {:?}", key),
+                    Err(_) => "key unreadable".to_string(),
+                };
+                if entry.is_compressed() {
+                    detail.push_str(", compressed");
+                }
+                if let Some(expires_at) = entry.expires_at() {
+                    detail.push_str(&format!(", expires at {}", expires_at));
+                }
+                println!("{:>12}  {:<14}  {:>10}  {}", header.offset, kind, header.len, detail);
+
+                if !entry.intact {
+                    report.errors += 1;
+                    print_error(header.offset, &Corruption::error(header.offset, "Checksum mismatch"));
+                    continue;
+                }
+                let key = match key {
+                    Ok(key) => key,
+                    Err(e) => {
+                        report.errors += 1;
+                        print_error(header.offset, &e);
+                        continue;
+                    }
+                };
+                let value = match entry.value() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        report.errors += 1;
+                        print_error(header.offset, &e);
+                        continue;
+                    }
+                };
+
+                if let Some(writer) = writer.as_mut() {
+                    if salvaged.insert(key.clone()) {
+                        writer.write_all(&frame::encode_entry_frame(&key, &value, entry.expires_at(), None, cipher)?.0)?;
+                    }
+                }
+            },
+        }
+    }
+
+    let fragmentation = if report.live_bytes + report.gap_bytes > 0 {
+        100.0 * report.gap_bytes as f64 / (report.live_bytes + report.gap_bytes) as f64
+    } else {
+        0.0
+    };
+
+    println!("--------------------------------------");
+    println!("Entries:        {}", report.entries);
+    println!("Live bytes:     {}", report.live_bytes);
+    println!("Gap frames:     {}", report.gap_frames);
+    println!("Gap bytes:      {}", report.gap_bytes);
+    println!("Fragmentation:  {:.2}%", fragmentation);
+    println!("Compressed:     {}", report.compressed);
+    println!("Saved bytes:    {}", report.saved_bytes);
+    println!("Skipped bytes:  {}", report.skipped_bytes);
+    println!("Errors:         {}", report.errors);
+
+    if let (Some(writer), Some(output)) = (writer, salvage) {
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        println!("Salvaged {} entries into {:?}", salvaged.len(), output);
+    }
+
+    Ok(report)
+}
+
+fn print_error(offset: u64, error: &Error) {
+    match Corruption::find(error) {
+        Some(corruption) => println!("ERROR: {}", corruption),
+        None => println!("ERROR: Frame at offset {}: {}", offset, error),
+    }
+}
+
+/// Finds the next offset behind `offset` that holds an intact checksummed
+/// entry frame, or the end of the file.
+fn resync<R: Read + Seek>(reader: R, offset: u64, file_len: u64, version: u8) -> Result<u64, Error> {
+    let mut reader = BufReader::new(reader);
+    reader.seek(SeekFrom::Start(offset))?;
+
+    for candidate in offset + 1..file_len {
+        // Step to the candidate within the read-ahead buffer, wherever the
+        // previous attempt stopped reading.
+        let position = reader.stream_position()?;
+        reader.seek_relative(candidate as i64 - position as i64)?;
+
+        let header = match frame::read_frame_header(&mut reader, candidate, file_len, version) {
+            Ok(Some(header)) if header.is_checked() => header,
+            _ => continue,
+        };
+        if let Ok(entry) = frame::read_entry_frame(&mut reader, header) {
+            if entry.intact {
+                return Ok(candidate);
+            }
+        }
+    }
+    Ok(file_len)
+}
@@ -0,0 +1,68 @@
+use std::{env, io::{Error, ErrorKind}, path::{Path, PathBuf}, process::ExitCode};
+
+use varia_db::store::{Cipher, DataDir, Disk, lock_data};
+
+const USAGE: &str = "Usage: varia-rekey <directory or file> [--key-file <key>] [--new-key-file <key>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (path, options) = match args.split_first() {
+        Some((path, options)) if options.len() % 2 == 0 => (PathBuf::from(path), options),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut key_file: Option<PathBuf> = None;
+    let mut new_key_file: Option<PathBuf> = None;
+    for option in options.chunks(2) {
+        match option[0].as_str() {
+            "--key-file" => key_file = Some(PathBuf::from(&option[1])),
+            "--new-key-file" => new_key_file = Some(PathBuf::from(&option[1])),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    match run(&path, key_file.as_deref(), new_key_file.as_deref()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to rekey {:?}: {}", path, e);
+            ExitCode::from(1)
+        }
+    }
+}
+
+/// Opens the data directory or data file with the key in `key_file` and
+/// re-encrypts it with the key in `new_key_file`. Without a current key plain
+/// data gets encrypted, without a new key the data gets decrypted. Fails if
+/// the server or another process holds the data.
+fn run(path: &Path, key_file: Option<&Path>, new_key_file: Option<&Path>) -> Result<(), Error> {
+    if !path.exists() {
+        return Err(Error::new(ErrorKind::NotFound, "Data file does not exist"));
+    }
+    let _lock = lock_data(path)?;
+    let cipher = key_file.map(Cipher::from_key_file).transpose()?;
+    let new_cipher = new_key_file.map(Cipher::from_key_file).transpose()?;
+
+    if path.is_dir() {
+        let mut data_dir = match cipher {
+            Some(cipher) => DataDir::new_encrypted(path, cipher)?,
+            None => DataDir::new(path)?,
+        };
+        data_dir.rekey(new_cipher)?;
+    } else {
+        let mut disk = match cipher {
+            Some(cipher) => Disk::new_encrypted(path, cipher)?,
+            None => Disk::new(path)?,
+        };
+        disk.rekey(new_cipher)?;
+    }
+
+    println!("Rekeyed {:?}", path);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{env, io::{Error, ErrorKind, Read, Write}, net::TcpStream, process::ExitCode};
+
+const USAGE: &str = "Usage: varia-snapshot [--url <url>]";
+
+const DEFAULT_URL: &str = "http://localhost:8654";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut url = DEFAULT_URL.to_string();
+    for option in args.chunks(2) {
+        match option {
+            [name, value] if name == "--url" => url = value.clone(),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    match run(&url) {
+        Ok(path) => {
+            println!("Snapshot written to {}", path);
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("Failed to take snapshot of {}: {}", url, e);
+            ExitCode::from(1)
+        }
+    }
+}
+
+/// Asks the server at `url` for a snapshot and returns the path the server
+/// wrote it to.
+fn run(url: &str) -> Result<String, Error> {
+    let authority = url.strip_prefix("http://")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Only http:// URLs are supported"))?
+        .trim_end_matches('/');
+    if authority.is_empty() || authority.contains('/') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid URL: {}", url)));
+    }
+
+    let mut stream = TcpStream::connect(authority)?;
+    write!(stream, "POST /snapshot HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", authority)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid HTTP response"))?;
+    let status = head.split(' ').nth(1)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid HTTP response"))?;
+    if status != "200" {
+        return Err(Error::other(format!("Server responded {}: {}", status, body)));
+    }
+
+    let respond: serde_json::Value = serde_json::from_str(body)?;
+    respond.pointer("/Value/Text")
+        .and_then(|path| path.as_str())
+        .map(|path| path.to_string())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unexpected respond: {}", body)))
+}
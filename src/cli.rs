@@ -0,0 +1,77 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write}, path::{Path, PathBuf}};
+
+use crate::store::{Engine, ConflictPolicy, ImportReport, EXPORT_BATCH, read_records};
+
+pub const USAGE: &str = "Usage: varia-db [export [--prefix <prefix>] [--output <file>] | import [--on-conflict overwrite|skip|fail] [--input <file>]]";
+
+/// What the `varia-db` binary was started to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Serve,
+    Export {
+        prefix: Option<String>,
+        output: Option<PathBuf>,
+    },
+    Import {
+        policy: ConflictPolicy,
+        input: Option<PathBuf>,
+    },
+}
+
+impl Command {
+
+    /// Reads the command from the arguments the binary was started with,
+    /// without the binary itself.
+    pub fn parse(args: &[String]) -> Result<Self, Error> {
+        let (mode, options) = match args.split_first() {
+            None => return Ok(Self::Serve),
+            Some((mode, options)) => (mode.as_str(), options),
+        };
+
+        let mut command = match mode {
+            "export" => Self::Export { prefix: None, output: None },
+            "import" => Self::Import { policy: ConflictPolicy::Overwrite, input: None },
+            _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+        };
+        for option in options.chunks(2) {
+            match (&mut command, option) {
+                (Self::Export { prefix, .. }, [name, value]) if name == "--prefix" => *prefix = Some(value.clone()),
+                (Self::Export { output, .. }, [name, value]) if name == "--output" => *output = Some(PathBuf::from(value)),
+                (Self::Import { policy, .. }, [name, value]) if name == "--on-conflict" => *policy = value.parse()?,
+                (Self::Import { input, .. }, [name, value]) if name == "--input" => *input = Some(PathBuf::from(value)),
+                _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+            }
+        }
+        Ok(command)
+    }
+}
+
+/// Writes every entry, or those whose key starts with `prefix`, as NDJSON to
+/// `output` or to stdout. Returns the number of entries written.
+pub async fn export(engine: &Engine, prefix: Option<&str>, output: Option<&Path>) -> Result<usize, Error> {
+    let mut writer: BufWriter<Box<dyn Write>> = match output {
+        Some(path) => BufWriter::new(Box::new(File::create(path)?)),
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
+
+    let mut entries: usize = 0;
+    let keys = engine.export_keys(prefix).await?;
+    for batch in keys.chunks(EXPORT_BATCH) {
+        let chunk = engine.export(batch).await?;
+        entries += chunk.iter().filter(|byte| **byte == b'\n').count();
+        writer.write_all(&chunk)?;
+    }
+    writer.flush()?;
+    Ok(entries)
+}
+
+/// Reads NDJSON from `input` or from stdin and stores every entry, deciding
+/// by `policy` what happens to keys that already exist.
+pub async fn import(engine: &Engine, policy: ConflictPolicy, input: Option<&Path>) -> Result<ImportReport, Error> {
+    let reader: Box<dyn Read> = match input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin().lock()),
+    };
+    let records = read_records(BufReader::new(reader))?;
+    engine.import(records, policy).await
+}
@@ -1,3 +1,4 @@
 pub mod server;
 pub mod store;
-pub mod setup;
\ No newline at end of file
+pub mod setup;
+pub mod cli;
\ No newline at end of file
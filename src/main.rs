@@ -1,29 +1,100 @@
 pub mod store;
 pub mod server;
 pub mod setup;
+pub mod cli;
+
+use std::{env, process::exit};
+
+use cli::Command;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match Command::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+
     let configuration = setup::Configuration::new();
 
+    let _lock = match setup::setup_lock(&configuration) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Failed to lock {}: {}", configuration.data_dir, e);
+            exit(1);
+        }
+    };
+
+    match command {
+        Command::Serve => serve(configuration).await,
+        Command::Export { prefix, output } => {
+            // Log lines would end up in the export if it goes to stdout.
+            if output.is_some() {
+                setup::setup_log(configuration.log_level);
+            }
+            let engine = setup_offline_engine(&configuration);
+            match cli::export(&engine, prefix.as_deref(), output.as_deref()).await {
+                Ok(entries) => eprintln!("Exported {} entries", entries),
+                Err(e) => {
+                    eprintln!("Failed to export: {}", e);
+                    exit(1);
+                }
+            }
+        },
+        Command::Import { policy, input } => {
+            setup::setup_log(configuration.log_level);
+            let engine = setup_offline_engine(&configuration);
+            match cli::import(&engine, policy, input.as_deref()).await {
+                Ok(report) => eprintln!("Imported {} entries, skipped {}", report.imported, report.skipped),
+                Err(e) => {
+                    eprintln!("Failed to import: {}", e);
+                    exit(1);
+                }
+            }
+        },
+    }
+}
+
+async fn serve(configuration: setup::Configuration) {
     setup::start_log(&configuration);
 
     setup::setup_log(configuration.log_level);
 
-    let secondary = setup::setup_secondary(
-        configuration.data_dir
-    );
+    let mut secondary = setup::setup_secondary(&configuration);
+
+    setup::setup_restore(&mut secondary, &configuration);
+
     let primary = setup::setup_primary(
         configuration.cache_size,
-        configuration.cache_ttl,
-        configuration.cache_tti
+        configuration.pinned_size,
+        setup::setup_cache_policy(&configuration)
     );
 
-    let engine = setup::setup_engine(secondary, primary);
+    let mut engine = setup::setup_engine(secondary, primary, configuration.storage_queue);
+
+    setup::setup_write_back(&mut engine, &configuration);
+    setup::setup_reaper(&mut engine, &configuration);
 
-    let engine_service = setup::setup_engine_service(engine, configuration.cors_allowed_origins);
+    let engine_service = setup::setup_engine_service(engine.clone(), configuration.cors_allowed_origins, configuration.snapshot_dir);
 
     let web_server = setup::setup_web_server(engine_service, configuration.port).await;
 
     web_server.run().await;
-}
\ No newline at end of file
+
+    setup::shutdown_engine(&engine).await;
+}
+
+/// Opens the storage for export and import while the server is stopped, which
+/// the lock taken in `main` makes sure of.
+fn setup_offline_engine(configuration: &setup::Configuration) -> store::Engine {
+    let secondary = setup::setup_secondary(configuration);
+    let primary = setup::setup_primary(
+        configuration.cache_size,
+        configuration.pinned_size,
+        setup::setup_cache_policy(configuration)
+    );
+    setup::setup_engine(secondary, primary, configuration.storage_queue)
+}
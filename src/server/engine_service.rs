@@ -1,17 +1,18 @@
-use std::{pin::Pin, future::Future, sync::Arc};
+use std::{pin::Pin, future::Future, sync::Arc, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
 
 
-use http_body_util::Full;
-use hyper::{body::{Bytes, Incoming}, service::Service, Error as HyperError, Request as HttpRequest, Response as HttpResponse, Method};
+use hyper::{body::Incoming, service::Service, Error as HyperError, Request as HttpRequest, Response as HttpResponse, Method};
+use log::error;
+use tokio::sync::mpsc;
 
-use crate::store::Engine;
+use crate::store::{Engine, Value, Condition, ConflictPolicy, EXPORT_BATCH, read_records};
 
 use super::{
-    GetPathing, put_pathing, get_pathing, del_pathing, 
-    Respond,
+    GetPathing, PostPathing, put_pathing, get_pathing, del_pathing, post_pathing, ttl_query, condition_query,
+    Respond, CompareAndSwap,
 
-    http_request_to_bytes, bytes_to_http_response, text_to_http_response, http_request_validate_cors, cors_preflight_http_response,
-    bytes_to_deserialized_value, serialized_respond_to_bytes
+    ResponseBody, http_request_to_bytes, ndjson_stream_to_http_response, bytes_to_http_response, text_to_http_response, error_to_http_response, http_request_validate_cors, cors_preflight_http_response,
+    bytes_to_deserialized_value, bytes_to_deserialized_compare_and_swap, serialized_respond_to_bytes
 };
 
 
@@ -19,6 +20,7 @@ use super::{
 pub struct EngineService {
     engine: Arc<Engine>,
     cors_allowed_origins: Vec<String>,
+    snapshot_dir: Option<PathBuf>,
 }
 
 impl EngineService {
@@ -26,13 +28,19 @@ impl EngineService {
         Self {
             engine: Arc::new(engine),
             cors_allowed_origins,
+            snapshot_dir: None,
         }
     }
+
+    /// Enables `POST /snapshot`, which writes snapshots into `snapshot_dir`.
+    pub fn set_snapshot_dir(&mut self, snapshot_dir: Option<PathBuf>) {
+        self.snapshot_dir = snapshot_dir;
+    }
 }
 
 
 impl Service<HttpRequest<Incoming>> for EngineService {
-    type Response = HttpResponse<Full<Bytes>>;
+    type Response = HttpResponse<ResponseBody>;
 
     type Error = HyperError;
 
@@ -42,6 +50,7 @@ impl Service<HttpRequest<Incoming>> for EngineService {
         
         let cors_allowed_origins = self.cors_allowed_origins.clone();
         let engine = self.engine.clone();
+        let snapshot_dir = self.snapshot_dir.clone();
         
         Box::pin(async move {
 
@@ -56,20 +65,31 @@ impl Service<HttpRequest<Incoming>> for EngineService {
             
             let method = req.method().clone();
             let path = req.uri().path().to_string();
+            let query = req.uri().query().map(str::to_string);
             let bytes = http_request_to_bytes(req).await;
 
-            return match method {
+            match method {
                 Method::OPTIONS => {
                     Ok(cors_preflight_http_response(cors_allowed_origins))
                 },
                 Method::PUT => {
                     let key = put_pathing(path);
                     let value = bytes_to_deserialized_value(bytes);
+                    let ttl = ttl_query(query.as_deref());
+                    let condition = condition_query(query.as_deref());
 
                     if let Err(e) = key {
                         return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
                     }
 
+                    if let Err(e) = ttl {
+                        return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                    }
+
+                    if let Err(e) = condition {
+                        return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                    }
+
                     if let Err(e) = value {
                         return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
                     }
@@ -77,10 +97,13 @@ impl Service<HttpRequest<Incoming>> for EngineService {
                     let key = key.unwrap();
                     let value = value.unwrap();
 
-                    let result = engine.put(key, value).await;
+                    let result = match condition.unwrap() {
+                        Some(condition) => engine.put_if(key, value, ttl.unwrap(), condition).await,
+                        None => engine.put_with_ttl(key, value, ttl.unwrap()).await,
+                    };
 
                     if let Err(e) = result {
-                        return Ok(text_to_http_response(e.to_string(), 500, cors_allowed_origins));
+                        return Ok(error_to_http_response(e, cors_allowed_origins));
                     }
 
                     let respond = Respond::Value(result.unwrap());
@@ -102,7 +125,7 @@ impl Service<HttpRequest<Incoming>> for EngineService {
                             let result = engine.get(key).await;
 
                             if let Err(e) = result {
-                                return Ok(text_to_http_response(e.to_string(), 500, cors_allowed_origins));
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
                             }
 
                             let respond = Respond::Value(result.unwrap());
@@ -113,12 +136,57 @@ impl Service<HttpRequest<Incoming>> for EngineService {
                             let result = engine.list().await;
 
                             if let Err(e) = result {
-                                return Ok(text_to_http_response(e.to_string(), 500, cors_allowed_origins));
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
                             }
 
                             let respond = Respond::Array(result.unwrap());
                             
                             Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
+                        },
+                        GetPathing::Stats => {
+                            let stats = engine.stats().await;
+
+                            let respond = Respond::Value(Some(Value::Map(vec![
+                                ("hits".to_string(), Value::Number(stats.hits as i128)),
+                                ("misses".to_string(), Value::Number(stats.misses as i128)),
+                                ("evictions".to_string(), Value::Number(stats.evictions as i128)),
+                                ("expirations".to_string(), Value::Number(stats.expirations as i128)),
+                                ("entries".to_string(), Value::Number(stats.entries as i128)),
+                                ("pinned".to_string(), Value::Number(stats.pinned as i128)),
+                                ("bytes".to_string(), Value::Number(stats.bytes as i128)),
+                                ("pinned_bytes".to_string(), Value::Number(stats.pinned_bytes as i128)),
+                                ("pinned_capacity".to_string(), Value::Number(stats.pinned_capacity as i128)),
+                                ("pinned_evictions".to_string(), Value::Number(stats.pinned_evictions as i128)),
+                                ("unflushed".to_string(), Value::Number(stats.unflushed as i128)),
+                                ("unflushed_bytes".to_string(), Value::Number(stats.unflushed_bytes as i128)),
+                            ])));
+
+                            Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
+                        },
+                        GetPathing::Export(prefix) => {
+                            let keys = engine.export_keys(prefix.as_deref()).await;
+
+                            if let Err(e) = keys {
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
+                            }
+
+                            let keys = keys.unwrap();
+
+                            let (sender, receiver) = mpsc::channel(4);
+                            tokio::task::spawn(async move {
+                                for batch in keys.chunks(EXPORT_BATCH) {
+                                    let chunk = engine.export(batch).await;
+                                    if let Err(e) = &chunk {
+                                        error!("Export failed: {}", e);
+                                    }
+                                    let failed = chunk.is_err();
+                                    if sender.send(chunk).await.is_err() || failed {
+                                        break;
+                                    }
+                                }
+                            });
+
+                            Ok(ndjson_stream_to_http_response(receiver, cors_allowed_origins))
                         }
                     }
                 },
@@ -135,13 +203,105 @@ impl Service<HttpRequest<Incoming>> for EngineService {
                         let result = engine.del(key).await;
 
                         if let Err(e) = result {
-                            return Ok(text_to_http_response(e.to_string(), 500, cors_allowed_origins));
+                            return Ok(error_to_http_response(e, cors_allowed_origins));
                         }
 
                         let respond = Respond::Value(result.unwrap());
 
                         Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
                 },
+                Method::POST => {
+                    let pathing = post_pathing(path);
+
+                    if let Err(e) = pathing {
+                        return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                    }
+
+                    match pathing.unwrap() {
+                        PostPathing::Snapshot => {
+                            let snapshot_dir = match snapshot_dir {
+                                Some(snapshot_dir) => snapshot_dir,
+                                None => {
+                                    return Ok(text_to_http_response("Snapshots are disabled, set SNAPSHOT_DIR".to_string(), 404, cors_allowed_origins));
+                                }
+                            };
+
+                            let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                            let snapshot = snapshot_dir.join(format!("snapshot-{}.bin", millis));
+
+                            let result = engine.snapshot(&snapshot).await;
+
+                            if let Err(e) = result {
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
+                            }
+
+                            let respond = Respond::Value(Some(Value::Text(snapshot.to_string_lossy().to_string())));
+
+                            Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
+                        },
+                        PostPathing::Import(policy) => {
+                            let policy = policy.map(|policy| policy.parse::<ConflictPolicy>()).unwrap_or(Ok(ConflictPolicy::Overwrite));
+
+                            if let Err(e) = policy {
+                                return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                            }
+
+                            let records = read_records(bytes.as_slice());
+
+                            if let Err(e) = records {
+                                return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                            }
+
+                            let result = engine.import(records.unwrap(), policy.unwrap()).await;
+
+                            if let Err(e) = result {
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
+                            }
+
+                            let report = result.unwrap();
+                            let respond = Respond::Value(Some(Value::Map(vec![
+                                ("imported".to_string(), Value::Number(report.imported as i128)),
+                                ("skipped".to_string(), Value::Number(report.skipped as i128)),
+                            ])));
+
+                            Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
+                        },
+                        PostPathing::CompareAndSwap(key) => {
+                            let ttl = ttl_query(query.as_deref());
+
+                            if let Err(e) = ttl {
+                                return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                            }
+
+                            let swap = bytes_to_deserialized_compare_and_swap(bytes);
+
+                            if let Err(e) = swap {
+                                return Ok(text_to_http_response(e.to_string(), 400, cors_allowed_origins));
+                            }
+
+                            let result = match swap.unwrap() {
+                                CompareAndSwap { expected, value: Some(value) } => {
+                                    let condition = expected.map(Condition::Equals).unwrap_or(Condition::Absent);
+                                    engine.put_if(key, value, ttl.unwrap(), condition).await
+                                },
+                                CompareAndSwap { expected: Some(expected), value: None } => {
+                                    engine.del_if(key, expected).await
+                                },
+                                CompareAndSwap { expected: None, value: None } => {
+                                    return Ok(text_to_http_response("Either expected or value must be set".to_string(), 400, cors_allowed_origins));
+                                },
+                            };
+
+                            if let Err(e) = result {
+                                return Ok(error_to_http_response(e, cors_allowed_origins));
+                            }
+
+                            let respond = Respond::Value(result.unwrap());
+
+                            Ok(bytes_to_http_response(serialized_respond_to_bytes(respond), 200, cors_allowed_origins))
+                        }
+                    }
+                },
                 _ => {
                     Ok(text_to_http_response("Method not allowed".to_string(), 405, cors_allowed_origins))
                 }
@@ -7,11 +7,11 @@ pub use web_server::WebServer;
 pub use engine_service::EngineService;
 
 use protocol::{
-    GetPathing, put_pathing, get_pathing, del_pathing,
-    Respond
+    GetPathing, PostPathing, put_pathing, get_pathing, del_pathing, post_pathing, ttl_query, condition_query,
+    Respond, CompareAndSwap
 };
 
 use utils::{
-    http_request_to_bytes, bytes_to_http_response, text_to_http_response, http_request_validate_cors, cors_preflight_http_response,
-    bytes_to_deserialized_value, serialized_respond_to_bytes
+    ResponseBody, http_request_to_bytes, ndjson_stream_to_http_response, bytes_to_http_response, text_to_http_response, error_to_http_response, http_request_validate_cors, cors_preflight_http_response,
+    bytes_to_deserialized_value, bytes_to_deserialized_compare_and_swap, serialized_respond_to_bytes
 };
@@ -1,8 +1,10 @@
 mod pathing;
 mod respond;
+mod request;
 
 pub use pathing::{
-    GetPathing, put_pathing, get_pathing, del_pathing
+    GetPathing, PostPathing, put_pathing, get_pathing, del_pathing, post_pathing, ttl_query, condition_query
 };
 
-pub use respond::Respond;
\ No newline at end of file
+pub use respond::Respond;
+pub use request::CompareAndSwap;
\ No newline at end of file
@@ -1,4 +1,6 @@
-use std::io::{Error, ErrorKind};
+use std::{io::{Error, ErrorKind}, time::Duration};
+
+use crate::store::Condition;
 
 pub fn put_pathing(path: String) -> Result<String, Error> {
     let segments = path.split("/");
@@ -25,6 +27,8 @@ pub fn put_pathing(path: String) -> Result<String, Error> {
 pub enum GetPathing {
     Get(String),
     List,
+    Export(Option<String>),
+    Stats,
 }
 
 pub fn get_pathing(path: String) -> Result<GetPathing, Error> {
@@ -55,6 +59,21 @@ pub fn get_pathing(path: String) -> Result<GetPathing, Error> {
         &"list" => {
             Ok(GetPathing::List)
         },
+        &"stats" => {
+            Ok(GetPathing::Stats)
+        },
+        &"export" => {
+            match slice_all.len() {
+                2 => Ok(GetPathing::Export(None)),
+                3 => Ok(GetPathing::Export(Some(slice_all.get(2).unwrap().to_string()))),
+                _ => Err(
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid path: {}", path),
+                    ),
+                ),
+            }
+        },
         _ => {
             Err(
                 Error::new(
@@ -86,4 +105,56 @@ pub fn del_pathing(path: String) -> Result<String, Error> {
         );
     }
     Ok(slice_all[2].to_string())
-}
\ No newline at end of file
+}
+
+pub enum PostPathing {
+    Snapshot,
+    Import(Option<String>),
+    CompareAndSwap(String),
+}
+
+pub fn post_pathing(path: String) -> Result<PostPathing, Error> {
+    let segments = path.split("/");
+    let slice_all = segments.clone().collect::<Vec<&str>>();
+    match slice_all[1..] {
+        ["snapshot"] => Ok(PostPathing::Snapshot),
+        ["import"] => Ok(PostPathing::Import(None)),
+        ["import", policy] => Ok(PostPathing::Import(Some(policy.to_string()))),
+        ["cas", key] => Ok(PostPathing::CompareAndSwap(key.to_string())),
+        _ => {
+            Err(
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid path: {}", path),
+                ),
+            )
+        }
+    }
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split("&")
+        .filter_map(|pair| pair.split_once("="))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn ttl_query(query: Option<&str>) -> Result<Option<Duration>, Error> {
+    let seconds = match query_param(query, "ttl") {
+        Some(seconds) => seconds,
+        None => return Ok(None),
+    };
+    match seconds.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(Some(Duration::from_secs(seconds))),
+        _ => Err(
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid ttl: {}, expected a positive number of seconds", seconds),
+            ),
+        ),
+    }
+}
+
+pub fn condition_query(query: Option<&str>) -> Result<Option<Condition>, Error> {
+    query_param(query, "if").map(str::parse::<Condition>).transpose()
+}
@@ -0,0 +1,12 @@
+use serde::{Serialize, Deserialize};
+
+use crate::store::Value;
+
+/// Body of `POST /cas/{key}`. The key must currently hold `expected`, or not
+/// exist if it is `None`, and is then set to `value`, or deleted if it is
+/// `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompareAndSwap {
+    pub expected: Option<Value>,
+    pub value: Option<Value>,
+}
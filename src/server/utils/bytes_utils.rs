@@ -1,11 +1,15 @@
 use serde_json::Error;
 
-use crate::{store::Value, server::protocol::Respond};
+use crate::{store::Value, server::protocol::{Respond, CompareAndSwap}};
 
 pub fn bytes_to_deserialized_value(bytes: Vec<u8>) -> Result<Value, Error> {
     serde_json::from_slice(&bytes)
 }
 
+pub fn bytes_to_deserialized_compare_and_swap(bytes: Vec<u8>) -> Result<CompareAndSwap, Error> {
+    serde_json::from_slice(&bytes)
+}
+
 pub fn serialized_respond_to_bytes(res: Respond) -> Vec<u8> {
     let bytes = serde_json::to_vec(&res);
     bytes.expect("Failed to serialize respond")
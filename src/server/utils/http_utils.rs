@@ -1,7 +1,36 @@
-use std::io::{Error, ErrorKind};
+use std::{io::{Error, ErrorKind}, pin::Pin, task::{Context, Poll}};
 
-use http_body_util::{BodyExt as _, Full};
-use hyper::{Response, Request, body::{Incoming, Bytes}};
+use http_body_util::{BodyExt as _, Full, combinators::BoxBody};
+use hyper::{Response, Request, body::{Body, Frame, Incoming, Bytes}};
+use log::error;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{store::{Corruption, Conflict}, server::protocol::Respond};
+
+use super::serialized_respond_to_bytes;
+
+/// Body of every response, either complete or streamed.
+pub type ResponseBody = BoxBody<Bytes, Error>;
+
+fn full_body(bytes: impl Into<Bytes>) -> ResponseBody {
+    Full::new(bytes.into()).map_err(|never| match never {}).boxed()
+}
+
+/// Streams the chunks received on a channel. An error aborts the response,
+/// so the client sees it is incomplete.
+struct ChannelBody {
+    receiver: Receiver<Result<Vec<u8>, Error>>,
+}
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+
+    type Error = Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        self.receiver.poll_recv(cx).map(|chunk| chunk.map(|chunk| chunk.map(|bytes| Frame::data(Bytes::from(bytes)))))
+    }
+}
 
 pub async fn http_request_to_bytes(req: Request<Incoming>) -> Vec<u8> {
     let mut body = req.into_body();
@@ -12,22 +41,43 @@ pub async fn http_request_to_bytes(req: Request<Incoming>) -> Vec<u8> {
         let frame = frame.unwrap();
         let bytes = frame.into_data();
 
-        if let Err(_) = bytes {
-            break;
+        match bytes {
+            Ok(bytes) => bytes_vec.extend(bytes),
+            Err(_) => break,
         }
-        
-        bytes_vec.extend(bytes.unwrap());
     }
 
     bytes_vec
 }
 
-pub fn bytes_to_http_response(bytes: Vec<u8>, exit: u16, cors_allowed_origins: Vec<String>) -> Response<Full<Bytes>> {
-    Response::builder().header("Access-Control-Allow-Origin", cors_allowed_origins.join(",")).header("Content-Type", "application/json").status(exit).body(bytes.into()).unwrap()
+pub fn bytes_to_http_response(bytes: Vec<u8>, exit: u16, cors_allowed_origins: Vec<String>) -> Response<ResponseBody> {
+    Response::builder().header("Access-Control-Allow-Origin", cors_allowed_origins.join(",")).header("Content-Type", "application/json").status(exit).body(full_body(bytes)).unwrap()
+}
+
+pub fn text_to_http_response(text: String, exit: u16, cors_allowed_origins: Vec<String>) -> Response<ResponseBody> {
+    Response::builder().header("Access-Control-Allow-Origin", cors_allowed_origins.join(",")).status(exit).body(full_body(text)).unwrap()
 }
 
-pub fn text_to_http_response(text: String, exit: u16, cors_allowed_origins: Vec<String>) -> Response<Full<Bytes>> {
-    Response::builder().header("Access-Control-Allow-Origin", cors_allowed_origins.join(",")).status(exit).body(text.into()).unwrap()
+pub fn ndjson_stream_to_http_response(receiver: Receiver<Result<Vec<u8>, Error>>, cors_allowed_origins: Vec<String>) -> Response<ResponseBody> {
+    Response::builder().header("Access-Control-Allow-Origin", cors_allowed_origins.join(",")).header("Content-Type", "application/x-ndjson").status(200).body(ChannelBody { receiver }.boxed()).unwrap()
+}
+
+pub fn error_to_http_response(error: Error, cors_allowed_origins: Vec<String>) -> Response<ResponseBody> {
+    if let Some(corruption) = Corruption::find(&error) {
+        error!("{}", corruption);
+        return text_to_http_response(format!("Data corruption detected: {}", corruption), 500, cors_allowed_origins);
+    }
+    if let Some(conflict) = Conflict::find(&error) {
+        let respond = Respond::Value(conflict.current.clone());
+        return bytes_to_http_response(serialized_respond_to_bytes(respond), 409, cors_allowed_origins);
+    }
+    if error.kind() == ErrorKind::AlreadyExists {
+        return text_to_http_response(error.to_string(), 409, cors_allowed_origins);
+    }
+    if error.kind() == ErrorKind::Unsupported {
+        return text_to_http_response(error.to_string(), 400, cors_allowed_origins);
+    }
+    text_to_http_response(error.to_string(), 500, cors_allowed_origins)
 }
 
 pub fn http_request_validate_cors(req: Request<Incoming>, cors_allowed_origins: Vec<String>) -> Result<Request<Incoming>, Error> {
@@ -37,22 +87,22 @@ pub fn http_request_validate_cors(req: Request<Incoming>, cors_allowed_origins:
 
     let origin = req.headers().get("Origin");
 
-    if let None = origin {
-        return Ok(req);
-    }
-
-    let origin = origin.unwrap().to_str();
-
-    if let Err(_) = origin {
-        return Err(
-            Error::new(
-                ErrorKind::InvalidInput,
-                "Invalid origin",
-            )
-        );
-    }
-
-    let origin = origin.unwrap();
+    let origin = match origin {
+        Some(origin) => origin.to_str(),
+        None => return Ok(req),
+    };
+
+    let origin = match origin {
+        Ok(origin) => origin,
+        Err(_) => {
+            return Err(
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "Invalid origin",
+                )
+            );
+        }
+    };
 
     if !cors_allowed_origins.contains(&origin.to_string()) {
         return Err(
@@ -66,12 +116,12 @@ pub fn http_request_validate_cors(req: Request<Incoming>, cors_allowed_origins:
     Ok(req)
 }
 
-pub fn cors_preflight_http_response(cors_allowed_origins: Vec<String>) -> Response<Full<Bytes>> {
+pub fn cors_preflight_http_response(cors_allowed_origins: Vec<String>) -> Response<ResponseBody> {
     Response::builder()
         .status(200)
         .header("Access-Control-Allow-Origin", cors_allowed_origins.join(","))
         .header("Access-Control-Allow-Methods", "DELETE, GET, HEAD, OPTIONS, PATCH, POST, PUT")
         .header("Access-Control-Allow-Headers", "Content-Type")
-        .body("".into())
+        .body(full_body(""))
         .unwrap()
 }
\ No newline at end of file
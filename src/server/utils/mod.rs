@@ -1,5 +1,5 @@
 mod http_utils;
 mod bytes_utils;
 
-pub use http_utils::{http_request_to_bytes, bytes_to_http_response, text_to_http_response, http_request_validate_cors, cors_preflight_http_response};
-pub use bytes_utils::{bytes_to_deserialized_value, serialized_respond_to_bytes};
\ No newline at end of file
+pub use http_utils::{ResponseBody, http_request_to_bytes, ndjson_stream_to_http_response, bytes_to_http_response, text_to_http_response, error_to_http_response, http_request_validate_cors, cors_preflight_http_response};
+pub use bytes_utils::{bytes_to_deserialized_value, bytes_to_deserialized_compare_and_swap, serialized_respond_to_bytes};
\ No newline at end of file
@@ -1,14 +1,17 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 
 use hyper::server::conn::http1::Builder ;
-use hyper_util::rt::TokioIo;
+use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown};
 use tokio::net::TcpListener;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 use super::EngineService;
 
+/// Time open connections get to finish their requests on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[allow(dead_code)]
 pub struct WebServer {
     addr: SocketAddr,
@@ -39,10 +42,16 @@ impl WebServer {
 
     pub async fn run(self) {
         info!("Successfully started server on port {}", self.addr.port());
+
+        let graceful = GracefulShutdown::new();
         
         loop {
-            let accepted = self.tcp_listener.accept().await;
+            let accepted = tokio::select! {
+                accepted = self.tcp_listener.accept() => accepted,
+                _ = Self::shutdown_signal() => break,
+            };
             let service_clone = self.engine_service.clone();
+            let watcher = graceful.watcher();
             tokio::task::spawn(async move {
 
                 if let Err(_) = accepted {
@@ -56,7 +65,7 @@ impl WebServer {
             
                 let builder = Builder::new();
 
-                let conn = builder.serve_connection(io, service_clone);
+                let conn = watcher.watch(builder.serve_connection(io, service_clone));
 
                 if let Err(_) = conn.await {
                     error!("Failed to serve connection");
@@ -65,6 +74,33 @@ impl WebServer {
                 
             });   
         }
+
+        info!("Stopped accepting connections");
+
+        // Requests in flight finish and idle connections are closed, so no
+        // write is accepted after the engine is shut down.
+        tokio::select! {
+            _ = graceful.shutdown() => info!("Closed all connections"),
+            _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => warn!("Connections still open after {:?}, shutting down anyway", SHUTDOWN_TIMEOUT),
+        }
+    }
+
+    /// Waits for Ctrl+C, or for SIGTERM on unix.
+    async fn shutdown_signal() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+        }
     }
     
 }
@@ -1,26 +1,56 @@
-use std::{path::Path, time::Duration};
+use std::{fs::{self, File}, io::Error, path::{Path, PathBuf}, time::Duration};
 
-use moka::future::Cache;
 use simple_logger::SimpleLogger;
-use log::Level;
+use log::{error, info, warn, Level};
 
-use crate::{store::{Disk, Engine, Value, weight}, server::{WebServer, EngineService}};
+use crate::{store::{Disk, DataDir, Memory, Lsm, StorageBackend, Engine, SyncPolicy, Cipher, restore_snapshot, CachePolicy, CacheRule, Primary, lock_data}, server::{WebServer, EngineService}};
 
 use std::env;
 
+/// Which `StorageBackend` the engine runs on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    Disk,
+    Memory,
+    Lsm,
+}
+
 #[derive(Debug)]
 pub struct Configuration {
     pub log_level: Level,
+    pub storage_backend: BackendKind,
     pub data_dir: String,
+    pub defrag_threshold: Option<f64>,
+    pub wal_sync: SyncPolicy,
+    pub disk_mmap: bool,
+    pub compression_threshold: Option<u64>,
+    pub encryption_key: Option<Cipher>,
+    pub segment_size: u64,
+    pub snapshot_dir: Option<String>,
+    pub restore_from: Option<String>,
+    pub storage_queue: usize,
+    pub write_back: bool,
+    pub write_back_interval: u64,
+    pub write_back_limit: usize,
+    pub reap_interval: u64,
     pub port: u16,
 
     pub cache_size: u64,
+    pub pinned_size: u64,
     pub cache_ttl: u64,
     pub cache_tti: u64,
+    pub cache_negative: bool,
+    pub cache_rules: Vec<CacheRule>,
 
     pub cors_allowed_origins: Vec<String>,
 }
 
+impl Default for Configuration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Configuration {
 
     pub fn new() -> Self {
@@ -32,22 +62,74 @@ impl Configuration {
             "trace" => log::Level::Trace,
             _ => log::Level::Info,
         };
+        let storage_backend = match env::var("STORAGE_BACKEND").unwrap_or("disk".to_string()).as_str() {
+            "disk" => BackendKind::Disk,
+            "memory" => BackendKind::Memory,
+            "lsm" => BackendKind::Lsm,
+            _ => panic!("STORAGE_BACKEND must be one of disk, memory or lsm"),
+        };
         let data_dir = env::var("DATA_DIR").expect("DATA_DIR not set");
+        let defrag_threshold = env::var("DEFRAG_THRESHOLD").ok().map(|s| s.parse::<f64>().expect("DEFRAG_THRESHOLD is not a valid number"));
+        let wal_sync = match env::var("WAL_SYNC").unwrap_or("always".to_string()).as_str() {
+            "always" => SyncPolicy::Always,
+            "never" => SyncPolicy::Never,
+            "interval" => {
+                let interval = env::var("WAL_SYNC_INTERVAL").unwrap_or("1000".to_string()).parse::<u64>().expect("WAL_SYNC_INTERVAL is not a valid number");
+                SyncPolicy::Interval(Duration::from_millis(interval))
+            },
+            _ => panic!("WAL_SYNC must be one of always, interval or never"),
+        };
+        let disk_mmap = env::var("DISK_MMAP").unwrap_or("true".to_string()).parse::<bool>().expect("DISK_MMAP is not a valid boolean");
+        let compression_threshold = env::var("COMPRESSION_THRESHOLD").ok().map(|s| s.parse::<u64>().expect("COMPRESSION_THRESHOLD is not a valid number"));
+        let encryption_key = match (env::var("ENCRYPTION_KEY").ok(), env::var("ENCRYPTION_KEY_FILE").ok()) {
+            (Some(_), Some(_)) => panic!("Only one of ENCRYPTION_KEY and ENCRYPTION_KEY_FILE can be set"),
+            (Some(key), None) => Some(Cipher::from_hex(&key).expect("ENCRYPTION_KEY is not a valid key")),
+            (None, Some(path)) => Some(Cipher::from_key_file(Path::new(&path)).expect("ENCRYPTION_KEY_FILE does not hold a valid key")),
+            (None, None) => None,
+        };
+        let segment_size = env::var("SEGMENT_SIZE").unwrap_or("256".to_string()).parse::<u64>().expect("SEGMENT_SIZE is not a valid number");
+        let snapshot_dir = env::var("SNAPSHOT_DIR").ok();
+        let restore_from = env::var("RESTORE_FROM").ok();
+        let storage_queue = env::var("STORAGE_QUEUE").unwrap_or("1024".to_string()).parse::<usize>().expect("STORAGE_QUEUE is not a valid number");
+        let write_back = env::var("WRITE_BACK").unwrap_or("false".to_string()).parse::<bool>().expect("WRITE_BACK is not a valid boolean");
+        let write_back_interval = env::var("WRITE_BACK_INTERVAL").unwrap_or("1000".to_string()).parse::<u64>().expect("WRITE_BACK_INTERVAL is not a valid number");
+        let write_back_limit = env::var("WRITE_BACK_LIMIT").unwrap_or("1024".to_string()).parse::<usize>().expect("WRITE_BACK_LIMIT is not a valid number");
+        let reap_interval = env::var("REAP_INTERVAL").unwrap_or("60".to_string()).parse::<u64>().expect("REAP_INTERVAL is not a valid number");
         let port = env::var("PORT").expect("PORT not set").parse::<u16>().expect("PORT is not a valid number");
 
         let cache_size = env::var("CACHE_SIZE").expect("CACHE_SIZE not set").parse::<u64>().expect("CACHE_SIZE is not a valid number");
+        let pinned_size = env::var("PINNED_SIZE").ok().map_or(cache_size, |s| s.parse::<u64>().expect("PINNED_SIZE is not a valid number"));
         let cache_ttl = env::var("CACHE_TTL").expect("CACHE_TTL not set").parse::<u64>().expect("CACHE_TTL is not a valid number");
         let cache_tti = env::var("CACHE_TTI").expect("CACHE_TTI not set").parse::<u64>().expect("CACHE_TTI is not a valid number");
+        let cache_negative = env::var("CACHE_NEGATIVE").unwrap_or("true".to_string()).parse::<bool>().expect("CACHE_NEGATIVE is not a valid boolean");
+        let cache_rules = CachePolicy::parse_rules(&env::var("CACHE_RULES").unwrap_or_default()).expect("CACHE_RULES is not a valid list of rules");
 
-        let cors_allowed_origins = env::var("CORS_ALLOWED_ORIGINS").expect("CORS_ALLOWED_ORIGINS not set").split(",").map(|s| s.to_string()).collect::<Vec<String>>();
+        let cors_allowed_origins = env::var("CORS_ALLOWED_ORIGINS").expect("CORS_ALLOWED_ORIGINS not set").split(',').map(|s| s.to_string()).collect::<Vec<String>>();
 
         Self {
             log_level,
+            storage_backend,
             data_dir,
+            defrag_threshold,
+            wal_sync,
+            disk_mmap,
+            compression_threshold,
+            encryption_key,
+            segment_size,
+            snapshot_dir,
+            restore_from,
+            storage_queue,
+            write_back,
+            write_back_interval,
+            write_back_limit,
+            reap_interval,
             port,
             cache_size,
+            pinned_size,
             cache_ttl,
             cache_tti,
+            cache_negative,
+            cache_rules,
             cors_allowed_origins,
         }
     }
@@ -71,31 +153,170 @@ pub fn setup_log(level: Level) {
         .init().expect("Logger failed to initialize");
 }
 
-pub fn setup_secondary(path: String) -> Disk {
-    let secondary = Disk::new(
-        Path::new(path.as_str())
+/// Locks DATA_DIR for this process, so the server and an export or import
+/// run from the command line never open the same data at once. The lock is
+/// held until the returned file is dropped, the memory backend takes none.
+pub fn setup_lock(configuration: &Configuration) -> Result<Option<File>, Error> {
+    if configuration.storage_backend == BackendKind::Memory {
+        return Ok(None);
+    }
+    let data_dir = Path::new(configuration.data_dir.as_str());
+    if !data_dir.exists() {
+        fs::create_dir_all(data_dir)?;
+    }
+    lock_data(data_dir).map(Some)
+}
+
+/// Opens the `StorageBackend` selected by the configuration.
+pub fn setup_secondary(configuration: &Configuration) -> Box<dyn StorageBackend> {
+    if configuration.storage_backend != BackendKind::Memory && configuration.wal_sync != SyncPolicy::Always {
+        warn!("WAL_SYNC is not always, a power loss may lose recent writes and leave the data files damaged");
+    }
+    if configuration.storage_backend != BackendKind::Disk && configuration.encryption_key.is_some() {
+        error!("Encryption is only supported by the disk backend");
+        panic!("Shutdown");
+    }
+    match configuration.storage_backend {
+        BackendKind::Disk if Path::new(configuration.data_dir.as_str()).is_file() => {
+            warn!("DATA_DIR points at a single data file, point it at its directory to store the data in segments");
+            Box::new(setup_disk(configuration))
+        },
+        BackendKind::Disk => Box::new(setup_data_dir(configuration)),
+        BackendKind::Memory => Box::new(Memory::new()),
+        BackendKind::Lsm => Box::new(setup_lsm(configuration)),
+    }
+}
+
+/// Replaces the data of `secondary` with the snapshot in RESTORE_FROM, if it
+/// is set. The snapshot is verified before anything is replaced.
+pub fn setup_restore(secondary: &mut Box<dyn StorageBackend>, configuration: &Configuration) {
+    let path = match &configuration.restore_from {
+        Some(path) => path.as_str(),
+        None => return,
+    };
+    match restore_snapshot(Path::new(path), configuration.encryption_key.as_ref(), secondary) {
+        Ok(entries) => info!("Restored {} entries from {}, unset RESTORE_FROM before the next start", entries, path),
+        Err(e) => {
+            error!("Failed to restore {}: {}", path, e);
+            panic!("Shutdown");
+        }
+    }
+}
+
+fn setup_lsm(configuration: &Configuration) -> Lsm {
+    let path = configuration.data_dir.as_str();
+    let secondary = Lsm::new(
+        Path::new(path)
     );
-    if let Err(_) = secondary {
+    if let Err(e) = secondary {
+        error!("Failed to open {}: {}", path, e);
+        panic!("Shutdown");
+    }
+    let mut secondary = secondary.unwrap();
+    if let Err(e) = secondary.set_sync_policy(configuration.wal_sync) {
+        error!("Failed to set sync policy: {}", e);
+        panic!("Shutdown");
+    }
+    secondary
+}
+
+fn setup_data_dir(configuration: &Configuration) -> DataDir {
+    let path = configuration.data_dir.as_str();
+    let secondary = match configuration.encryption_key.clone() {
+        Some(cipher) => DataDir::new_encrypted(Path::new(path), cipher),
+        None => DataDir::new(Path::new(path)),
+    };
+    if let Err(e) = secondary {
+        error!("Failed to open {}: {}", path, e);
         panic!("Shutdown");
     }
-    secondary.unwrap()
+    let mut secondary = secondary.unwrap();
+    secondary.set_segment_size(configuration.segment_size * 1024 * 1024);
+    secondary.set_defrag_threshold(configuration.defrag_threshold);
+    secondary.set_mmap(configuration.disk_mmap);
+    secondary.set_compression_threshold(configuration.compression_threshold);
+    if let Err(e) = secondary.set_sync_policy(configuration.wal_sync) {
+        error!("Failed to set sync policy: {}", e);
+        panic!("Shutdown");
+    }
+    secondary
 }
 
-pub fn setup_primary(size: u64, ttl: u64, tti: u64) -> Cache<String, Option<Value>> {
-    Cache::builder()
-        .max_capacity(size)
-        .time_to_live(Duration::from_secs(ttl))
-        .time_to_idle(Duration::from_secs(tti))
-        .weigher(weight)
-        .build()
+fn setup_disk(configuration: &Configuration) -> Disk {
+    let path = configuration.data_dir.as_str();
+    let secondary = match configuration.encryption_key.clone() {
+        Some(cipher) => Disk::new_encrypted(Path::new(path), cipher),
+        None => Disk::new(Path::new(path)),
+    };
+    if let Err(e) = secondary {
+        error!("Failed to open {}: {}", path, e);
+        panic!("Shutdown");
+    }
+    let mut secondary = secondary.unwrap();
+    secondary.set_defrag_threshold(configuration.defrag_threshold);
+    secondary.set_mmap(configuration.disk_mmap);
+    secondary.set_compression_threshold(configuration.compression_threshold);
+    if let Err(e) = secondary.set_sync_policy(configuration.wal_sync) {
+        error!("Failed to set sync policy: {}", e);
+        panic!("Shutdown");
+    }
+    secondary
+}
+
+pub fn setup_cache_policy(configuration: &Configuration) -> CachePolicy {
+    let mut policy = CachePolicy::new(
+        Duration::from_secs(configuration.cache_ttl),
+        Duration::from_secs(configuration.cache_tti)
+    );
+    policy.set_negative(configuration.cache_negative);
+    policy.set_rules(configuration.cache_rules.clone());
+    policy
 }
 
-pub fn setup_engine(secondary: Disk, primary: Cache<String, Option<Value>>) -> Engine {
-    Engine::new(secondary, primary)
+/// Builds the cache, holding at most `size` megabytes of entries and
+/// `pinned_size` megabytes of pinned entries as weighed by `weight`.
+pub fn setup_primary(size: u64, pinned_size: u64, policy: CachePolicy) -> Primary {
+    Primary::with_pinned_capacity(size.saturating_mul(1024 * 1024), pinned_size.saturating_mul(1024 * 1024), policy)
 }
 
-pub fn setup_engine_service(engine: Engine, cors_allowed_origins: Vec<String>) -> EngineService {
-    EngineService::new(engine, cors_allowed_origins)
+pub fn setup_engine(secondary: Box<dyn StorageBackend>, primary: Primary, storage_queue: usize) -> Engine {
+    Engine::with_queue_size(secondary, primary, storage_queue)
+}
+
+/// Switches `engine` to write-back mode if WRITE_BACK is set.
+pub fn setup_write_back(engine: &mut Engine, configuration: &Configuration) {
+    if configuration.write_back {
+        warn!("Write-back is enabled, writes of the last {}ms may be lost if the server does not shut down cleanly", configuration.write_back_interval);
+        engine.set_write_back(Duration::from_millis(configuration.write_back_interval), configuration.write_back_limit);
+    }
+}
+
+/// Lets `engine` delete expired keys every REAP_INTERVAL seconds, unless it
+/// is 0.
+pub fn setup_reaper(engine: &mut Engine, configuration: &Configuration) {
+    if configuration.reap_interval > 0 {
+        engine.set_reap_interval(Duration::from_secs(configuration.reap_interval));
+    }
+}
+
+/// Flushes the writes `engine` has not stored yet before the server exits.
+pub async fn shutdown_engine(engine: &Engine) {
+    match engine.flush().await {
+        Ok(flushed) => info!("Flushed {} writes on shutdown", flushed),
+        Err(e) => error!("Failed to flush writes on shutdown: {}", e),
+    }
+}
+
+pub fn setup_engine_service(engine: Engine, cors_allowed_origins: Vec<String>, snapshot_dir: Option<String>) -> EngineService {
+    let mut engine_service = EngineService::new(engine, cors_allowed_origins);
+    if let Some(snapshot_dir) = snapshot_dir {
+        if let Err(e) = fs::create_dir_all(&snapshot_dir) {
+            error!("Failed to create {}: {}", snapshot_dir, e);
+            panic!("Shutdown");
+        }
+        engine_service.set_snapshot_dir(Some(PathBuf::from(snapshot_dir)));
+    }
+    engine_service
 }
 
 pub async fn setup_web_server(engine_service: EngineService, port: u16) -> WebServer {
@@ -0,0 +1,245 @@
+use std::{any::Any, io::{Error, ErrorKind}, panic::{self, AssertUnwindSafe}, sync::{Arc, PoisonError, RwLock, RwLockWriteGuard, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}};
+
+use log::{error, trace, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{StorageBackend, snapshot::Snapshotting};
+
+/// Number of requests that can wait for the storage thread before senders
+/// have to wait for room in the queue.
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// Number of requests the storage thread works through at most before it
+/// syncs the writes among them and lets reads in again.
+const GROUP_LIMIT: usize = 256;
+
+/// Runs on the storage thread and returns what tells the sender how the
+/// write went once it is synced.
+type WriteJob = Box<dyn FnOnce(&mut Snapshotting) -> Completion + Send>;
+
+type Completion = Box<dyn FnOnce(Result<(), &Error>) + Send>;
+
+enum Request {
+    /// Answered as soon as it ran.
+    Run(Box<dyn FnOnce(&mut Snapshotting) + Send>),
+    /// Answered once it ran and the writes of its group are synced.
+    Write(WriteJob),
+}
+
+/// Runs the `StorageBackend` of the `Engine` off the async runtime, so
+/// blocking file I/O never stalls it. Reads run on the blocking thread pool
+/// and share the backend with each other. Everything else waits in a bounded
+/// queue for a dedicated thread, which has the backend to itself while it
+/// works through the queue. Writes that queue up while the thread is busy are
+/// committed as a group with a single sync. A request that panics on the
+/// storage thread may have left the backend half changed, so every request
+/// for the thread fails from then on, while reads carry on.
+pub struct StorageActor {
+    backend: Arc<RwLock<Snapshotting>>,
+    failed: Arc<AtomicBool>,
+    sender: Option<mpsc::Sender<Request>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StorageActor {
+    pub fn spawn(backend: Snapshotting, queue_size: usize) -> Self {
+        let backend = Arc::new(RwLock::new(backend));
+        let (sender, receiver) = mpsc::channel::<Request>(queue_size.max(1));
+        let failed = Arc::new(AtomicBool::new(false));
+        let thread_backend = backend.clone();
+        let thread_failed = failed.clone();
+        let handle = thread::Builder::new()
+            .name("varia-storage".to_string())
+            .spawn(move || Self::process(&thread_backend, &thread_failed, receiver))
+            .expect("Failed to spawn storage thread");
+        Self {
+            backend,
+            failed,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool alongside other reads and returns
+    /// its result. It never sees writes that are not synced yet.
+    pub async fn read<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Snapshotting) -> Result<R, Error> + Send + 'static,
+    {
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || f(&backend.read().unwrap_or_else(PoisonError::into_inner)))
+            .await
+            .unwrap_or_else(|e| {
+                if let Ok(payload) = e.try_into_panic() {
+                    error!("Storage operation panicked: {}", Self::panic_message(&payload));
+                }
+                Err(Self::panicked())
+            })
+    }
+
+    /// Runs `f` on the storage thread and returns its result.
+    pub async fn run<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Snapshotting) -> Result<R, Error> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.send(Request::Run(Box::new(move |backend| {
+            let _ = reply.send(f(backend));
+        }))).await?;
+        result.await.unwrap_or_else(|_| Err(Self::panicked()))
+    }
+
+    /// Runs `f` on the storage thread and returns its result once the writes
+    /// it made are synced.
+    pub async fn write<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Snapshotting) -> Result<R, Error> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.send(Request::Write(Box::new(move |backend| {
+            let outcome = f(backend);
+            Box::new(move |synced: Result<(), &Error>| {
+                let outcome = match synced {
+                    Ok(()) => outcome,
+                    Err(e) => outcome.and(Err(Error::new(e.kind(), format!("Failed to sync: {}", e)))),
+                };
+                let _ = reply.send(outcome);
+            })
+        }))).await?;
+        result.await.unwrap_or_else(|_| Err(Self::panicked()))
+    }
+
+    /// Runs `f` on the calling thread with the backend to itself and syncs
+    /// the writes it made, for callers that cannot wait for the storage
+    /// thread, like a `Drop` implementation.
+    pub fn write_now<R>(&self, f: impl FnOnce(&mut Snapshotting) -> Result<R, Error>) -> Result<R, Error> {
+        let mut backend = Self::lock(&self.backend);
+        if self.failed.load(Ordering::Acquire) {
+            return Err(Self::failed());
+        }
+        let result = f(&mut backend)?;
+        backend.sync()?;
+        Ok(result)
+    }
+
+    async fn send(&self, request: Request) -> Result<(), Error> {
+        if self.failed.load(Ordering::Acquire) {
+            return Err(Self::failed());
+        }
+        let sender = self.sender.as_ref().expect("Storage thread is shut down");
+        sender.send(request).await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Storage thread is gone"))
+    }
+
+    fn panicked() -> Error {
+        Error::other("Storage operation panicked")
+    }
+
+    fn failed() -> Error {
+        Error::other("Storage operation panicked before, restart the server to write again")
+    }
+
+    fn process(backend: &RwLock<Snapshotting>, failed: &AtomicBool, mut receiver: mpsc::Receiver<Request>) {
+        if let Err(e) = Self::lock(backend).set_group_commit(true) {
+            warn!("Failed to enable group commit: {}", e);
+        }
+
+        let mut group: Vec<Completion> = Vec::new();
+        while let Some(request) = receiver.blocking_recv() {
+            // Reads wait until the group is synced.
+            let mut backend = Self::lock(backend);
+            let mut next = Some(request);
+            let mut handled: usize = 0;
+            while let Some(request) = next.take() {
+                handled += 1;
+                // Requests queued behind one that panicked see their reply
+                // dropped.
+                if failed.load(Ordering::Acquire) {
+                    continue;
+                }
+                match request {
+                    Request::Run(job) => {
+                        // Nothing is read that is not durable yet.
+                        Self::commit(&mut backend, &mut group, failed);
+                        Self::guard(|| job(&mut backend), failed);
+                    },
+                    Request::Write(job) => {
+                        if let Some(completion) = Self::guard(|| job(&mut backend), failed) {
+                            group.push(completion);
+                        }
+                    },
+                }
+                if handled < GROUP_LIMIT {
+                    next = receiver.try_recv().ok();
+                }
+            }
+            Self::commit(&mut backend, &mut group, failed);
+        }
+
+        trace!("Storage thread shuts down");
+        if failed.load(Ordering::Acquire) {
+            warn!("Storage thread shuts down without a final sync after a panic");
+            return;
+        }
+        if let Err(e) = Self::lock(backend).set_group_commit(false) {
+            warn!("Failed to sync on shutdown: {}", e);
+        }
+    }
+
+    fn lock(backend: &RwLock<Snapshotting>) -> RwLockWriteGuard<'_, Snapshotting> {
+        backend.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Syncs the writes of the group and answers them.
+    fn commit(backend: &mut Snapshotting, group: &mut Vec<Completion>, failed: &AtomicBool) {
+        if group.is_empty() {
+            return;
+        }
+        trace!("Commit group of {} writes", group.len());
+        let synced = Self::guard(|| backend.sync(), failed)
+            .unwrap_or_else(|| Err(Self::panicked()));
+        for completion in group.drain(..) {
+            completion(synced.as_ref().map(|_| ()));
+        }
+    }
+
+    /// Runs `f`, returning `None` and marking the backend as `failed` if it
+    /// panics. The sender of the request that panicked sees its reply
+    /// dropped.
+    fn guard<R>(f: impl FnOnce() -> R, failed: &AtomicBool) -> Option<R> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(result) => Some(result),
+            Err(payload) => {
+                failed.store(true, Ordering::Release);
+                error!("Storage operation panicked, rejecting further writes: {}", Self::panic_message(&payload));
+                None
+            },
+        }
+    }
+
+    fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            return message;
+        }
+        if let Some(message) = payload.downcast_ref::<String>() {
+            return message;
+        }
+        "unknown panic"
+    }
+}
+
+impl Drop for StorageActor {
+    /// Waits for the storage thread to finish the queued requests and close
+    /// the backend.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("Storage thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{io::{Error, ErrorKind}, path::Path};
+
+use super::{Disk, Value};
+
+/// Storage behind the cache of the `Engine`. Reads take `&self`, so they can
+/// run on several threads at once while writes have exclusive access.
+pub trait StorageBackend: Send + Sync {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error>;
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error>;
+
+    fn del(&mut self, key: String) -> Result<(), Error>;
+
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    fn clear(&mut self) -> Result<(), Error>;
+
+    fn len(&self) -> Result<usize, Error>;
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Stores `value` under `key` until `expires_at`, in milliseconds since
+    /// the unix epoch, or for good if it is `None` like `put`. Once expired
+    /// the key reads as missing until `reap` deletes it. Backends that cannot
+    /// store the expiry refuse expiring values.
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
+        match expires_at {
+            Some(_) => Err(Error::new(ErrorKind::Unsupported, "Expiring keys are not supported by this storage backend")),
+            None => self.put(key, value),
+        }
+    }
+
+    /// Whether `put_expiring` stores an expiry rather than refusing it.
+    fn supports_expiry(&self) -> bool {
+        false
+    }
+
+    /// Returns the time `key` expires at, `None` if it does not exist or
+    /// never expires.
+    fn expires_at(&self, _key: String) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// Deletes the keys that have expired and returns how many there were.
+    fn reap(&mut self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    /// Reclaims space left behind by updates and deletes. Backends without
+    /// such space do nothing.
+    fn defrag(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Lets writes leave syncing their write-ahead log to `sync`, so a group
+    /// of writes shares a single sync. Backends without a write-ahead log
+    /// ignore it.
+    fn set_group_commit(&mut self, _group_commit: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Makes the writes since the last `sync` durable in group commit mode.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Creates the data file a snapshot of this backend is written to.
+    /// Backends that encrypt or compress their data files do the same for
+    /// their snapshots.
+    fn snapshot_file(&mut self, path: &Path) -> Result<Disk, Error> {
+        Disk::new(path)
+    }
+}
+
+impl StorageBackend for Box<dyn StorageBackend> {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        (**self).put(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        (**self).get(key)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        (**self).del(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        (**self).list()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        (**self).clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        (**self).is_empty()
+    }
+
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
+        (**self).put_expiring(key, value, expires_at)
+    }
+
+    fn supports_expiry(&self) -> bool {
+        (**self).supports_expiry()
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>, Error> {
+        (**self).expires_at(key)
+    }
+
+    fn reap(&mut self) -> Result<usize, Error> {
+        (**self).reap()
+    }
+
+    fn defrag(&mut self) -> Result<(), Error> {
+        (**self).defrag()
+    }
+
+    fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        (**self).set_group_commit(group_commit)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        (**self).sync()
+    }
+
+    fn snapshot_file(&mut self, path: &Path) -> Result<Disk, Error> {
+        (**self).snapshot_file(path)
+    }
+}
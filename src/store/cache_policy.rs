@@ -0,0 +1,139 @@
+use std::{io::{Error, ErrorKind}, str::FromStr, time::Duration};
+
+/// How the entries of keys a rule matches are cached.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CacheMode {
+    /// Cached until they expire or the cache runs out of room.
+    #[default]
+    Cache,
+    /// Cached until they are written again, however full the cache is.
+    Pin,
+    /// Never cached.
+    Skip,
+}
+
+/// Overrides the cache policy for keys that start with `prefix`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheRule {
+    pub prefix: String,
+    pub mode: CacheMode,
+    pub ttl: Option<Duration>,
+    pub tti: Option<Duration>,
+    pub negative: Option<bool>,
+}
+
+impl FromStr for CacheRule {
+    type Err = Error;
+
+    /// Reads a rule written as `<prefix>=<option>,<option>...`, where an
+    /// option is one of `pin`, `skip`, `ttl:<seconds>`, `tti:<seconds>` or
+    /// `negative:<true|false>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid cache rule {:?}: {}", s, reason));
+
+        let (prefix, options) = s.split_once('=').ok_or_else(|| invalid("expected <prefix>=<options>"))?;
+        let mut rule = CacheRule {
+            prefix: prefix.trim().to_string(),
+            ..CacheRule::default()
+        };
+
+        for option in options.split(',').map(str::trim) {
+            let seconds = |value: &str| value.parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| invalid("expected a number of seconds"));
+            match option.split_once(':') {
+                None if option == "pin" => rule.mode = CacheMode::Pin,
+                None if option == "skip" => rule.mode = CacheMode::Skip,
+                Some(("ttl", value)) => rule.ttl = Some(seconds(value)?),
+                Some(("tti", value)) => rule.tti = Some(seconds(value)?),
+                Some(("negative", value)) => {
+                    rule.negative = Some(value.parse::<bool>().map_err(|_| invalid("expected true or false"))?);
+                },
+                _ => return Err(invalid(&format!("unknown option {:?}", option))),
+            }
+        }
+
+        if rule.mode != CacheMode::Cache && (rule.ttl.is_some() || rule.tti.is_some()) {
+            return Err(invalid("pinned and skipped keys do not expire"));
+        }
+        Ok(rule)
+    }
+}
+
+/// Decides which entries the cache of the `Engine` holds and for how long.
+/// The rule with the longest prefix a key starts with applies to it, keys no
+/// rule matches get the defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachePolicy {
+    ttl: Option<Duration>,
+    tti: Option<Duration>,
+    /// Whether keys that do not exist are cached as such.
+    negative: bool,
+    rules: Vec<CacheRule>,
+}
+
+impl Default for CachePolicy {
+    /// Caches every key, including the ones that do not exist, for as long as
+    /// there is room.
+    fn default() -> Self {
+        Self {
+            ttl: None,
+            tti: None,
+            negative: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl CachePolicy {
+    /// Caches every key, including the ones that do not exist, for at most
+    /// `ttl` after it was written and `tti` after it was last read.
+    pub fn new(ttl: Duration, tti: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            tti: Some(tti),
+            negative: true,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn set_negative(&mut self, negative: bool) {
+        self.negative = negative;
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<CacheRule>) {
+        self.rules = rules;
+    }
+
+    /// Reads rules separated by `;`.
+    pub fn parse_rules(s: &str) -> Result<Vec<CacheRule>, Error> {
+        s.split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    fn rule(&self, key: &str) -> Option<&CacheRule> {
+        self.rules.iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    pub fn mode(&self, key: &str) -> CacheMode {
+        self.rule(key).map(|rule| rule.mode).unwrap_or_default()
+    }
+
+    /// Returns how long the entry of `key` is kept after it was written and
+    /// after it was last read, `None` for as long as there is room.
+    pub fn expiry(&self, key: &str) -> (Option<Duration>, Option<Duration>) {
+        match self.rule(key) {
+            Some(rule) => (rule.ttl.or(self.ttl), rule.tti.or(self.tti)),
+            None => (self.ttl, self.tti),
+        }
+    }
+
+    /// Whether `key` is cached as not existing if it does not.
+    pub fn caches_absence(&self, key: &str) -> bool {
+        self.rule(key).and_then(|rule| rule.negative).unwrap_or(self.negative)
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fmt, fs, io::{Error, ErrorKind}, path::Path};
+
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, XChaCha20Poly1305, XNonce};
+
+use super::Corruption;
+
+/// Length of the random nonce in front of every sealed buffer.
+const NONCE_LEN: usize = 24;
+
+/// Length of the authentication tag behind every sealed buffer.
+const TAG_LEN: usize = 16;
+
+/// Authenticated encryption of entry frames with XChaCha20-Poly1305 under a
+/// 256-bit key.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+
+    /// Reads the key from 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Encryption key must be 64 hex digits"));
+        }
+
+        let mut key: [u8; 32] = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Encryption key must be 64 hex digits"))?;
+        }
+        Ok(Self {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    /// Reads the key from a file holding 64 hex digits.
+    pub fn from_key_file(path: &Path) -> Result<Self, Error> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// Returns a value derived from the key that tells keys apart without
+    /// revealing them, so a wrong key is noticed before any frame is read.
+    pub fn key_check(&self) -> u32 {
+        let payload = Payload {
+            msg: &[],
+            aad: b"varia-db key check",
+        };
+        let tag = self.aead.encrypt(&XNonce::default(), payload)
+            .expect("Encrypting an empty buffer cannot fail");
+        u32::from_be_bytes(tag[0..4].try_into().unwrap())
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce and authenticates it
+    /// together with `aad`.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.aead.encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Encryption failed"))?;
+
+        let mut buf: Vec<u8> = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Ok(buf)
+    }
+
+    /// Decrypts a buffer sealed with `aad`, failing if it was altered. The
+    /// buffer belongs to the frame at `offset`.
+    pub fn open(&self, buf: &[u8], aad: &[u8], offset: u64) -> Result<Vec<u8>, Error> {
+        if buf.len() < NONCE_LEN + TAG_LEN {
+            return Err(Corruption::error(offset, "Invalid encrypted buffer"));
+        }
+        let (nonce, ciphertext) = buf.split_at(NONCE_LEN);
+        self.aead.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| Corruption::error(offset, "Authentication failed"))
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher(XChaCha20-Poly1305)")
+    }
+}
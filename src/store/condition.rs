@@ -0,0 +1,74 @@
+use std::{fmt, io::{Error, ErrorKind}, str::FromStr};
+
+use super::Value;
+
+/// What the current value of a key must be for a conditional write to go
+/// ahead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// The key does not exist.
+    Absent,
+    /// The key exists, whatever its value.
+    Exists,
+    /// The key exists with exactly this value.
+    Equals(Value),
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    /// Reads `absent` or `exists`, the conditions that need no value.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absent" => Ok(Self::Absent),
+            "exists" => Ok(Self::Exists),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid condition {:?}, must be one of absent or exists", s))),
+        }
+    }
+}
+
+impl Condition {
+    pub fn holds(&self, current: &Option<Value>) -> bool {
+        match self {
+            Self::Absent => current.is_none(),
+            Self::Exists => current.is_some(),
+            Self::Equals(expected) => current.as_ref() == Some(expected),
+        }
+    }
+
+    /// Fails with a `Conflict` carrying `current` unless the condition holds
+    /// for it.
+    pub fn check(&self, current: &Option<Value>) -> Result<(), Error> {
+        if self.holds(current) {
+            return Ok(());
+        }
+        Err(Conflict::error(current.clone()))
+    }
+}
+
+/// Error for a conditional write whose condition did not hold. It is carried
+/// inside an `std::io::Error` of kind `AlreadyExists`, like other conflicts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// The value of the key the condition was checked against.
+    pub current: Option<Value>,
+}
+
+impl Conflict {
+    pub fn error(current: Option<Value>) -> Error {
+        Error::new(ErrorKind::AlreadyExists, Self { current })
+    }
+
+    /// Returns the conflict carried by an error, if any.
+    pub fn find(error: &Error) -> Option<&Self> {
+        error.get_ref().and_then(|e| e.downcast_ref::<Self>())
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Condition failed, the current value is {:?}", self.current)
+    }
+}
+
+impl std::error::Error for Conflict {}
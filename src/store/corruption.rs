@@ -0,0 +1,34 @@
+use std::{fmt, io::{Error, ErrorKind}};
+
+/// Error for data that was written but does not read back intact. It is
+/// carried inside an `std::io::Error` of kind `InvalidData`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl Corruption {
+    pub fn error(offset: u64, reason: impl Into<String>) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            Self {
+                offset,
+                reason: reason.into(),
+            }
+        )
+    }
+
+    /// Returns the corruption carried by an error, if any.
+    pub fn find(error: &Error) -> Option<&Self> {
+        error.get_ref().and_then(|e| e.downcast_ref::<Self>())
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Corrupted frame at offset {}: {}", self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for Corruption {}
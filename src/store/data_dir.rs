@@ -0,0 +1,511 @@
+use std::{path::{Path, PathBuf}, fs::{self, File, OpenOptions}, io::{Error, ErrorKind, Read, Write}, collections::{BTreeMap, HashSet}};
+
+use log::{debug, info, trace, warn};
+use serde::{Serialize, Deserialize};
+
+use super::{Value, StorageBackend, Disk, Cipher, SyncPolicy, FileHeader};
+
+/// Size of the active segment from which it is sealed and writes go to a new
+/// segment.
+const SEGMENT_BYTES: u64 = 256 * 1024 * 1024;
+
+pub(super) const MANIFEST: &str = "MANIFEST";
+
+const EXTENSION: &str = "bin";
+
+/// The data file of the single-file layout, which is adopted as the first
+/// segment of a directory without a manifest.
+pub const LEGACY_FILE: &str = "varia.bin";
+
+/// The live segments in the order they were started and the next free id.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    segments: Vec<u64>,
+}
+
+/// Data directory of rotating segments, each a `Disk` data file. Writes go to
+/// the newest segment, which is sealed once it grows beyond the segment size.
+/// Every key lives in exactly one segment and updates move keys out of sealed
+/// segments, so sealed segments only shrink and can be compacted or archived
+/// on their own.
+pub struct DataDir {
+    dir: PathBuf,
+    /// Oldest segment first, the last one is the active segment.
+    segments: BTreeMap<u64, Disk>,
+    next_id: u64,
+    segment_limit: u64,
+    cipher: Option<Cipher>,
+    defrag_threshold: Option<f64>,
+    compression_threshold: Option<u64>,
+    mmap: bool,
+    sync_policy: SyncPolicy,
+    group_commit: bool,
+}
+
+impl DataDir {
+
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        Self::open(path, None)
+    }
+
+    /// Opens a data directory whose segments are encrypted with `cipher`.
+    pub fn new_encrypted(path: &Path, cipher: Cipher) -> Result<Self, Error> {
+        Self::open(path, Some(cipher))
+    }
+
+    fn open(path: &Path, cipher: Option<Cipher>) -> Result<Self, Error> {
+        fs::create_dir_all(path)?;
+
+        let mut manifest = Self::read_manifest(path)?;
+        Self::adopt_legacy_file(path, &mut manifest)?;
+        Self::remove_orphans(path, &manifest)?;
+
+        let mut data_dir = Self {
+            dir: path.to_path_buf(),
+            segments: BTreeMap::new(),
+            next_id: manifest.next_id,
+            segment_limit: SEGMENT_BYTES,
+            cipher,
+            defrag_threshold: None,
+            compression_threshold: None,
+            mmap: false,
+            sync_policy: SyncPolicy::Always,
+            group_commit: false,
+        };
+
+        for id in manifest.segments {
+            let disk = data_dir.open_segment(id)?;
+            data_dir.segments.insert(id, disk);
+        }
+        if data_dir.segments.is_empty() {
+            data_dir.rotate()?;
+        }
+        data_dir.remove_duplicates()?;
+        data_dir.remove_empty_segments()?;
+
+        Ok(data_dir)
+    }
+
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:06}.{}", id, EXTENSION))
+    }
+
+    fn wal_path(path: &Path) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".wal");
+        path.with_file_name(file_name)
+    }
+
+    fn read_manifest(dir: &Path) -> Result<Manifest, Error> {
+        let path = dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+
+        if buf.len() < 4 || crc32fast::hash(&buf[4..]) != u32::from_be_bytes(buf[0..4].try_into().unwrap()) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid manifest"));
+        }
+        postcard::from_bytes(&buf[4..])
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid manifest"))
+    }
+
+    /// Atomically replaces the manifest of `dir`.
+    fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<(), Error> {
+        let payload = postcard::to_allocvec(manifest)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid manifest"))?;
+
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp_path)?;
+        file.write_all(&crc32fast::hash(&payload).to_be_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, dir.join(MANIFEST))?;
+        File::open(dir)?.sync_all()
+    }
+
+    /// Records the current segments in the manifest.
+    fn save_manifest(&self, segments: Vec<u64>) -> Result<(), Error> {
+        Self::write_manifest(&self.dir, &Manifest {
+            next_id: self.next_id,
+            segments,
+        })
+    }
+
+    /// Turns the data file of the single-file layout into the first segment.
+    /// The manifest is written first, so a crash halfway through is finished
+    /// on the next open.
+    fn adopt_legacy_file(dir: &Path, manifest: &mut Manifest) -> Result<(), Error> {
+        let legacy_path = dir.join(LEGACY_FILE);
+        let path = Self::segment_path(dir, 0);
+        if !legacy_path.exists() || path.exists() {
+            return Ok(());
+        }
+
+        if manifest.next_id == 0 {
+            *manifest = Manifest {
+                next_id: 1,
+                segments: vec![0],
+            };
+            Self::write_manifest(dir, manifest)?;
+        } else if !manifest.segments.contains(&0) {
+            return Ok(());
+        }
+
+        info!("Adopting {:?} as the first segment", legacy_path);
+        // The write-ahead log goes first, a data file without one has
+        // nothing to replay.
+        if Self::wal_path(&legacy_path).exists() {
+            fs::rename(Self::wal_path(&legacy_path), Self::wal_path(&path))?;
+        }
+        fs::rename(&legacy_path, &path)?;
+        File::open(dir)?.sync_all()
+    }
+
+    /// Removes segment files a crash left behind after they were dropped from
+    /// the manifest.
+    fn remove_orphans(dir: &Path, manifest: &Manifest) -> Result<(), Error> {
+        let live: HashSet<u64> = manifest.segments.iter().copied().collect();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|extension| extension != EXTENSION).unwrap_or(true) {
+                continue;
+            }
+            let id = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok());
+            if id.map(|id| !live.contains(&id)).unwrap_or(false) {
+                warn!("Removing orphaned segment {:?}", path);
+                Self::remove_files(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_files(path: &Path) -> Result<(), Error> {
+        fs::remove_file(path)?;
+        match fs::remove_file(Self::wal_path(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn open_segment(&self, id: u64) -> Result<Disk, Error> {
+        let path = Self::segment_path(&self.dir, id);
+        let mut disk = match &self.cipher {
+            Some(cipher) => Disk::new_encrypted(&path, cipher.clone())?,
+            None => Disk::new(&path)?,
+        };
+        disk.set_defrag_threshold(self.defrag_threshold);
+        disk.set_compression_threshold(self.compression_threshold);
+        disk.set_mmap(self.mmap);
+        disk.set_sync_policy(self.sync_policy)?;
+        disk.set_group_commit(self.group_commit)?;
+        Ok(disk)
+    }
+
+    /// A crash while a key moves out of a sealed segment can leave it in two
+    /// segments, the copy in the newer one wins.
+    fn remove_duplicates(&mut self) -> Result<(), Error> {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut stale: Vec<(u64, String)> = Vec::new();
+
+        for (id, disk) in self.segments.iter().rev() {
+            for key in disk.keys() {
+                if !seen.insert(key.clone()) {
+                    stale.push((*id, key.clone()));
+                }
+            }
+        }
+
+        if !stale.is_empty() {
+            warn!("Removing {} stale copies of moved keys", stale.len());
+        }
+        for (id, key) in stale {
+            self.release(id, key)?;
+        }
+        Ok(())
+    }
+
+    /// Drops sealed segments a crash left empty before they were removed.
+    fn remove_empty_segments(&mut self) -> Result<(), Error> {
+        let active_id = self.active_id();
+        let empty: Vec<u64> = self.segments.iter()
+            .filter(|(id, disk)| **id != active_id && disk.keys().next().is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in empty {
+            self.remove_segment(id)?;
+        }
+        Ok(())
+    }
+
+    fn active_id(&self) -> u64 {
+        *self.segments.keys().next_back().expect("Data directory without segments")
+    }
+
+    fn active(&mut self) -> &mut Disk {
+        self.segments.values_mut().next_back().expect("Data directory without segments")
+    }
+
+    /// Returns the id of the segment that holds `key`.
+    fn segment_of(&self, key: &str) -> Option<u64> {
+        self.segments.iter().rev().find(|(_, disk)| disk.contains_key(key)).map(|(id, _)| *id)
+    }
+
+    /// Seals the active segment and starts a new one.
+    fn rotate(&mut self) -> Result<(), Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut segments: Vec<u64> = self.segments.keys().copied().collect();
+        segments.push(id);
+        self.save_manifest(segments)?;
+
+        let disk = self.open_segment(id)?;
+        self.segments.insert(id, disk);
+
+        info!("Started segment {}", id);
+        Ok(())
+    }
+
+    fn rotate_if_full(&mut self) -> Result<(), Error> {
+        let (live_bytes, gap_bytes) = self.active().usage()?;
+        if FileHeader::LEN + live_bytes + gap_bytes < self.segment_limit {
+            return Ok(());
+        }
+        debug!("Active segment exceeded {} bytes", self.segment_limit);
+        self.rotate()
+    }
+
+    /// Deletes `key` from the segment `id` and drops the segment once it is
+    /// sealed and empty.
+    fn release(&mut self, id: u64, key: String) -> Result<(), Error> {
+        let active_id = self.active_id();
+        let disk = self.segments.get_mut(&id).expect("Unknown segment");
+        disk.del(key)?;
+
+        if id != active_id && disk.is_empty()? {
+            self.remove_segment(id)?;
+        }
+        Ok(())
+    }
+
+    fn remove_segment(&mut self, id: u64) -> Result<(), Error> {
+        // Keys moved out of the segment have to be durable in their new
+        // segment before it is gone.
+        self.sync()?;
+
+        let segments: Vec<u64> = self.segments.keys().copied().filter(|segment| *segment != id).collect();
+        self.save_manifest(segments)?;
+
+        drop(self.segments.remove(&id));
+        Self::remove_files(&Self::segment_path(&self.dir, id))?;
+
+        info!("Removed empty segment {}", id);
+        Ok(())
+    }
+
+    pub fn set_segment_size(&mut self, bytes: u64) {
+        self.segment_limit = bytes;
+    }
+
+    pub fn set_defrag_threshold(&mut self, defrag_threshold: Option<f64>) {
+        self.defrag_threshold = defrag_threshold;
+        for disk in self.segments.values_mut() {
+            disk.set_defrag_threshold(defrag_threshold);
+        }
+    }
+
+    pub fn set_compression_threshold(&mut self, compression_threshold: Option<u64>) {
+        self.compression_threshold = compression_threshold;
+        for disk in self.segments.values_mut() {
+            disk.set_compression_threshold(compression_threshold);
+        }
+    }
+
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.mmap = mmap;
+        for disk in self.segments.values_mut() {
+            disk.set_mmap(mmap);
+        }
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) -> Result<(), Error> {
+        self.sync_policy = sync_policy;
+        for disk in self.segments.values_mut() {
+            disk.set_sync_policy(sync_policy)?;
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts every segment with `cipher`, or decrypts them if it is
+    /// `None`, and uses it from then on.
+    pub fn rekey(&mut self, cipher: Option<Cipher>) -> Result<(), Error> {
+        for disk in self.segments.values_mut() {
+            disk.rekey(cipher.clone())?;
+        }
+        self.cipher = cipher;
+        Ok(())
+    }
+
+    /// Returns the paths of the live segments, oldest first. All but the last
+    /// one are sealed.
+    pub fn segments(&self) -> Vec<PathBuf> {
+        self.segments.keys().map(|id| Self::segment_path(&self.dir, *id)).collect()
+    }
+
+    /// Returns the number of compressed entry frames and the bytes
+    /// compression saved in them, over all segments.
+    pub fn compression(&self) -> (u64, u64) {
+        self.segments.values()
+            .map(Disk::compression)
+            .fold((0, 0), |(frames, saved_bytes), (segment_frames, segment_saved_bytes)| {
+                (frames + segment_frames, saved_bytes + segment_saved_bytes)
+            })
+    }
+}
+
+impl StorageBackend for DataDir {
+
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.put_expiring(key, value, None)
+    }
+
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
+        let holder = self.segment_of(&key);
+        let active_id = self.active_id();
+
+        self.active().put_expiring(key.clone(), value, expires_at)?;
+
+        // The key is only removed from the sealed segment once it is written
+        // to the active one, so a crash in between leaves a duplicate rather
+        // than losing the key.
+        if let Some(id) = holder.filter(|id| *id != active_id) {
+            trace!("Move key out of sealed segment {}", id);
+            self.release(id, key)?;
+        }
+
+        self.rotate_if_full()
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        match self.segment_of(&key) {
+            Some(id) => self.segments.get(&id).expect("Unknown segment").get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        match self.segment_of(&key) {
+            Some(id) => self.release(id, key),
+            None => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let mut keys: Vec<String> = Vec::new();
+        for disk in self.segments.values() {
+            keys.extend(disk.list()?);
+        }
+        Ok(keys)
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.sync()?;
+
+        let active_id = self.active_id();
+        self.save_manifest(vec![active_id])?;
+
+        let sealed: Vec<u64> = self.segments.keys().copied().filter(|id| *id != active_id).collect();
+        for id in sealed {
+            drop(self.segments.remove(&id));
+            Self::remove_files(&Self::segment_path(&self.dir, id))?;
+        }
+
+        self.active().clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+        for disk in self.segments.values() {
+            len += disk.len()?;
+        }
+        Ok(len)
+    }
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        for disk in self.segments.values() {
+            if !disk.is_empty()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn supports_expiry(&self) -> bool {
+        true
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>, Error> {
+        match self.segment_of(&key) {
+            Some(id) => self.segments.get(&id).expect("Unknown segment").expires_at(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Reaps every segment on its own and drops the sealed segments that
+    /// end up empty.
+    fn reap(&mut self) -> Result<usize, Error> {
+        let mut reaped = 0;
+        for disk in self.segments.values_mut() {
+            reaped += disk.reap()?;
+        }
+        if reaped > 0 {
+            self.remove_empty_segments()?;
+        }
+        Ok(reaped)
+    }
+
+    /// Compacts every segment on its own.
+    fn defrag(&mut self) -> Result<(), Error> {
+        for disk in self.segments.values_mut() {
+            Disk::defrag(disk)?;
+        }
+        Ok(())
+    }
+
+    fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        self.group_commit = group_commit;
+        for disk in self.segments.values_mut() {
+            disk.set_group_commit(group_commit)?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the newest segment first. Keys only move into newer segments,
+    /// so a key is durable in its new segment before it is gone from the old
+    /// one.
+    fn sync(&mut self) -> Result<(), Error> {
+        for disk in self.segments.values_mut().rev() {
+            disk.sync()?;
+        }
+        Ok(())
+    }
+
+    fn snapshot_file(&mut self, path: &Path) -> Result<Disk, Error> {
+        let mut disk = match self.cipher.clone() {
+            Some(cipher) => Disk::new_encrypted(path, cipher)?,
+            None => Disk::new(path)?,
+        };
+        disk.set_compression_threshold(self.compression_threshold);
+        Ok(disk)
+    }
+}
@@ -1,504 +1,911 @@
-use core::panic;
-use std::{path::Path, fs::{File, OpenOptions}, io::{Error, Write, ErrorKind, Read, Seek, SeekFrom}};
+use std::{path::{Path, PathBuf}, fs::{self, File, OpenOptions}, io::{Error, Write, ErrorKind, Read, Seek, SeekFrom, BufReader, BufWriter}, collections::{HashMap, HashSet}, sync::{Mutex, MutexGuard, PoisonError}};
 
-use log::trace;
+use log::{debug, info, trace, warn};
+use memmap2::{Mmap, MmapOptions};
 
-use super::Value;
+use super::{Value, StorageBackend, Wal, WalWrite, SyncPolicy, Corruption, FileHeader, Cipher, expiry, free::FreeMap, positional::PositionalReader, frame::{self, Frame, FrameReader, FrameHeader, EntryFrame}};
+
+/// Size of the write-ahead log after which the data file is synced and the
+/// log is truncated.
+const WAL_CHECKPOINT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Gap bytes below this amount never trigger an automatic defrag, so small
+/// files are not rewritten on every delete.
+const DEFRAG_MIN_GAP_BYTES: u64 = 64 * 1024;
+
+/// Read ahead when a single frame is read, enough for the header and the
+/// payload of most entries to arrive in a single read.
+const FRAME_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Smallest map of the data file. Maps reach twice as far as the file, so
+/// a growing file is only mapped again each time it doubles.
+const MAP_MIN_BYTES: u64 = 1024 * 1024;
 
 pub struct Disk {
-    pub buf_stream: File
+    pub buf_stream: File,
+    path: PathBuf,
+    header: FileHeader,
+    /// Encrypts the entry frames of an encrypted data file.
+    cipher: Option<Cipher>,
+    wal: Wal,
+    /// Offset of the entry frame of every live key.
+    index: HashMap<String, u64>,
+    /// Time every expiring key expires at. Expired keys stay in `index`
+    /// until they are reaped.
+    expiries: HashMap<String, u64>,
+    /// Offsets of indexed entry frames whose checksum does not match. Reads
+    /// add the ones they run into.
+    corrupted: Mutex<HashSet<u64>>,
+    /// Offsets of entry frames whose key could not be read at all.
+    lost: Vec<u64>,
+    /// Sum of the lengths of all live entry frames.
+    live_bytes: u64,
+    /// Gap frames that new entry frames can be placed into.
+    free: FreeMap,
+    /// Length of the data file once the writes planned so far are applied.
+    file_len: u64,
+    /// Ratio of gap bytes to live bytes above which `defrag` runs on its own.
+    defrag_threshold: Option<f64>,
+    /// Size from which values are written compressed.
+    compression_threshold: Option<u64>,
+    /// Bytes compression saved, by offset of the compressed entry frame.
+    savings: HashMap<u64, u64>,
+    /// Whether entry frames are read from a memory map of the data file.
+    mmap: bool,
+    /// Map of the data file, mapped again whenever the file outgrows it,
+    /// shrinks or is replaced.
+    map: Option<Mmap>,
+    /// Bytes of the data file the map covers. The map reaches beyond them,
+    /// into room the file has yet to grow into.
+    map_len: u64,
+    /// Writes logged in group commit mode, applied to the data file once the
+    /// write-ahead log is synced.
+    pending: Vec<WalWrite>,
+    /// Position in `pending` of the write that places an entry frame, by
+    /// offset of the entry frame. No other live entry frame overlaps a
+    /// pending write.
+    pending_frames: HashMap<u64, usize>,
 }
 
 impl Disk {
 
     pub fn new(path: &Path) -> Result<Self, Error> {
-        let file = Self::initilize_signed_file(path)?;
-        Ok(Self {
-            buf_stream: file
-        })
+        Self::open(path, None)
     }
 
-    fn initilize_signed_file(path: &Path) -> Result<File, Error> {
-        let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .open(path)?;
+    /// Opens a data file whose entry frames are encrypted with `cipher`. A
+    /// data file that is not encrypted yet is encrypted on open.
+    pub fn new_encrypted(path: &Path, cipher: Cipher) -> Result<Self, Error> {
+        Self::open(path, Some(cipher))
+    }
 
-        file.seek(SeekFrom::Start(0))?;
-        if file.metadata()?.len() == 0 {
-            file.write(&Self::signed_buffer())?;
+    fn open(path: &Path, cipher: Option<Cipher>) -> Result<Self, Error> {
+        let created = !path.exists();
+        let target = Self::target_header(cipher.as_ref());
+        let file = Self::initilize_signed_file(path, target)?;
+        let (wal, batches) = Wal::open(&Self::sibling_path(path, "wal"))?;
+        let mut disk = Self {
+            buf_stream: file,
+            path: path.to_path_buf(),
+            header: target,
+            cipher,
+            wal,
+            index: HashMap::new(),
+            expiries: HashMap::new(),
+            corrupted: Mutex::new(HashSet::new()),
+            lost: Vec::new(),
+            live_bytes: 0,
+            free: FreeMap::default(),
+            file_len: 0,
+            defrag_threshold: None,
+            compression_threshold: None,
+            savings: HashMap::new(),
+            mmap: false,
+            map: None,
+            map_len: 0,
+            pending: Vec::new(),
+            pending_frames: HashMap::new(),
+        };
+        if created {
+            debug!("Discarding write-ahead log of a missing data file");
+            disk.wal.reset()?;
+        } else if !batches.is_empty() {
+            info!("Replaying {} batches from the write-ahead log", batches.len());
+            for batch in batches.iter() {
+                disk.apply(batch)?;
+            }
+            disk.checkpoint()?;
         }
 
-        file.seek(SeekFrom::Start(0))?;
-        let mut buf: [u8; 16] = [0; 16];
-        file.read(&mut buf)?;
-        Self::is_signed_buffer_valid(buf)?;
-    
-        Ok(file)
-    }
+        disk.header = disk.read_header()?;
+        disk.header.check_supported()?;
+        disk.check_key()?;
 
-    fn signed_buffer() -> [u8; 16] {
-        let mut buf: [u8; 16] = [0; 16];
-        buf[0] = 'v' as u8;
-        buf[1] = 'a' as u8;
-        buf[2] = 'r' as u8;
-        buf[3] = 'i' as u8;
-        buf[4] = 'a' as u8;
-
-        buf[5] = '-' as u8;
-        buf[6] = '-' as u8;
-        buf[7] = '-' as u8;
-        buf[8] = '-' as u8;
-        buf[9] = '-' as u8;
-        buf[10] = '-' as u8;
-        buf[11] = '-' as u8;
-        buf[12] = '-' as u8;
-        buf[13] = '-' as u8;
-
-        buf[14] = 'd' as u8;
-        buf[15] = 'b' as u8;
-        buf
-    }
-
-    fn is_signed_buffer_valid(buf: [u8; 16]) -> Result<(), Error> {
-        if buf[0] != 'v' as u8 ||
-        buf[1] != 'a' as u8 ||
-        buf[2] != 'r' as u8 ||
-        buf[3] != 'i' as u8 ||
-        buf[4] != 'a' as u8 ||
-        buf[5] != '-' as u8 ||
-        buf[6] != '-' as u8 ||
-        buf[7] != '-' as u8 ||
-        buf[8] != '-' as u8 ||
-        buf[9] != '-' as u8 ||
-        buf[10] != '-' as u8 ||
-        buf[11] != '-' as u8 ||
-        buf[12] != '-' as u8 ||
-        buf[13] != '-' as u8 ||
-        buf[14] != 'd' as u8 ||
-        buf[15] != 'b' as u8 {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid signature"));
+        disk.load_index()?;
+
+        if disk.header.version != target.version {
+            disk.migrate()?;
+        } else if disk.header != target {
+            info!("Encrypting {:?}", disk.path);
+            let cipher = disk.cipher.clone();
+            disk.rewrite(target, true, cipher.as_ref())?;
         }
-        Ok(())
+        Ok(disk)
     }
 
-    fn read_sign(&mut self) -> Result<(), Error> {
-        self.buf_stream.seek(SeekFrom::Start(0))?;
-        let mut signed_buf: [u8; 16] = [0; 16];
-        self.buf_stream.read(&mut signed_buf)?;
-        Self::is_signed_buffer_valid(signed_buf)?;
-        Ok(())
+    /// Header of a data file of the current version with entry frames
+    /// encrypted by `cipher`.
+    fn target_header(cipher: Option<&Cipher>) -> FileHeader {
+        match cipher {
+            Some(cipher) => FileHeader::encrypted(cipher.key_check()),
+            None => FileHeader::current(),
+        }
     }
 
-    fn entry_frame(key: &String, value: &Value) -> Result<Vec<u8>, Error> {
-        let mut buf: Vec<u8> = Vec::new();
+    /// Fails if the data file is encrypted with another key than the one
+    /// given, or without one being given at all.
+    fn check_key(&self) -> Result<(), Error> {
+        if !self.header.is_encrypted() {
+            return Ok(());
+        }
+        match &self.cipher {
+            None => Err(Error::new(ErrorKind::InvalidInput, "Data file is encrypted, but no encryption key was given")),
+            Some(cipher) if cipher.key_check() != self.header.key_check => {
+                Err(Error::new(ErrorKind::InvalidInput, "Wrong encryption key for data file"))
+            },
+            Some(_) => Ok(()),
+        }
+    }
 
-        buf.push(
-            0 as u8
-        );
+    pub fn set_defrag_threshold(&mut self, defrag_threshold: Option<f64>) {
+        self.defrag_threshold = defrag_threshold;
+    }
 
-        let key_buf = postcard::to_allocvec(&key);
-        if let Err(_) = key_buf {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid key"));
-        }
-        let key_buf = key_buf.unwrap();
+    /// Compresses values of at least `compression_threshold` bytes from now
+    /// on, frames already written are left as they are.
+    pub fn set_compression_threshold(&mut self, compression_threshold: Option<u64>) {
+        self.compression_threshold = compression_threshold;
+    }
 
-        let value_buf = postcard::to_allocvec(&value);
-        if let Err(_) = value_buf {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid value"));
-        }
-        let value_buf = value_buf.unwrap();
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) -> Result<(), Error> {
+        self.wal.set_sync_policy(sync_policy)
+    }
 
-        let key_len = key_buf.len() as u128;
-        let value_len = value_buf.len() as u128;
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.mmap = mmap;
+        self.map = None;
+        self.remap();
+    }
 
-        if let None = key_len.checked_add(value_len) {
-            return Err(Error::new(ErrorKind::InvalidData, "Key and value too big"));
+    /// Maps the data file again if reads go through a map and it was dropped.
+    /// Reads go through the file if it cannot be mapped.
+    fn remap(&mut self) {
+        if !self.mmap || self.map.is_some() {
+            return;
         }
+        let file_len = match self.buf_stream.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                warn!("Failed to map {:?}, reading through the file: {}", self.path, e);
+                return;
+            },
+        };
+        let reserve = file_len.saturating_mul(2).max(MAP_MIN_BYTES);
+        trace!("Map data file of {} bytes with room for {}", file_len, reserve);
+        // Safety: the data file is only changed through this `Disk`, which
+        // drops the map before the file shrinks or is replaced. Only the
+        // first `map_len` bytes of the map are read, which the file holds.
+        match unsafe { MmapOptions::new().len(reserve as usize).map(&self.buf_stream) } {
+            Ok(map) => {
+                self.map = Some(map);
+                self.map_len = file_len;
+            },
+            Err(e) => warn!("Failed to map {:?}, reading through the file: {}", self.path, e),
+        }
+    }
 
-        buf.extend_from_slice(&key_len.to_be_bytes());
-        buf.extend_from_slice(&value_len.to_be_bytes());
+    fn corrupted(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.corrupted.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        buf.extend_from_slice(&key_buf);
-        buf.extend_from_slice(&value_buf);
+    fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".");
+        file_name.push(extension);
+        path.with_file_name(file_name)
+    }
 
-        Ok(buf)
+    /// Logs a batch of writes to the write-ahead log and then applies it to
+    /// the data file. In group commit mode the batch is applied by `sync`.
+    fn commit(&mut self, batch: Vec<WalWrite>) -> Result<(), Error> {
+        self.wal.append(&batch)?;
+        self.apply_logged(batch)
     }
 
-    fn gap_frame(len: u128) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
+    /// Starts planning a batch with `release` and `place_entry_frame`, which
+    /// change `free` and `file_len` as they go. Returns the length of the
+    /// file to go back to if the batch is not logged.
+    fn begin_plan(&mut self) -> u64 {
+        self.free.begin();
+        self.file_len
+    }
 
-        if len == 0 {
-            panic!("Gap frame len is 0");
+    /// Commits a batch planned since `begin_plan`. If it cannot be logged,
+    /// `free` and `file_len` are put back as they were, so neither the frames
+    /// it would have replaced nor the bytes it would have written are taken
+    /// for gaps. Once logged the batch is replayed on open even if applying it
+    /// fails, so the plan is kept.
+    fn commit_plan(&mut self, batch: Vec<WalWrite>, file_len: u64) -> Result<(), Error> {
+        if let Err(e) = self.wal.append(&batch) {
+            self.free.undo();
+            self.file_len = file_len;
+            return Err(e);
         }
+        self.free.keep();
+        self.apply_logged(batch)
+    }
 
-        if len < 17 {
-            
-            buf.push(
-                len as u8
-            );
-
-            for _ in 0..len-1 {
-                buf.push(
-                    0 as u8
-                );
-            }
-      
-            buf
+    /// Applies a batch that is in the write-ahead log already.
+    fn apply_logged(&mut self, batch: Vec<WalWrite>) -> Result<(), Error> {
+        if self.wal.is_group_commit() {
+            self.pending.extend(batch);
         } else {
-            buf.push(
-                17 as u8
-            );
-    
-            buf.extend_from_slice(&len.to_be_bytes());
+            self.apply(&batch)?;
+        }
 
-            for _ in 0..len-17 {
-                buf.push(
-                    0 as u8
-                );
-            }
-    
-            buf
+        if self.wal.len()? > WAL_CHECKPOINT_BYTES {
+            self.checkpoint()?;
         }
+        Ok(())
     }
 
-    fn entry_frame_len(buf: [u8; 32]) -> Result<(u128, u128, u128), Error> {
-        let mut len_buf_key: [u8; 16] = [0; 16];
-        len_buf_key.copy_from_slice(&buf[0..16]);
-        let mut len_buf_value: [u8; 16] = [0; 16];
-        len_buf_value.copy_from_slice(&buf[16..32]);
-        let len_key = u128::from_be_bytes(len_buf_key);
-        let len_value = u128::from_be_bytes(len_buf_value);
-        let frame_len = len_key + len_value + 33;
+    fn apply(&mut self, batch: &[WalWrite]) -> Result<(), Error> {
+        for write in batch {
+            match write {
+                WalWrite::Write { offset, bytes } => {
+                    self.buf_stream.seek(SeekFrom::Start(*offset))?;
+                    self.buf_stream.write_all(bytes)?;
+
+                    // Writes within the map show through it, including those
+                    // that grow the file into the room the map left.
+                    let end = offset + bytes.len() as u64;
+                    match &self.map {
+                        Some(map) if end > map.len() as u64 => self.map = None,
+                        Some(_) => self.map_len = self.map_len.max(end),
+                        None => {},
+                    }
+                },
+                WalWrite::Truncate { len } => {
+                    self.map = None;
+                    self.buf_stream.set_len(*len)?;
+                },
+            }
+        }
+        self.remap();
+        Ok(())
+    }
 
-        Ok((frame_len, len_key, len_value))
+    /// Syncs the write-ahead log and applies the writes held back for group
+    /// commit.
+    fn apply_pending(&mut self) -> Result<(), Error> {
+        self.wal.sync_pending()?;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        trace!("Apply {} pending writes", self.pending.len());
+        let pending = std::mem::take(&mut self.pending);
+        self.apply(&pending)?;
+        self.pending_frames.clear();
+        Ok(())
     }
 
-    fn big_gap_frame_len(buf: [u8; 16]) -> Result<u128, Error> {
-        let len = u128::from_be_bytes(buf);
-        Ok(len)
+    /// Syncs the data file and drops the write-ahead log.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        trace!("Checkpoint write-ahead log");
+        self.apply_pending()?;
+        self.buf_stream.sync_data()?;
+        self.wal.reset()
     }
 
-    fn positive_seek(&mut self, skip: usize) -> Result<(), Error> {
-        let mut unskiped = skip;
-        let directon_max: usize = 100000;
-        while unskiped > 0 {
-            if unskiped > directon_max {
-                self.buf_stream.seek(SeekFrom::Current(directon_max as i64))?;
-                unskiped -= directon_max;
-            } else {
-                self.buf_stream.seek(SeekFrom::Current(unskiped as i64))?;
-                unskiped = 0;
+    fn load_index(&mut self) -> Result<(), Error> {
+        self.read_sign()?;
+
+        self.index.clear();
+        self.expiries.clear();
+        self.corrupted().clear();
+        self.lost.clear();
+        self.live_bytes = 0;
+        self.free.clear();
+        self.savings.clear();
+
+        let file_len = self.buf_stream.metadata()?.len();
+        self.file_len = file_len;
+        let mut frames = FrameReader::new(&mut self.buf_stream, file_len, self.header.version)?;
+
+        while let Some(frame) = frames.next_frame()? {
+            let frame = match frame {
+                Frame::Entry(frame) => frame,
+                Frame::Gap(header) => {
+                    // Files written before gaps were merged can hold runs of
+                    // gap frames, which are tracked as one gap.
+                    match self.free.ending_at(header.offset) {
+                        Some((offset, len)) => self.free.insert(offset, len + header.len),
+                        None => self.free.insert(header.offset, header.len),
+                    }
+                    continue;
+                },
+            };
+            let offset = frame.header.offset;
+            let frame_len = frame.header.len;
+
+            match frame.decrypt(self.cipher.as_ref()).and_then(|frame| Ok((frame.key()?, frame))) {
+                Ok((key, frame)) => {
+                    if !frame.intact {
+                        warn!("Checksum mismatch of entry frame at offset {}", offset);
+                        self.corrupted.get_mut().unwrap_or_else(PoisonError::into_inner).insert(offset);
+                    }
+                    if frame.is_compressed() {
+                        self.savings.insert(offset, frame.saved_bytes());
+                    }
+                    if let Some(expires_at) = frame.expires_at() {
+                        self.expiries.insert(key.clone(), expires_at);
+                    }
+                    self.index.insert(key, offset);
+                },
+                Err(_) => {
+                    warn!("Unreadable key of entry frame at offset {}", offset);
+                    self.lost.push(offset);
+                },
             }
+            self.live_bytes += frame_len;
+        }
+
+        if !self.savings.is_empty() {
+            let (frames, saved_bytes) = self.compression();
+            info!("Compression saved {} bytes in {} entry frames", saved_bytes, frames);
+        }
+        let corrupted = self.corrupted().len() + self.lost.len();
+        if corrupted > 0 {
+            warn!("Found {} corrupted entry frames in {:?}", corrupted, self.path);
         }
         Ok(())
     }
 
-    fn negative_seek(&mut self, skip: usize) -> Result<(), Error> {
-        let mut unskiped = skip;
-        let directon_max: usize = 100000;
-        while unskiped > 0 {
-            if unskiped > directon_max {
-                self.buf_stream.seek(SeekFrom::Current(-(directon_max as i64)))?;
-                unskiped -= directon_max;
-            } else {
-                self.buf_stream.seek(SeekFrom::Current(-(unskiped as i64)))?;
-                unskiped = 0;
-            }
+    fn initilize_signed_file(path: &Path, header: FileHeader) -> Result<File, Error> {
+        let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .read(true)
+                .open(path)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header.to_bytes())?;
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf: [u8; 16] = [0; 16];
+        file.read_exact(&mut buf)?;
+        FileHeader::from_bytes(buf)?;
+    
+        Ok(file)
+    }
+
+    fn read_header(&mut self) -> Result<FileHeader, Error> {
+        self.buf_stream.seek(SeekFrom::Start(0))?;
+        let mut buf: [u8; 16] = [0; 16];
+        self.buf_stream.read_exact(&mut buf)?;
+        FileHeader::from_bytes(buf)
+    }
+
+    fn read_sign(&mut self) -> Result<(), Error> {
+        if self.read_header()? != self.header {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid signature"));
         }
         Ok(())
     }
 
-    fn seek_gap(&mut self, opt: u8) -> Result<(), Error> {
-        if opt == 0 {
-            let mut buf: [u8; 32] = [0; 32];
-            self.buf_stream.read(&mut buf)?;
-            let (_, key_len, value_len) = Self::entry_frame_len(buf).unwrap();
+    /// Upgrades a file written in an older format version to the current one.
+    fn migrate(&mut self) -> Result<(), Error> {
+        info!("Migrating {:?} from version {} to version {}", self.path, self.header.version, FileHeader::current().version);
 
-            self.positive_seek(key_len as usize)?;
-            self.positive_seek(value_len as usize)?;
+        let cipher = self.cipher.clone();
+        let target = Self::target_header(cipher.as_ref());
 
+        // Version 3 only added expiring entry frames, so frames of version 2
+        // are read the same and only the header changes.
+        if self.header.version == 2 && self.header.flags == target.flags && self.header.key_check == target.key_check {
+            self.commit(vec![WalWrite::Write { offset: 0, bytes: target.to_bytes().to_vec() }])?;
+            self.checkpoint()?;
+            self.header = target;
             return Ok(());
         }
-        if opt == 17 {
-            let mut buf: [u8; 16] = [0; 16];
-            self.buf_stream.read(&mut buf)?;
-            let frame_len = Self::big_gap_frame_len(buf)?;
-  
-            self.positive_seek(frame_len as usize)?;
-            self.negative_seek(17)?;
 
-            return Ok(());
-        }
-        if opt < 17 {
-            let frame_len = opt;
-            self.positive_seek(frame_len as usize)?;
-            self.negative_seek(1)?;
-            return Ok(());
-        }
-        return Ok(());
+        // Older versions only differ in how frames are encoded, so re-encoding
+        // every entry frame is all that is needed.
+        self.rewrite(target, true, cipher.as_ref())
     }
 
-    fn read_vec(&mut self, vec: &mut Vec<u8>, len: u128) -> Result<(), Error> {
-        let mut unfill = len;
-        while unfill > 0 {
-            let mut  buf: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut buf)?;
-            if bytes_read == 0 {
-                panic!("Unexpected end of file");
-            }
-            vec.push(buf[0]);
-            unfill -= 1;
-        }
+    /// Re-encrypts every entry frame with `cipher`, or decrypts them if it
+    /// is `None`, and uses it from then on.
+    pub fn rekey(&mut self, cipher: Option<Cipher>) -> Result<(), Error> {
+        self.read_sign()?;
+
+        info!("Re-encrypting {:?}", self.path);
+        self.rewrite(Self::target_header(cipher.as_ref()), true, cipher.as_ref())?;
+        self.cipher = cipher;
+
         Ok(())
     }
 
-    pub fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+    /// Reads the header of the entry frame at `offset` and leaves the reader
+    /// behind it.
+    fn entry_frame_reader_at(&self, offset: u64) -> Result<(FrameHeader, BufReader<PositionalReader<'_>>), Error> {
+        let file_len = self.buf_stream.metadata()?.len();
+        let mut reader = BufReader::with_capacity(FRAME_BUFFER_BYTES, PositionalReader::new(&self.buf_stream, offset));
 
-        if let Some(_) = self.get(key.clone())? {
-            self.del(key.clone())?;
+        match frame::read_frame_header(&mut reader, offset, file_len, self.header.version)? {
+            Some(header) if header.is_entry() => Ok((header, reader)),
+            _ => Err(Corruption::error(offset, "Expected entry frame")),
         }
+    }
 
-        self.read_sign()?;
+    /// Returns the bytes from the entry frame at `offset` on with its header
+    /// read off, if they are in memory. Entry frames of pending writes are
+    /// only there, the others are if reads go through a map.
+    fn buffered_entry_frame_at(&self, offset: u64) -> Result<Option<(FrameHeader, &[u8])>, Error> {
+        let (mut reader, start, file_len): (&[u8], u64, u64) = match self.pending_frames.get(&offset) {
+            Some(position) => match &self.pending[*position] {
+                WalWrite::Write { bytes, .. } => (bytes, offset, offset + bytes.len() as u64),
+                WalWrite::Truncate { .. } => unreachable!("Pending entry frame without a write"),
+            },
+            None => match &self.map {
+                Some(map) => (&map[..self.map_len as usize], 0, self.map_len),
+                None => return Ok(None),
+            },
+        };
+
+        reader = reader.get((offset - start) as usize..)
+            .ok_or_else(|| Corruption::error(offset, "Frame exceeds end of file"))?;
+        match frame::read_frame_header(&mut reader, offset, file_len, self.header.version)? {
+            Some(header) if header.is_entry() => Ok(Some((header, reader))),
+            _ => Err(Corruption::error(offset, "Expected entry frame")),
+        }
+    }
 
-        let entry_buf: Vec<u8> = Self::entry_frame(&key, &value)?;
+    fn entry_frame_len_at(&self, offset: u64) -> Result<u64, Error> {
+        if let Some((header, _)) = self.buffered_entry_frame_at(offset)? {
+            return Ok(header.len);
+        }
+        Ok(self.entry_frame_reader_at(offset)?.0.len)
+    }
 
-        loop {
-            let mut opt: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut opt)?;
+    fn entry_frame_at(&self, offset: u64) -> Result<EntryFrame, Error> {
+        if let Some((header, mut reader)) = self.buffered_entry_frame_at(offset)? {
+            return frame::read_entry_frame(&mut reader, header);
+        }
+        let (header, mut reader) = self.entry_frame_reader_at(offset)?;
+        frame::read_entry_frame(&mut reader, header)
+    }
 
-            if bytes_read == 0 {
-                trace!("Append entry");
-                self.buf_stream.write(entry_buf.as_slice())?;
-                break;
-            }
+    /// Returns the encoded entry frame at `offset` as it is.
+    fn raw_entry_frame_at(&mut self, offset: u64) -> Result<Vec<u8>, Error> {
+        self.apply_pending()?;
+        let (header, mut reader) = self.entry_frame_reader_at(offset)?;
+        reader.seek_relative(-(header.header_len as i64))?;
 
-            if opt[0] == 0 {
-                trace!("Skip entry");
-                let mut buf: [u8; 32] = [0; 32];
-                self.buf_stream.read(&mut buf)?;
-                let (_, key_len, value_len) = Self::entry_frame_len(buf)?;
-                self.positive_seek(key_len as usize)?;
-                self.positive_seek(value_len as usize)?;
-                continue;
-            }
+        let mut frame_buf: Vec<u8> = vec![0; header.len as usize];
+        reader.read_exact(&mut frame_buf)?;
+        Ok(frame_buf)
+    }
 
-            if opt[0] == 17 {
-                let mut buf: [u8; 16] = [0; 16];
-                self.buf_stream.read(&mut buf)?;
-                let frame_len = Self::big_gap_frame_len(buf)?;
-                if frame_len > entry_buf.len() as u128 {
-                    trace!("Fill gap frame");
-                    self.negative_seek(17)?;
-                    self.buf_stream.write(&Self::gap_frame(frame_len - entry_buf.len() as u128))?;
-                    self.buf_stream.write(entry_buf.as_slice())?;
-                    break;
-                }
-                if frame_len == entry_buf.len() as u128 {
-                    trace!("Replace gap frame");
-                    self.negative_seek(17)?;
-                    self.buf_stream.write(entry_buf.as_slice())?;
-                    break;
-                }
-                trace!("Skip gap frame");
-                self.positive_seek(frame_len as usize)?;
-                self.negative_seek(17)?;
-                continue;
-            }
+    /// Finds the smallest gap the entry frame fits into, or the end of the
+    /// file, and returns the offset of the entry frame together with the
+    /// writes that place it there.
+    fn place_entry_frame(&mut self, entry_buf: Vec<u8>) -> (u64, Vec<WalWrite>) {
+        let entry_len = entry_buf.len() as u64;
 
-            if opt[0] < 17 {
-                trace!("Skip gap frame");
-                let frame_len = opt[0];
-                self.positive_seek(frame_len as usize)?;
-                self.negative_seek(1)?;
-                continue;
+        if let Some((offset, gap_len)) = self.free.find(entry_len) {
+            self.free.remove(offset);
+
+            if gap_len == entry_len {
+                trace!("Replace gap frame");
+                return (offset, vec![WalWrite::Write { offset, bytes: entry_buf }]);
             }
+
+            trace!("Fill gap frame");
+            let gap_len = gap_len - entry_len;
+            let entry_offset = offset + gap_len;
+            self.free.insert(offset, gap_len);
+            return (entry_offset, vec![
+                WalWrite::Write { offset, bytes: frame::gap_frame_head(gap_len) },
+                WalWrite::Write { offset: entry_offset, bytes: entry_buf },
+            ]);
         }
-        Ok(())
+
+        trace!("Append entry");
+        let offset = self.file_len;
+        self.file_len += entry_len;
+        (offset, vec![WalWrite::Write { offset, bytes: entry_buf }])
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<Value>, Error> {
-        self.read_sign()?;
+    /// Turns `len` bytes at `offset` into a gap, merged with the gaps right
+    /// before and after it, and returns the writes that do so. A gap at the
+    /// end of the file is cut off instead.
+    fn release(&mut self, offset: u64, len: u64) -> Vec<WalWrite> {
+        let mut start = offset;
+        let mut end = offset + len;
+
+        if let Some((gap_offset, _)) = self.free.ending_at(offset) {
+            self.free.remove(gap_offset);
+            start = gap_offset;
+        }
+        if let Some(gap_len) = self.free.remove(end) {
+            end += gap_len;
+        }
 
-        let key_buf = postcard::to_allocvec(&key);
-        if let Err(_) = key_buf {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid key"));
+        if end >= self.file_len {
+            trace!("Truncate trailing gap");
+            self.file_len = start;
+            return vec![WalWrite::Truncate { len: start }];
         }
-        let key_buf = key_buf.unwrap();
 
-        loop {
-            let mut opt: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut opt)?;
+        trace!("Write gap frame");
+        self.free.insert(start, end - start);
 
-            if bytes_read == 0 {
-                return Ok(None);
-            }
+        if start == offset && end == offset + len {
+            return vec![WalWrite::Write { offset, bytes: frame::gap_frame(len) }];
+        }
+        // The released bytes are zeroed so no stale entry frame is left in
+        // the gap, then the merged gap gets a single header.
+        vec![
+            WalWrite::Write { offset, bytes: vec![0; len as usize] },
+            WalWrite::Write { offset: start, bytes: frame::gap_frame_head(end - start) },
+        ]
+    }
 
-            if opt[0] == 0 {
-                trace!("Read entry frame");
-                let mut buf: [u8; 32] = [0; 32];
-                self.buf_stream.read(&mut buf)?;
-                let (_, key_len, value_len) = Self::entry_frame_len(buf)?;
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        expiry::is_expired(self.expiries.get(key).copied(), now)
+    }
 
-                let mut key_buf_read: Vec<u8> = Vec::new();
-                self.read_vec(&mut key_buf_read, key_len)?;
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
 
-                if key_buf_read != key_buf {
-                    self.positive_seek(value_len as usize)?;
-                    continue;
-                }
+    /// Returns the keys of all readable entry frames in no particular order,
+    /// unlike `list` even if some entry frames are corrupted or expired.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
 
-                let mut value_buf_read: Vec<u8> = Vec::new();
-                self.read_vec(&mut value_buf_read, value_len)?;
+    /// Returns the number of compressed entry frames and the bytes
+    /// compression saved in them.
+    pub fn compression(&self) -> (u64, u64) {
+        (self.savings.len() as u64, self.savings.values().sum())
+    }
 
-                let value = postcard::from_bytes(&value_buf_read);
-                if let Err(_) = value {
-                    return Err(Error::new(ErrorKind::InvalidData, "Invalid value"));
-                }
-                let value: Value = value.unwrap();
+    /// Returns the bytes held by live entry frames and by gap frames.
+    pub fn usage(&self) -> Result<(u64, u64), Error> {
+        let gap_bytes = self.file_len.saturating_sub(FileHeader::LEN + self.live_bytes);
+        Ok((self.live_bytes, gap_bytes))
+    }
 
-                return Ok(Some(value));
-            }
+    fn defrag_if_fragmented(&mut self) -> Result<(), Error> {
+        let threshold = match self.defrag_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
 
-            trace!("Skip gap frame");
-            self.seek_gap(opt[0])?;
+        let (live_bytes, gap_bytes) = self.usage()?;
+        if gap_bytes < DEFRAG_MIN_GAP_BYTES {
+            return Ok(());
         }
+        if live_bytes > 0 && (gap_bytes as f64) / (live_bytes as f64) < threshold {
+            return Ok(());
+        }
+
+        debug!("Gap ratio exceeded {} with {} gap bytes and {} live bytes", threshold, gap_bytes, live_bytes);
+        self.defrag()
+    }
+
+    fn sync_parent(path: &Path) -> Result<(), Error> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()
     }
 
-    pub fn del(&mut self, key: String) -> Result<(), Error> {
+    /// Rewrites all live entry frames into a fresh file without any gaps and
+    /// atomically swaps it with the current one.
+    pub fn defrag(&mut self) -> Result<(), Error> {
         self.read_sign()?;
 
-        let key_buf = postcard::to_allocvec(&key);
-        if let Err(_) = key_buf {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid key"));
+        self.rewrite(self.header, false, None)?;
+
+        Ok(())
+    }
+
+    /// Writes all live entry frames behind `header` into a fresh file and
+    /// atomically swaps it with the current one. With `reencode` every frame
+    /// is decoded and written in the current frame layout and encrypted with
+    /// `cipher`, otherwise the frames are copied as they are.
+    fn rewrite(&mut self, header: FileHeader, reencode: bool, cipher: Option<&Cipher>) -> Result<(), Error> {
+        let corrupted = self.corrupted().iter().min().copied();
+        if let Some(offset) = self.lost.first().copied().or(corrupted) {
+            return Err(Corruption::error(offset, "Refusing to rewrite a corrupted data file"));
         }
-        let key_buf = key_buf.unwrap();
 
-        loop {
-            let mut opt: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut opt)?;
+        // The write-ahead log refers to offsets in the current file, so it
+        // has to be empty before the file is swapped.
+        self.checkpoint()?;
 
-            if bytes_read == 0 {
-                return Ok(());
-            }
+        let rewrite_path = Self::sibling_path(&self.path, "rewrite");
 
-            if opt[0] == 0 {
-                trace!("Read entry frame");
+        let mut entries: Vec<(String, u64)> = self.index.iter().map(|(key, offset)| (key.clone(), *offset)).collect();
+        entries.sort_by_key(|(_, offset)| *offset);
 
-                let mut buf: [u8; 32] = [0; 32];
-                self.buf_stream.read(&mut buf)?;
-                let (frame_len, key_len, value_len) = Self::entry_frame_len(buf)?;
+        let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&rewrite_path)?;
 
-                let mut key_buf_read: Vec<u8> = Vec::new();
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header.to_bytes())?;
 
-                self.read_vec(&mut key_buf_read, key_len)?;
+        let mut index: HashMap<String, u64> = HashMap::with_capacity(entries.len());
+        let mut savings: HashMap<u64, u64> = HashMap::new();
+        let mut offset: u64 = FileHeader::LEN;
+        let mut live_bytes: u64 = 0;
 
-                if key_buf_read != key_buf {
-                    self.positive_seek(value_len as usize)?;
-                    continue;
+        for (key, old_offset) in entries {
+            let (frame_buf, saved) = if reencode {
+                let entry = self.entry_frame_at(old_offset)?;
+                if !entry.intact {
+                    return Err(Corruption::error(old_offset, "Checksum mismatch"));
                 }
+                let entry = entry.decrypt(self.cipher.as_ref())?;
+                frame::encode_entry_frame(&key, &entry.value()?, entry.expires_at(), self.compression_threshold, cipher)?
+            } else {
+                (self.raw_entry_frame_at(old_offset)?, self.savings.get(&old_offset).copied().unwrap_or(0))
+            };
 
-                self.positive_seek(value_len as usize)?;
-                self.negative_seek(frame_len as usize)?;
-
-                trace!("Write gap frame");
-                self.buf_stream.write(&Self::gap_frame(frame_len))?;
-                return Ok(());
+            writer.write_all(&frame_buf)?;
+            if saved > 0 {
+                savings.insert(offset, saved);
             }
-            
-            trace!("Skip gap frame");
-            self.seek_gap(opt[0])?;
+            index.insert(key, offset);
+            offset += frame_buf.len() as u64;
+            live_bytes += frame_buf.len() as u64;
         }
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        self.map = None;
+        fs::rename(&rewrite_path, &self.path)?;
+        Self::sync_parent(&self.path)?;
+
+        let (_, gap_bytes) = self.usage()?;
+        info!("Rewrote {:?}, reclaimed {} gap bytes", self.path, gap_bytes);
+
+        self.buf_stream = file;
+        self.remap();
+        self.header = header;
+        self.index = index;
+        self.savings = savings;
+        self.live_bytes = live_bytes;
+        self.free.clear();
+        self.file_len = offset;
+
+        Ok(())
     }
+}
+
+impl StorageBackend for Disk {
 
-    pub fn list(&mut self) -> Result<Vec<String>, Error> {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.put_expiring(key, value, None)
+    }
+
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
         self.read_sign()?;
 
-        let mut keys: Vec<String> = Vec::new();
+        let (entry_buf, saved) = frame::encode_entry_frame(&key, &value, expires_at, self.compression_threshold, self.cipher.as_ref())?;
+        let entry_len = entry_buf.len() as u64;
+
+        let mut batch: Vec<WalWrite> = Vec::new();
+
+        let file_len = self.begin_plan();
+        let mut old_frame_len: u64 = 0;
+        let offset = match self.index.get(&key).copied() {
+            Some(old_offset) => {
+                old_frame_len = self.entry_frame_len_at(old_offset)?;
+                if entry_len <= old_frame_len {
+                    trace!("Update entry frame in place");
+                    batch.push(WalWrite::Write { offset: old_offset, bytes: entry_buf });
+                    if entry_len < old_frame_len {
+                        batch.extend(self.release(old_offset + entry_len, old_frame_len - entry_len));
+                    }
+                    old_offset
+                } else {
+                    batch.extend(self.release(old_offset, old_frame_len));
+                    let (offset, writes) = self.place_entry_frame(entry_buf);
+                    batch.extend(writes);
+                    offset
+                }
+            },
+            None => {
+                let (offset, writes) = self.place_entry_frame(entry_buf);
+                batch.extend(writes);
+                offset
+            },
+        };
+
+        self.commit_plan(batch, file_len)?;
+        let placed = self.pending.iter().rposition(|write| matches!(write, WalWrite::Write { offset: write_offset, .. } if *write_offset == offset));
+        if let Some(position) = placed {
+            self.pending_frames.insert(offset, position);
+        }
 
-        loop {
-            let mut opt: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut opt)?;
-            if bytes_read == 0 {
-                return Ok(keys);
-            }
-            if opt[0] == 0 {
-                let mut buf: [u8; 32] = [0; 32];
-                self.buf_stream.read(&mut buf)?;
-                let (_, key_len, value_len) = Self::entry_frame_len(buf)?;
+        if let Some(old_offset) = self.index.insert(key.clone(), offset) {
+            self.corrupted().remove(&old_offset);
+            self.savings.remove(&old_offset);
+        }
+        if saved > 0 {
+            self.savings.insert(offset, saved);
+        }
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key, expires_at),
+            None => self.expiries.remove(&key),
+        };
+        self.live_bytes = self.live_bytes + entry_len - old_frame_len;
 
-                let mut key_buf_read: Vec<u8> = Vec::new();
-                self.read_vec(&mut key_buf_read, key_len)?;
+        self.defrag_if_fragmented()
+    }
 
-                let key = postcard::from_bytes(&key_buf_read);
-                if let Err(_) = key {
-                    return Err(Error::new(ErrorKind::InvalidData, "Invalid key"));
-                }
-                let key: String = key.unwrap();
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        let offset = match self.index.get(&key) {
+            Some(_) if self.is_expired(&key, expiry::now()) => return Ok(None),
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+
+        if self.corrupted().contains(&offset) {
+            return Err(Corruption::error(offset, "Checksum mismatch"));
+        }
 
-                keys.push(key);
+        trace!("Read entry frame");
+        let frame = self.entry_frame_at(offset)?;
 
-                self.positive_seek(value_len as usize)?;
-                continue;
-            }
+        if !frame.intact {
+            warn!("Checksum mismatch of entry frame at offset {}", offset);
+            self.corrupted().insert(offset);
+            return Err(Corruption::error(offset, "Checksum mismatch"));
+        }
+
+        Ok(Some(frame.decrypt(self.cipher.as_ref())?.value()?))
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        let offset = match self.index.get(&key) {
+            Some(offset) => *offset,
+            None => return Ok(()),
+        };
+
+        let frame_len = self.entry_frame_len_at(offset)?;
+
+        let file_len = self.begin_plan();
+        let batch = self.release(offset, frame_len);
+        self.commit_plan(batch, file_len)?;
+
+        self.index.remove(&key);
+        self.expiries.remove(&key);
+        self.corrupted().remove(&offset);
+        self.savings.remove(&offset);
+        self.live_bytes -= frame_len;
 
-            self.seek_gap(opt[0])?;
+        self.defrag_if_fragmented()
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        if let Some(offset) = self.lost.first() {
+            return Err(Corruption::error(*offset, "Unreadable key"));
         }
+
+        let now = expiry::now();
+        let mut entries: Vec<(&String, &u64)> = self.index.iter()
+            .filter(|(key, _)| !self.is_expired(key, now))
+            .collect();
+        entries.sort_by_key(|(_, offset)| **offset);
+        Ok(entries.into_iter().map(|(key, _)| key.clone()).collect())
     }
 
-    pub fn clear(&mut self) -> Result<(), Error> {
+    fn clear(&mut self) -> Result<(), Error> {
         self.read_sign()?;
 
-        self.buf_stream.set_len(0)?;
+        self.commit(vec![WalWrite::Truncate { len: FileHeader::LEN }])?;
 
-        self.buf_stream.seek(SeekFrom::Start(0))?;
-        self.buf_stream.write(&Self::signed_buffer())?;
+        self.index.clear();
+        self.expiries.clear();
+        self.corrupted().clear();
+        self.lost.clear();
+        self.savings.clear();
+        self.live_bytes = 0;
+        self.free.clear();
+        self.file_len = FileHeader::LEN;
 
         Ok(())
     }
 
-    pub fn len(&mut self) -> Result<usize, Error> {
-        self.read_sign()?;
-        
-        let mut len: usize = 0;
-
-        loop {
-            let mut opt: [u8; 1] = [0; 1];
-            let bytes_read = self.buf_stream.read(&mut opt)?;
-            if bytes_read == 0 {
-                return Ok(len);
-            }
-            if opt[0] == 0 {
-                let mut buf: [u8; 32] = [0; 32];
-                self.buf_stream.read(&mut buf)?;
-                let (_, key_len, value_len) = Self::entry_frame_len(buf)?;
+    fn len(&self) -> Result<usize, Error> {
+        let now = expiry::now();
+        let expired = self.expiries.values().filter(|expires_at| **expires_at <= now).count();
+        Ok(self.index.len() - expired)
+    }
 
-                len = len + 1;
+    fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
 
-                self.positive_seek(key_len as usize)?;
-                self.positive_seek(value_len as usize)?;
-                continue;
-            }
+    fn supports_expiry(&self) -> bool {
+        true
+    }
 
-            self.seek_gap(opt[0])?
+    fn expires_at(&self, key: String) -> Result<Option<u64>, Error> {
+        if self.is_expired(&key, expiry::now()) {
+            return Ok(None);
         }
+        Ok(self.expiries.get(&key).copied())
     }
 
-    pub fn is_empty(&mut self) -> Result<bool, Error> {
-        self.read_sign()?;
-        if self.len()? == 0 {
-            return Ok(true);
+    fn reap(&mut self) -> Result<usize, Error> {
+        let now = expiry::now();
+        let expired: Vec<String> = self.expiries.iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if !expired.is_empty() {
+            debug!("Reaping {} expired keys", expired.len());
+        }
+        for key in &expired {
+            self.del(key.clone())?;
         }
-        Ok(false)
+        Ok(expired.len())
     }
 
-    pub fn defrag(&mut self) -> Result<(), Error> {
-        self.read_sign()?;
+    fn defrag(&mut self) -> Result<(), Error> {
+        Disk::defrag(self)
+    }
+
+    fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        if !group_commit {
+            self.apply_pending()?;
+        }
+        self.wal.set_group_commit(group_commit)
+    }
 
-        // TODO: Implement
+    fn sync(&mut self) -> Result<(), Error> {
+        self.apply_pending()
+    }
 
+    fn snapshot_file(&mut self, path: &Path) -> Result<Disk, Error> {
+        let mut disk = match self.cipher.clone() {
+            Some(cipher) => Disk::new_encrypted(path, cipher)?,
+            None => Disk::new(path)?,
+        };
+        disk.set_compression_threshold(self.compression_threshold);
+        Ok(disk)
+    }
+}
 
-        Ok(())
+impl Drop for Disk {
+    fn drop(&mut self) {
+        if let Err(e) = self.checkpoint() {
+            warn!("Failed to checkpoint {:?}: {}", self.path, e);
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,30 +1,127 @@
 use std::io::Error;
 use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::time::Duration;
 use log::debug;
 use log::info;
-use moka::future::Cache;
+use log::warn;
 
-use super::Disk;
+use super::StorageBackend;
 use super::Value;
+use super::primary::{Primary, CacheStats};
+use super::snapshot::Snapshotting;
+use super::actor::{StorageActor, DEFAULT_QUEUE_SIZE};
+use super::stripes::Stripes;
+use super::write_back::WriteBack;
+use super::expiry;
+use super::condition::{Condition, Conflict};
+use super::ndjson::{self, ConflictPolicy, ImportReport, ImportFailure};
+
+/// Number of keys a snapshot copies before it lets other requests at the
+/// secondary storage again.
+const SNAPSHOT_BATCH: usize = 256;
+
+/// Number of locks the keys are spread over.
+const KEY_LOCKS: usize = 64;
 
 pub struct Engine {
-    secondary: Arc<Mutex<Disk>>,
-    primary: Cache<String, Option<Value>>,
+    secondary: Arc<StorageActor>,
+    primary: Primary,
+    /// Writes hold the lock of their key until both storages are updated,
+    /// cache misses hold it shared until the value read is cached, so the
+    /// primary storage never falls behind the secondary one.
+    keys: Arc<Stripes>,
+    /// Set in write-back mode, where writes only reach the secondary storage
+    /// when they are flushed.
+    write_back: Option<Arc<WriteBack>>,
+    /// Whether the secondary storage stores expiring keys. Others are
+    /// refused before they are cached, as they could never be flushed.
+    supports_expiry: bool,
 }
 
 impl Engine {
-    pub fn new(secondary: Disk, primary: Cache<String, Option<Value>>) -> Self {
-        let secondary = Arc::new(Mutex::new(secondary));
+    pub fn new(secondary: impl StorageBackend + 'static, primary: impl Into<Primary>) -> Self {
+        Self::with_queue_size(secondary, primary, DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Runs the secondary storage on its own thread behind a queue of
+    /// `queue_size` requests.
+    pub fn with_queue_size(secondary: impl StorageBackend + 'static, primary: impl Into<Primary>, queue_size: usize) -> Self {
+        let supports_expiry = secondary.supports_expiry();
+        let secondary = Snapshotting::new(Box::new(secondary));
+        let secondary = Arc::new(StorageActor::spawn(secondary, queue_size));
         Self {
             secondary,
-            primary,
+            primary: primary.into(),
+            keys: Arc::new(Stripes::new(KEY_LOCKS)),
+            write_back: None,
+            supports_expiry,
+        }
+    }
+
+    /// Switches to write-back mode. Writes return once they are cached and
+    /// are flushed to the secondary storage in batches, every `interval` and
+    /// whenever `limit` keys are waiting. Writes that are not flushed are lost
+    /// if the process stops, so `flush` has to be called before it does.
+    /// Must be called from within the async runtime, before the `Engine` is
+    /// cloned.
+    pub fn set_write_back(&mut self, interval: Duration, limit: usize) {
+        self.write_back = Some(WriteBack::spawn(&self.secondary, interval, limit));
+    }
+
+    /// Deletes the keys that have expired from both storages every
+    /// `interval`, until the `Engine` is dropped. Must be called from within
+    /// the async runtime.
+    pub fn set_reap_interval(&mut self, interval: Duration) {
+        let secondary = Arc::downgrade(&self.secondary);
+        let primary = self.primary.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let secondary = match secondary.upgrade() {
+                    Some(secondary) => secondary,
+                    None => break,
+                };
+                if let Err(e) = Self::reap_expired(&secondary, &primary).await {
+                    warn!("Failed to reap expired keys: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn reap_expired(secondary: &StorageActor, primary: &Primary) -> Result<usize, Error> {
+        let reaped = secondary.write(|secondary| secondary.reap()).await?;
+        primary.expire_all().await;
+        if reaped > 0 {
+            debug!("Reaped {} expired keys", reaped);
         }
+        Ok(reaped)
     }
 
-    fn key_validation(key: &String) -> Result<(), Error> {
-        if key.len() < 1 {
+    /// Deletes the keys that have expired and returns how many there were in
+    /// the secondary storage.
+    pub async fn reap(&self) -> Result<usize, Error> {
+        info!("REAP");
+
+        Self::reap_expired(&self.secondary, &self.primary).await
+    }
+
+    /// Writes everything that is only cached to the secondary storage and
+    /// returns the number of keys written. Does nothing unless in write-back
+    /// mode.
+    pub async fn flush(&self) -> Result<usize, Error> {
+        match &self.write_back {
+            Some(write_back) => {
+                info!("FLUSH");
+                write_back.flush(&self.secondary).await
+            },
+            None => Ok(0),
+        }
+    }
+
+    fn key_validation(key: &str) -> Result<(), Error> {
+        if key.is_empty() {
             return Err(
                 Error::new(
                     ErrorKind::InvalidInput,
@@ -45,69 +142,127 @@ impl Engine {
         Ok(())
     }
 
+    /// Runs the write `f` on the secondary storage. If it fails, `key` may
+    /// or may not have changed, so it is dropped from the primary storage.
+    async fn write_key<R, F>(&self, key: &str, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Snapshotting) -> Result<R, Error> + Send + 'static,
+    {
+        let result = self.secondary.write(f).await;
+        if result.is_err() {
+            debug!("Invalidating primary storage");
+            self.primary.invalidate(key).await;
+        }
+        result
+    }
+
+    /// Returns the current value of `key` in write-back mode, where the
+    /// secondary storage may not have it yet. The caller holds the lock of
+    /// `key`.
+    async fn current(&self, key: &str, write_back: &WriteBack) -> Result<Option<Value>, Error> {
+        if let Some(value) = self.primary.get(key).await {
+            return Ok(value);
+        }
+        if let Some(value) = write_back.get(key) {
+            return Ok(value);
+        }
+        let key = key.to_string();
+        self.secondary.read(move |secondary| secondary.get(key)).await
+    }
+
     pub async fn put(&self, key: String, value: Value) -> Result<Option<Value>, Error> {
-        info!("PUT {:?} {:?}", key, value);
+        self.put_with_ttl(key, value, None).await
+    }
+
+    /// Stores `value` under `key` like `put`, but only for `ttl` if it is
+    /// set. Once it has passed the key reads as missing.
+    pub async fn put_with_ttl(&self, key: String, value: Value, ttl: Option<Duration>) -> Result<Option<Value>, Error> {
+        info!("PUT {:?} {:?} {:?}", key, value, ttl);
+
+        self.put_checked(key, value, ttl, None).await
+    }
+
+    /// Stores `value` under `key` like `put_with_ttl` if `condition` holds for
+    /// the current value, as one step no other write of the key can come
+    /// between. Fails with a `Conflict` carrying the current value otherwise.
+    pub async fn put_if(&self, key: String, value: Value, ttl: Option<Duration>, condition: Condition) -> Result<Option<Value>, Error> {
+        info!("PUT {:?} {:?} {:?} IF {:?}", key, value, ttl, condition);
 
+        self.put_checked(key, value, ttl, Some(condition)).await
+    }
+
+    async fn put_checked(&self, key: String, value: Value, ttl: Option<Duration>, condition: Option<Condition>) -> Result<Option<Value>, Error> {
         Self::key_validation(&key)?;
-        
-        if let Some(current_value) = self.primary.get(&key).await {
 
-            debug!("Cache hit for key {:?} with value {:?}", key, current_value);
+        if ttl.is_some() && !self.supports_expiry {
+            return Err(
+                Error::new(
+                    ErrorKind::Unsupported,
+                    "Expiring keys are not supported by this storage backend"
+                )
+            );
+        }
 
-            debug!("Updating secondary storage");
-            debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-            self.secondary.lock().unwrap().put(key.clone(), value.clone())?;
+        let expires_at = ttl.map(expiry::expires_in);
 
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), value.clone());
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, Some(value_clone)).await;
-            });
+        let _lock = self.keys.write(&key).await;
+
+        if let Some(write_back) = &self.write_back {
+            let current_value = self.current(&key, write_back).await?;
+
+            if let Some(condition) = &condition {
+                condition.check(&current_value)?;
+            }
+
+            debug!("Marking key {:?} dirty", key);
+            write_back.mark(key.clone(), Some(value.clone()), expires_at);
+
+            debug!("Updating primary storage");
+            self.primary.insert_expiring(key, Some(value), expires_at).await;
 
             debug!("Returning old value");
             return Ok(current_value);
-
-        } 
+        }
         
-        let mut secondary = self.secondary.lock().unwrap();
+        if let Some(current_value) = self.primary.get(&key).await {
 
-        if let Some(current_value) = &secondary.get(key.clone())? {
+            debug!("Cache hit for key {:?} with value {:?}", key, current_value);
 
-            debug!("Cache miss for key {:?} with value {:?}", key, current_value);
+            if let Some(condition) = &condition {
+                condition.check(&current_value)?;
+            }
 
             debug!("Updating secondary storage");
-            debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-            secondary.put(key.clone(), value.clone())?;
-            
-            let primary = self.primary.clone();
             let (key_clone, value_clone) = (key.clone(), value.clone());
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, Some(value_clone)).await;
-            });
+            self.write_key(&key, move |secondary| secondary.put_expiring(key_clone, value_clone, expires_at)).await?;
+
+            debug!("Updating primary storage");
+            self.primary.insert_expiring(key, Some(value), expires_at).await;
 
             debug!("Returning old value");
-            return Ok(Some(current_value.clone()));
+            return Ok(current_value);
 
-        } else {
-            debug!("Cache miss for key {:?}", key);
+        } 
+        
+        debug!("Updating secondary storage");
+        let (key_clone, value_clone) = (key.clone(), value.clone());
+        let current_value = self.write_key(&key, move |secondary| {
+            let current_value = secondary.get(key_clone.clone())?;
+            if let Some(condition) = &condition {
+                condition.check(&current_value)?;
+            }
+            secondary.put_expiring(key_clone, value_clone, expires_at)?;
+            Ok(current_value)
+        }).await?;
 
-            debug!("Updating secondary storage");
-            debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-            secondary.put(key.clone(), value.clone())?;
-            
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), value.clone());
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, Some(value_clone)).await;
-            });
+        debug!("Cache miss for key {:?} with value {:?}", key, current_value);
 
-            debug!("Returning old value");
-            return Ok(None);
+        debug!("Updating primary storage");
+        self.primary.insert_expiring(key, Some(value), expires_at).await;
 
-        }
+        debug!("Returning old value");
+        Ok(current_value)
     }
 
     pub async fn get(&self, key: String) -> Result<Option<Value>, Error> {
@@ -115,7 +270,7 @@ impl Engine {
         
         Self::key_validation(&key)?;
 
-        if let Some(value) = self.primary.get(&key).await {
+        if let Some(value) = self.primary.lookup(&key).await {
 
             debug!("Cache hit for key {:?} with value {:?}", key, value);
 
@@ -124,116 +279,258 @@ impl Engine {
 
         } 
 
-        let mut secondary = self.secondary.lock().unwrap(); 
-        
-        if let Some(value) = &secondary.get(key.clone())? {
-
-            debug!("Cache miss for key {:?} with value {:?}", key, value);
+        let _lock = self.keys.read(&key).await;
 
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), value.clone());
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, Some(value_clone)).await;
-            });
+        if let Some(value) = self.write_back.as_ref().and_then(|write_back| write_back.get(&key)) {
+            debug!("Unflushed write for key {:?} with value {:?}", key, value);
+            return Ok(value);
+        }
 
-            debug!("Returning value");
-            return Ok(Some(value.clone()));
+        // Concurrent misses of the key wait for a single read of the
+        // secondary storage, which also updates the primary storage.
+        let key_clone = key.clone();
+        let value = self.primary.get_with(&key, async {
+            debug!("Cache miss for key {:?}", key);
+            self.secondary.read(move |secondary| {
+                Ok((secondary.get(key_clone.clone())?, secondary.expires_at(key_clone)?))
+            }).await
+        }).await?;
 
-        } else {
+        debug!("Returning value {:?}", value);
+        Ok(value)
+    }
 
-            debug!("Cache miss for key {:?}", key);
+    pub async fn del(&self, key: String) -> Result<Option<Value>, Error> {
+        info!("DEL {:?}", key);
 
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), None);
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, value_clone).await;
-            });
+        self.del_checked(key, None).await
+    }
 
-            debug!("Returning value");
-            return Ok(None);
+    /// Deletes `key` like `del` if its current value equals `expected`, as
+    /// one step no other write of the key can come between. Fails with a
+    /// `Conflict` carrying the current value otherwise.
+    pub async fn del_if(&self, key: String, expected: Value) -> Result<Option<Value>, Error> {
+        info!("DEL {:?} IF {:?}", key, expected);
 
-        }
+        self.del_checked(key, Some(Condition::Equals(expected))).await
     }
 
-    pub async fn del(&self, key: String) -> Result<Option<Value>, Error> {
-        info!("DEL {:?}", key);
+    async fn del_checked(&self, key: String, condition: Option<Condition>) -> Result<Option<Value>, Error> {
         Self::key_validation(&key)?;
 
-        
-        if let Some(value) = self.primary.get(&key).await {
+        let _lock = self.keys.write(&key).await;
 
-            debug!("Cache hit for key {:?} with value {:?}", key, value);
+        if let Some(write_back) = &self.write_back {
+            let value = self.current(&key, write_back).await?;
 
-            debug!("Updating secondary storage");
-            debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-            self.secondary.lock().unwrap().del(key.clone())?;
-            
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), None);
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, value_clone).await;
-            });
+            if let Some(condition) = &condition {
+                condition.check(&value)?;
+            }
 
-            debug!("Returning old value");
-            return Ok(value);
+            if value.is_some() {
+                debug!("Marking key {:?} dirty", key);
+                write_back.mark(key.clone(), None, None);
+            }
 
-        } 
+            debug!("Updating primary storage");
+            self.primary.insert(key, None).await;
 
-        let mut secondary = self.secondary.lock().unwrap();
+            debug!("Returning old value");
+            return Ok(value);
+        }
         
-        if let Some(value) = &secondary.get(key.clone())? {
+        if let Some(value) = self.primary.get(&key).await {
+
+            debug!("Cache hit for key {:?} with value {:?}", key, value);
 
-            debug!("Cache miss for key {:?} with value {:?}", key, value);
+            if let Some(condition) = &condition {
+                condition.check(&value)?;
+            }
 
             debug!("Updating secondary storage");
-            debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-            secondary.del(key.clone())?;
+            let key_clone = key.clone();
+            self.write_key(&key, move |secondary| secondary.del(key_clone)).await?;
             
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), None);
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, value_clone).await;
-            });
+            debug!("Updating primary storage");
+            self.primary.insert(key, None).await;
 
             debug!("Returning old value");
-            return Ok(Some(value.clone()));
+            return Ok(value);
 
-        } else {
+        } 
 
-            debug!("Cache miss for key {:?}", key);
+        debug!("Updating secondary storage");
+        let key_clone = key.clone();
+        let value = self.write_key(&key, move |secondary| {
+            let value = secondary.get(key_clone.clone())?;
+            if let Some(condition) = &condition {
+                condition.check(&value)?;
+            }
+            if value.is_some() {
+                secondary.del(key_clone)?;
+            }
+            Ok(value)
+        }).await?;
 
-            let primary = self.primary.clone();
-            let (key_clone, value_clone) = (key.clone(), None);
-            tokio::task::spawn(async move {
-                debug!("Updating primary storage");
-                primary.insert(key_clone, value_clone).await;
-            });
+        debug!("Cache miss for key {:?} with value {:?}", key, value);
 
-            debug!("Returning old value");
-            return Ok(None);
+        debug!("Updating primary storage");
+        self.primary.insert(key, None).await;
 
-        }
-        
+        debug!("Returning old value");
+        Ok(value)
     }
 
     pub async fn list(&self) -> Result<Vec<String>, Error> {
         info!("LIST");
 
+        self.flush().await?;
+
         debug!("Use secondary storage");
-        debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-        return Ok(self.secondary.lock().unwrap().list()?);
+        self.secondary.read(|secondary| secondary.list()).await
     }
 
     pub async fn clear(&self) -> Result<(), Error> {
         info!("CLEAR");
 
+        let _locks = self.keys.write_all().await;
+        let _flushing = match &self.write_back {
+            Some(write_back) => Some(write_back.discard().await),
+            None => None,
+        };
+
         debug!("Updating secondary storage");
-        debug!("Secondary is poisoned: {:?}", self.secondary.is_poisoned());
-        return Ok(self.secondary.lock().unwrap().clear()?);
+        let result = self.secondary.write(|secondary| secondary.clear()).await;
+
+        debug!("Invalidating primary storage");
+        self.primary.invalidate_all();
+        result
+    }
+
+    pub async fn defrag(&self) -> Result<(), Error> {
+        info!("DEFRAG");
+
+        debug!("Defragmenting secondary storage");
+        self.secondary.run(|secondary| secondary.defrag()).await
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        info!("STATS");
+
+        let mut stats = self.primary.stats().await;
+        if let Some(write_back) = &self.write_back {
+            (stats.unflushed, stats.unflushed_bytes) = write_back.unflushed();
+        }
+        stats
+    }
+
+    /// Returns the keys to export in order, all of them or those starting with
+    /// `prefix`.
+    pub async fn export_keys(&self, prefix: Option<&str>) -> Result<Vec<String>, Error> {
+        info!("EXPORT {:?}", prefix);
+
+        self.flush().await?;
+
+        let mut keys = self.secondary.read(|secondary| secondary.list()).await?;
+        if let Some(prefix) = prefix {
+            keys.retain(|key| key.starts_with(prefix));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Encodes the entries of `keys` as NDJSON, one line per entry. Keys that
+    /// were deleted since they were listed are left out.
+    pub async fn export(&self, keys: &[String]) -> Result<Vec<u8>, Error> {
+        debug!("Exporting {} keys", keys.len());
+
+        let keys = keys.to_vec();
+        self.secondary.read(move |secondary| {
+            let mut buf: Vec<u8> = Vec::new();
+            for key in keys {
+                if let Some(value) = secondary.get(key.clone())? {
+                    ndjson::write_record(&mut buf, &key, &value)?;
+                }
+            }
+            Ok(buf)
+        }).await
+    }
+
+    /// Stores `records` one after another, deciding by `policy` what happens
+    /// to keys that already exist. Every key is validated first, and with
+    /// `ConflictPolicy::Fail` nothing is stored if any key exists. A key is
+    /// checked and stored as one step, so a key another write creates during
+    /// the import is never overwritten unless `policy` says so. An import
+    /// that stops part way fails with an `ImportFailure` telling what it did.
+    pub async fn import(&self, records: Vec<(String, Value)>, policy: ConflictPolicy) -> Result<ImportReport, Error> {
+        info!("IMPORT {} records with {:?}", records.len(), policy);
+
+        for (key, _) in &records {
+            Self::key_validation(key)?;
+        }
+        if policy == ConflictPolicy::Fail {
+            for (key, _) in &records {
+                if self.get(key.clone()).await?.is_some() {
+                    return Err(Self::exists(key));
+                }
+            }
+        }
+
+        let condition = match policy {
+            ConflictPolicy::Overwrite => None,
+            ConflictPolicy::Skip | ConflictPolicy::Fail => Some(Condition::Absent),
+        };
+        let mut report = ImportReport::default();
+        for (key, value) in records {
+            match self.put_checked(key.clone(), value, None, condition.clone()).await {
+                Ok(_) => report.imported += 1,
+                Err(e) if Conflict::find(&e).is_some() && policy == ConflictPolicy::Skip => report.skipped += 1,
+                Err(e) if Conflict::find(&e).is_some() => return Err(ImportFailure::error(report, Self::exists(&key))),
+                Err(e) => return Err(ImportFailure::error(report, e)),
+            }
+        }
+        Ok(report)
+    }
+
+    fn exists(key: &str) -> Error {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Key {:?} already exists", key)
+        )
+    }
+
+    /// Writes a consistent copy of the secondary storage as it is now into
+    /// the data file at `path`. Requests are served while the snapshot is
+    /// copied, values they change are copied before they are changed.
+    /// Returns the number of keys in the snapshot.
+    pub async fn snapshot(&self, path: &Path) -> Result<usize, Error> {
+        info!("SNAPSHOT {:?}", path);
+
+        self.flush().await?;
+
+        // The copy runs on a task of its own, so a caller that goes away,
+        // like a client that disconnects, cannot leave it running forever.
+        let secondary = self.secondary.clone();
+        let path = path.to_path_buf();
+        tokio::spawn(async move { Self::copy_snapshot(&secondary, path).await })
+            .await
+            .map_err(|e| Error::other(format!("Snapshot task failed: {}", e)))?
+    }
+
+    async fn copy_snapshot(secondary: &StorageActor, path: PathBuf) -> Result<usize, Error> {
+        secondary.run(move |secondary| secondary.begin(&path)).await?;
+
+        loop {
+            // Requests queued behind a batch run before the next one.
+            let done = secondary.run(|secondary| {
+                secondary.copy_batch(SNAPSHOT_BATCH).inspect_err(|_| secondary.abort())
+            }).await?;
+            if done {
+                break;
+            }
+        }
+
+        secondary.run(|secondary| secondary.finish()).await
     }
 }
 
@@ -242,6 +539,9 @@ impl Clone for Engine {
         Self {
             secondary: self.secondary.clone(),
             primary: self.primary.clone(),
+            keys: self.keys.clone(),
+            write_back: self.write_back.clone(),
+            supports_expiry: self.supports_expiry,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,19 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Returns the current time in milliseconds since the unix epoch, the unit
+/// entries store the time they expire at in.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the time an entry written now expires at if it lives for `ttl`.
+pub fn expires_in(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
+
+pub fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
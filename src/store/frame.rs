@@ -0,0 +1,555 @@
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+
+use super::{Value, Corruption, FileHeader, Cipher};
+
+/// Opcode of entry frames written before checksums were introduced, up to
+/// version 1.
+pub const ENTRY_FRAME: u8 = 0;
+
+/// Opcode of gap frames of at least 17 bytes up to version 1. Shorter gap
+/// frames use their length as opcode.
+pub const BIG_GAP_FRAME: u8 = 17;
+
+/// Opcode of entry frames that end with a CRC32 of the whole frame, up to
+/// version 1.
+pub const CHECKED_ENTRY_FRAME: u8 = 18;
+
+/// Opcode of gap frames longer than `COMPACT_SHORT_GAP_MAX` since version 2,
+/// followed by the varint length of the frame. Shorter gap frames use their
+/// length as opcode.
+pub const COMPACT_BIG_GAP_FRAME: u8 = 0x7f;
+
+/// Longest gap frame that is encoded in its opcode alone since version 2.
+pub const COMPACT_SHORT_GAP_MAX: u64 = 0x7e;
+
+/// Bit of the opcode that marks an entry frame since version 2. The
+/// remaining bits hold the flags of the entry.
+pub const COMPACT_ENTRY_FRAME: u8 = 0x80;
+
+/// Entry flag of compact entry frames whose value is lz4 compressed, with
+/// the uncompressed length in front.
+pub const ENTRY_COMPRESSED: u8 = 0x01;
+
+/// Entry flag of compact entry frames whose key and value are each sealed by
+/// a `Cipher`, the value together with the plain key and the expiry.
+pub const ENTRY_ENCRYPTED: u8 = 0x02;
+
+/// Entry flag of compact entry frames that expire, with the time they expire
+/// at behind the lengths as varint milliseconds since the unix epoch. Since
+/// version 3.
+pub const ENTRY_EXPIRES: u8 = 0x04;
+
+/// Entry flags this build knows how to read.
+pub const SUPPORTED_ENTRY_FLAGS: u8 = ENTRY_COMPRESSED | ENTRY_ENCRYPTED | ENTRY_EXPIRES;
+
+/// Read ahead of sequential scans over the frames of a file.
+const SCAN_BUFFER_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    /// Entry frame without checksum of version 0.
+    Entry,
+    /// Entry frame with u128 lengths and a checksum of version 1.
+    CheckedEntry,
+    /// Entry frame with varint lengths, a raw key and a checksum since
+    /// version 2.
+    CompactEntry,
+    ShortGap,
+    BigGap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub offset: u64,
+    pub kind: FrameKind,
+    /// Length of the whole frame including the opcode.
+    pub len: u64,
+    /// Length of the opcode and the encoded lengths.
+    pub header_len: u64,
+    pub key_len: u64,
+    pub value_len: u64,
+    /// Flags of compact entry frames.
+    pub flags: u8,
+    /// Time the entry expires at in milliseconds since the unix epoch, for
+    /// compact entry frames with `ENTRY_EXPIRES`.
+    pub expires_at: Option<u64>,
+}
+
+impl FrameHeader {
+    pub fn is_entry(&self) -> bool {
+        matches!(self.kind, FrameKind::Entry | FrameKind::CheckedEntry | FrameKind::CompactEntry)
+    }
+
+    /// Whether the frame carries a checksum.
+    pub fn is_checked(&self) -> bool {
+        matches!(self.kind, FrameKind::CheckedEntry | FrameKind::CompactEntry)
+    }
+}
+
+pub struct EntryFrame {
+    pub header: FrameHeader,
+    pub key_buf: Vec<u8>,
+    pub value_buf: Vec<u8>,
+    /// Whether the checksum matches, always true for frames without one.
+    pub intact: bool,
+}
+
+impl EntryFrame {
+    pub fn key(&self) -> Result<String, Error> {
+        if self.is_encrypted() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Entry frame is encrypted"));
+        }
+        if self.header.kind == FrameKind::CompactEntry {
+            return String::from_utf8(self.key_buf.clone())
+                .map_err(|_| Corruption::error(self.header.offset, "Invalid key"));
+        }
+        postcard::from_bytes(&self.key_buf)
+            .map_err(|_| Corruption::error(self.header.offset, "Invalid key"))
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.header.kind == FrameKind::CompactEntry && self.header.flags & ENTRY_COMPRESSED != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.header.kind == FrameKind::CompactEntry && self.header.flags & ENTRY_ENCRYPTED != 0
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.header.expires_at
+    }
+
+    /// Decrypts the key and value of an encrypted frame with `cipher`,
+    /// frames that are not encrypted are returned as they are.
+    pub fn decrypt(mut self, cipher: Option<&Cipher>) -> Result<Self, Error> {
+        if !self.is_encrypted() {
+            return Ok(self);
+        }
+        let cipher = cipher.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Entry frame is encrypted, but no encryption key was given"))?;
+
+        self.key_buf = cipher.open(&self.key_buf, &[], self.header.offset)?;
+        self.value_buf = cipher.open(&self.value_buf, &sealed_with(&self.key_buf, self.header.expires_at), self.header.offset)?;
+        self.header.flags &= !ENTRY_ENCRYPTED;
+        Ok(self)
+    }
+
+    /// Returns how many bytes compression saved on the value, which is only
+    /// known once the frame is decrypted.
+    pub fn saved_bytes(&self) -> u64 {
+        if !self.is_compressed() || self.is_encrypted() {
+            return 0;
+        }
+        lz4_flex::block::uncompressed_size(&self.value_buf)
+            .map(|(len, _)| (len as u64).saturating_sub(self.value_buf.len() as u64))
+            .unwrap_or(0)
+    }
+
+    pub fn value(&self) -> Result<Value, Error> {
+        if self.is_encrypted() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Entry frame is encrypted"));
+        }
+        if self.is_compressed() {
+            let value_buf = lz4_flex::decompress_size_prepended(&self.value_buf)
+                .map_err(|_| Corruption::error(self.header.offset, "Invalid compressed value"))?;
+            return postcard::from_bytes(&value_buf)
+                .map_err(|_| Corruption::error(self.header.offset, "Invalid value"));
+        }
+        postcard::from_bytes(&self.value_buf)
+            .map_err(|_| Corruption::error(self.header.offset, "Invalid value"))
+    }
+}
+
+pub enum Frame {
+    Entry(EntryFrame),
+    Gap(FrameHeader),
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a varint as `write_varint` writes it. Varints that do not fit in 64
+/// bits or take more bytes than needed are corrupt, as the length of the
+/// frame header is worked out from the values read.
+fn read_varint<R: Read>(reader: &mut R, offset: u64) -> Result<u64, Error> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte: [u8; 1] = [0; 1];
+        reader.read_exact(&mut byte)
+            .map_err(|_| Corruption::error(offset, "Frame exceeds end of file"))?;
+        let bits = (byte[0] & 0x7f) as u64;
+        if (bits << shift) >> shift != bits {
+            return Err(Corruption::error(offset, "Varint exceeds 64 bits"));
+        }
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            if byte[0] == 0 && shift > 0 {
+                return Err(Corruption::error(offset, "Overlong varint"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(Corruption::error(offset, "Invalid varint"))
+}
+
+fn varint_len(value: u64) -> u64 {
+    let mut len = 1;
+    let mut value = value >> 7;
+    while value > 0 {
+        len += 1;
+        value >>= 7;
+    }
+    len
+}
+
+/// Opcode, lengths and expiry of a compact entry frame, which the checksum
+/// covers.
+fn compact_entry_head(flags: u8, key_len: u64, value_len: u64, expires_at: Option<u64>) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::with_capacity(1 + varint_len(key_len) as usize + varint_len(value_len) as usize);
+    buf.push(COMPACT_ENTRY_FRAME | flags);
+    write_varint(&mut buf, key_len);
+    write_varint(&mut buf, value_len);
+    if let Some(expires_at) = expires_at {
+        write_varint(&mut buf, expires_at);
+    }
+    buf
+}
+
+/// Data the value of an encrypted entry frame is sealed together with, so
+/// it cannot be moved to another key or given another expiry.
+fn sealed_with(key_buf: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut buf = key_buf.to_vec();
+    if let Some(expires_at) = expires_at {
+        buf.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    buf
+}
+
+/// Encodes an entry frame in the layout of the current format version.
+pub fn entry_frame(key: &str, value: &Value) -> Result<Vec<u8>, Error> {
+    Ok(encode_entry_frame(key, value, None, None, None)?.0)
+}
+
+/// Encodes an entry frame in the layout of the current format version that
+/// expires at `expires_at`, compresses values of at least `compress_above`
+/// bytes as long as that makes them smaller and encrypts key and value with
+/// `cipher`. Returns the frame and the bytes compression saved.
+pub fn encode_entry_frame(key: &str, value: &Value, expires_at: Option<u64>, compress_above: Option<u64>, cipher: Option<&Cipher>) -> Result<(Vec<u8>, u64), Error> {
+    let mut value_buf = postcard::to_allocvec(&value)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid value"))?;
+
+    let mut flags: u8 = 0;
+    let mut saved: u64 = 0;
+    // The uncompressed length in front of the value is a u32.
+    let compressible = value_buf.len() <= u32::MAX as usize;
+    if compressible && compress_above.is_some_and(|threshold| value_buf.len() as u64 >= threshold) {
+        let compressed_buf = lz4_flex::compress_prepend_size(&value_buf);
+        if compressed_buf.len() < value_buf.len() {
+            saved = (value_buf.len() - compressed_buf.len()) as u64;
+            flags |= ENTRY_COMPRESSED;
+            value_buf = compressed_buf;
+        }
+    }
+
+    if expires_at.is_some() {
+        flags |= ENTRY_EXPIRES;
+    }
+
+    let mut key_buf = key.as_bytes().to_vec();
+    if let Some(cipher) = cipher {
+        flags |= ENTRY_ENCRYPTED;
+        value_buf = cipher.seal(&value_buf, &sealed_with(&key_buf, expires_at))?;
+        key_buf = cipher.seal(&key_buf, &[])?;
+    }
+
+    let mut buf = compact_entry_head(flags, key_buf.len() as u64, value_buf.len() as u64, expires_at);
+    buf.reserve(key_buf.len() + value_buf.len() + 4);
+
+    buf.extend_from_slice(&key_buf);
+    buf.extend_from_slice(&value_buf);
+
+    let checksum = crc32fast::hash(&buf);
+    buf.extend_from_slice(&checksum.to_be_bytes());
+
+    Ok((buf, saved))
+}
+
+/// Encodes the opcode and length of a gap frame in the layout of the current
+/// format version, without the payload.
+pub fn gap_frame_head(len: u64) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    if len == 0 {
+        panic!("Gap frame len is 0");
+    }
+
+    if len <= COMPACT_SHORT_GAP_MAX {
+        buf.push(len as u8);
+    } else {
+        buf.push(COMPACT_BIG_GAP_FRAME);
+        write_varint(&mut buf, len);
+    }
+
+    buf
+}
+
+/// Encodes a gap frame in the layout of the current format version.
+pub fn gap_frame(len: u64) -> Vec<u8> {
+    let mut buf = gap_frame_head(len);
+    buf.resize(len as usize, 0);
+    buf
+}
+
+fn frame_len(offset: u64, len: u128, file_len: u64) -> Result<u64, Error> {
+    if len > (file_len - offset) as u128 {
+        return Err(Corruption::error(offset, "Frame exceeds end of file"));
+    }
+    Ok(len as u64)
+}
+
+/// Reads the header of the frame at `offset` in a file of format `version`,
+/// the reader must be positioned there. Returns `None` at the end of the file.
+pub fn read_frame_header<R: Read>(reader: &mut R, offset: u64, file_len: u64, version: u8) -> Result<Option<FrameHeader>, Error> {
+    if offset >= file_len {
+        return Ok(None);
+    }
+
+    let mut opt: [u8; 1] = [0; 1];
+    reader.read_exact(&mut opt)?;
+
+    if version >= 2 {
+        return read_compact_frame_header(reader, offset, file_len, opt[0]).map(Some);
+    }
+
+    match opt[0] {
+        ENTRY_FRAME | CHECKED_ENTRY_FRAME => {
+            let mut buf: [u8; 32] = [0; 32];
+            reader.read_exact(&mut buf)
+                .map_err(|_| Corruption::error(offset, "Frame exceeds end of file"))?;
+
+            let key_len = u128::from_be_bytes(buf[0..16].try_into().unwrap());
+            let value_len = u128::from_be_bytes(buf[16..32].try_into().unwrap());
+
+            let (kind, checksum_len) = if opt[0] == CHECKED_ENTRY_FRAME {
+                (FrameKind::CheckedEntry, 4)
+            } else {
+                (FrameKind::Entry, 0)
+            };
+            let len = key_len.checked_add(value_len)
+                .and_then(|len| len.checked_add(33 + checksum_len))
+                .ok_or_else(|| Corruption::error(offset, "Invalid frame length"))?;
+
+            Ok(Some(FrameHeader {
+                offset,
+                kind,
+                len: frame_len(offset, len, file_len)?,
+                header_len: 33,
+                key_len: key_len as u64,
+                value_len: value_len as u64,
+                flags: 0,
+                expires_at: None,
+            }))
+        },
+        BIG_GAP_FRAME => {
+            let mut buf: [u8; 16] = [0; 16];
+            reader.read_exact(&mut buf)
+                .map_err(|_| Corruption::error(offset, "Frame exceeds end of file"))?;
+
+            let len = u128::from_be_bytes(buf);
+            if len < BIG_GAP_FRAME as u128 {
+                return Err(Corruption::error(offset, "Invalid frame length"));
+            }
+
+            Ok(Some(FrameHeader {
+                offset,
+                kind: FrameKind::BigGap,
+                len: frame_len(offset, len, file_len)?,
+                header_len: 17,
+                key_len: 0,
+                value_len: 0,
+                flags: 0,
+                expires_at: None,
+            }))
+        },
+        len if len < BIG_GAP_FRAME => {
+            Ok(Some(FrameHeader {
+                offset,
+                kind: FrameKind::ShortGap,
+                len: frame_len(offset, len as u128, file_len)?,
+                header_len: 1,
+                key_len: 0,
+                value_len: 0,
+                flags: 0,
+                expires_at: None,
+            }))
+        },
+        opt => Err(Corruption::error(offset, format!("Unknown opcode {}", opt))),
+    }
+}
+
+fn read_compact_frame_header<R: Read>(reader: &mut R, offset: u64, file_len: u64, opt: u8) -> Result<FrameHeader, Error> {
+    if opt & COMPACT_ENTRY_FRAME != 0 {
+        let flags = opt & !COMPACT_ENTRY_FRAME;
+        if flags & !SUPPORTED_ENTRY_FLAGS != 0 {
+            return Err(Corruption::error(offset, format!("Unknown entry flags {:#04x}", flags)));
+        }
+
+        let key_len = read_varint(reader, offset)?;
+        let value_len = read_varint(reader, offset)?;
+        let expires_at = match flags & ENTRY_EXPIRES {
+            0 => None,
+            _ => Some(read_varint(reader, offset)?),
+        };
+
+        let header_len = 1 + varint_len(key_len) + varint_len(value_len) + expires_at.map(varint_len).unwrap_or(0);
+        let len = (header_len + 4) as u128 + key_len as u128 + value_len as u128;
+
+        return Ok(FrameHeader {
+            offset,
+            kind: FrameKind::CompactEntry,
+            len: frame_len(offset, len, file_len)?,
+            header_len,
+            key_len,
+            value_len,
+            flags,
+            expires_at,
+        });
+    }
+
+    match opt {
+        COMPACT_BIG_GAP_FRAME => {
+            let len = read_varint(reader, offset)?;
+            if len <= COMPACT_SHORT_GAP_MAX {
+                return Err(Corruption::error(offset, "Invalid frame length"));
+            }
+
+            Ok(FrameHeader {
+                offset,
+                kind: FrameKind::BigGap,
+                len: frame_len(offset, len as u128, file_len)?,
+                header_len: 1 + varint_len(len),
+                key_len: 0,
+                value_len: 0,
+                flags: 0,
+                expires_at: None,
+            })
+        },
+        0 => Err(Corruption::error(offset, "Unknown opcode 0")),
+        len => {
+            Ok(FrameHeader {
+                offset,
+                kind: FrameKind::ShortGap,
+                len: frame_len(offset, len as u128, file_len)?,
+                header_len: 1,
+                key_len: 0,
+                value_len: 0,
+                flags: 0,
+                expires_at: None,
+            })
+        },
+    }
+}
+
+/// Reads the key, value and checksum of an entry frame whose header was just
+/// read and verifies the checksum.
+pub fn read_entry_frame<R: Read>(reader: &mut R, header: FrameHeader) -> Result<EntryFrame, Error> {
+    let mut key_buf: Vec<u8> = vec![0; header.key_len as usize];
+    reader.read_exact(&mut key_buf)?;
+
+    let mut value_buf: Vec<u8> = vec![0; header.value_len as usize];
+    reader.read_exact(&mut value_buf)?;
+
+    let mut intact = true;
+    if header.is_checked() {
+        let mut checksum_buf: [u8; 4] = [0; 4];
+        reader.read_exact(&mut checksum_buf)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        if header.kind == FrameKind::CompactEntry {
+            hasher.update(&compact_entry_head(header.flags, header.key_len, header.value_len, header.expires_at));
+        } else {
+            hasher.update(&[CHECKED_ENTRY_FRAME]);
+            hasher.update(&(header.key_len as u128).to_be_bytes());
+            hasher.update(&(header.value_len as u128).to_be_bytes());
+        }
+        hasher.update(&key_buf);
+        hasher.update(&value_buf);
+        intact = hasher.finalize() == u32::from_be_bytes(checksum_buf);
+    }
+
+    Ok(EntryFrame {
+        header,
+        key_buf,
+        value_buf,
+        intact,
+    })
+}
+
+/// Reads the frames of a data file one after another through a read-ahead
+/// buffer.
+pub struct FrameReader<R: Read + Seek> {
+    reader: BufReader<R>,
+    offset: u64,
+    file_len: u64,
+    version: u8,
+}
+
+impl<R: Read + Seek> FrameReader<R> {
+
+    /// Starts reading at the first frame behind the file header of a file of
+    /// format `version`.
+    pub fn new(mut reader: R, file_len: u64, version: u8) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(FileHeader::LEN))?;
+        Ok(Self {
+            reader: BufReader::with_capacity(SCAN_BUFFER_BYTES, reader),
+            offset: FileHeader::LEN,
+            file_len,
+            version,
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Continues reading at `offset`, which must be the start of a frame.
+    pub fn seek(&mut self, offset: u64) -> Result<(), Error> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Returns the header of the next frame and skips its payload.
+    pub fn next_header(&mut self) -> Result<Option<FrameHeader>, Error> {
+        let header = match read_frame_header(&mut self.reader, self.offset, self.file_len, self.version)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        self.reader.seek_relative((header.len - header.header_len) as i64)?;
+        self.offset = header.offset + header.len;
+        Ok(Some(header))
+    }
+
+    /// Returns the next frame, reading the key and value of entry frames.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let header = match read_frame_header(&mut self.reader, self.offset, self.file_len, self.version)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let frame = if header.is_entry() {
+            Frame::Entry(read_entry_frame(&mut self.reader, header)?)
+        } else {
+            self.reader.seek_relative((header.len - header.header_len) as i64)?;
+            Frame::Gap(header)
+        };
+
+        self.offset = header.offset + header.len;
+        Ok(Some(frame))
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Number of size classes, one for every power of two a gap length can have.
+const SIZE_CLASSES: usize = 64;
+
+/// Gap frames of a data file, grouped by size class so a gap that fits an
+/// entry frame is found without scanning the file.
+pub struct FreeMap {
+    /// Length of every gap by its offset.
+    gaps: BTreeMap<u64, u64>,
+    /// Length and offset of every gap, grouped by the power of two below
+    /// its length.
+    classes: Vec<BTreeSet<(u64, u64)>>,
+    /// Length every gap changed since `begin` had before, `None` if there
+    /// was no gap, in the order of the changes.
+    journal: Option<Vec<(u64, Option<u64>)>>,
+}
+
+impl Default for FreeMap {
+    fn default() -> Self {
+        Self {
+            gaps: BTreeMap::new(),
+            classes: vec![BTreeSet::new(); SIZE_CLASSES],
+            journal: None,
+        }
+    }
+}
+
+impl FreeMap {
+
+    fn class(len: u64) -> usize {
+        (u64::BITS - 1 - len.leading_zeros()) as usize
+    }
+
+    fn record(&mut self, offset: u64) {
+        if let Some(journal) = &mut self.journal {
+            journal.push((offset, self.gaps.get(&offset).copied()));
+        }
+    }
+
+    fn set(&mut self, offset: u64, len: u64) -> Option<u64> {
+        let old_len = self.gaps.insert(offset, len);
+        if let Some(old_len) = old_len {
+            self.classes[Self::class(old_len)].remove(&(old_len, offset));
+        }
+        self.classes[Self::class(len)].insert((len, offset));
+        old_len
+    }
+
+    fn unset(&mut self, offset: u64) -> Option<u64> {
+        let len = self.gaps.remove(&offset)?;
+        self.classes[Self::class(len)].remove(&(len, offset));
+        Some(len)
+    }
+
+    pub fn insert(&mut self, offset: u64, len: u64) {
+        self.record(offset);
+        self.set(offset, len);
+    }
+
+    /// Removes the gap at `offset` and returns its length.
+    pub fn remove(&mut self, offset: u64) -> Option<u64> {
+        self.record(offset);
+        self.unset(offset)
+    }
+
+    /// Starts recording changes, so they can be undone with `undo` until
+    /// `keep` is called.
+    pub fn begin(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Keeps the changes made since `begin`.
+    pub fn keep(&mut self) {
+        self.journal = None;
+    }
+
+    /// Reverts the changes made since `begin`.
+    pub fn undo(&mut self) {
+        let journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+        for (offset, len) in journal.into_iter().rev() {
+            match len {
+                Some(len) => self.set(offset, len),
+                None => self.unset(offset),
+            };
+        }
+    }
+
+    /// Returns the offset and length of the gap that ends at `offset`.
+    pub fn ending_at(&self, offset: u64) -> Option<(u64, u64)> {
+        self.gaps.range(..offset).next_back()
+            .filter(|(start, len)| *start + *len == offset)
+            .map(|(start, len)| (*start, *len))
+    }
+
+    /// Returns the offset and length of the smallest gap of at least `len`
+    /// bytes.
+    pub fn find(&self, len: u64) -> Option<(u64, u64)> {
+        self.classes[Self::class(len)..].iter()
+            .find_map(|class| class.range((len, 0)..).next())
+            .map(|(len, offset)| (*offset, *len))
+    }
+
+    pub fn clear(&mut self) {
+        self.gaps.clear();
+        self.classes.iter_mut().for_each(BTreeSet::clear);
+        self.journal = None;
+    }
+}
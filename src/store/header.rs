@@ -0,0 +1,109 @@
+use std::io::{Error, ErrorKind};
+
+/// Version of the frame layout written by this build.
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Every entry frame carries a checksum.
+pub const FLAG_CHECKSUMS: u16 = 1;
+
+/// Every entry frame is encrypted, the header carries the key check of the
+/// key.
+pub const FLAG_ENCRYPTED: u16 = 2;
+
+/// Flags this build knows how to read.
+pub const SUPPORTED_FLAGS: u16 = FLAG_CHECKSUMS | FLAG_ENCRYPTED;
+
+/// The 16 bytes at the start of every data file: the magic `varia`, the
+/// format version, two bytes of big-endian flags, the big-endian key check of
+/// encrypted files, two reserved bytes and the magic `db`.
+///
+/// Files written before the header was versioned carry the fixed signature
+/// `varia---------db` and are read as version 0 without flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileHeader {
+    pub version: u8,
+    pub flags: u16,
+    /// `Cipher::key_check` of the key of encrypted files, 0 otherwise.
+    pub key_check: u32,
+}
+
+impl FileHeader {
+    pub const LEN: u64 = 16;
+
+    pub fn current() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            flags: FLAG_CHECKSUMS,
+            key_check: 0,
+        }
+    }
+
+    /// Header of a file of the current version encrypted under the key with
+    /// `key_check`.
+    pub fn encrypted(key_check: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            flags: FLAG_CHECKSUMS | FLAG_ENCRYPTED,
+            key_check,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        if self.version == 0 {
+            return *b"varia---------db";
+        }
+
+        let mut buf: [u8; 16] = [0; 16];
+        buf[0..5].copy_from_slice(b"varia");
+        buf[5] = self.version;
+        buf[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.key_check.to_be_bytes());
+        buf[14..16].copy_from_slice(b"db");
+        buf
+    }
+
+    pub fn from_bytes(buf: [u8; 16]) -> Result<Self, Error> {
+        if &buf[0..5] != b"varia" || &buf[14..16] != b"db" {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid signature"));
+        }
+
+        if &buf == b"varia---------db" {
+            return Ok(Self {
+                version: 0,
+                flags: 0,
+                key_check: 0,
+            });
+        }
+
+        Ok(Self {
+            version: buf[5],
+            flags: u16::from_be_bytes([buf[6], buf[7]]),
+            key_check: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        })
+    }
+
+    /// Fails for files written by a newer build.
+    pub fn check_supported(&self) -> Result<(), Error> {
+        if self.version > FORMAT_VERSION {
+            return Err(
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported data file version {}, this build supports up to version {}", self.version, FORMAT_VERSION)
+                )
+            );
+        }
+        if self.flags & !SUPPORTED_FLAGS != 0 {
+            return Err(
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported data file flags {:#06x}", self.flags & !SUPPORTED_FLAGS)
+                )
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fs::{File, TryLockError}, io::{Error, ErrorKind}, path::{Path, PathBuf}};
+
+use super::data_dir::MANIFEST;
+
+/// Returns the file that guards `path`, a data directory, one of its
+/// segments, or a single data file.
+fn lock_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        return path.join("LOCK");
+    }
+    match path.parent() {
+        Some(dir) if dir.join(MANIFEST).is_file() => dir.join("LOCK"),
+        _ => path.with_extension("lock"),
+    }
+}
+
+/// Locks the data at `path`, which has to exist, for this process, so no
+/// two processes change it at once. The lock is held until the returned file
+/// is dropped.
+pub fn lock_data(path: &Path) -> Result<File, Error> {
+    let lock_path = lock_path(path);
+    let file = File::options().create(true).truncate(false).write(true).open(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(
+            Error::new(
+                ErrorKind::WouldBlock,
+                format!("Another process holds the lock {:?}, stop the server first", lock_path)
+            )
+        ),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::Error;
+
+use super::super::Corruption;
+
+const BITS_PER_KEY: u64 = 10;
+const HASHES: u32 = 7;
+
+/// Set in the stored number of hashes of filters whose bit positions come
+/// from `hash`. Filters without it were written with positions from two
+/// correlated CRC32 hashes and are not consulted.
+const HASHED: u32 = 1 << 31;
+
+/// Bloom filter over the keys of a segment. The bit positions are derived
+/// from the two halves of a 64-bit FNV-1a hash, so they stay stable across
+/// builds.
+pub struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+    /// Whether the filter was written before `HASHED`, in which case it
+    /// holds every key.
+    legacy: bool,
+}
+
+impl Bloom {
+
+    pub fn with_capacity(keys: u64) -> Self {
+        let bytes = (keys * BITS_PER_KEY).div_ceil(8).max(8);
+        Self {
+            bits: vec![0; bytes as usize],
+            hashes: HASHES,
+            legacy: false,
+        }
+    }
+
+    /// FNV-1a with the finalizer of MurmurHash3, which spreads the bits of
+    /// short keys that differ in a few bytes over both halves.
+    fn hash(key: &str) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in key.as_bytes() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+
+    fn positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let hash = Self::hash(key);
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for position in self.positions(key).collect::<Vec<u64>>() {
+            self.bits[(position / 8) as usize] |= 1 << (position % 8);
+        }
+    }
+
+    /// Returns false only if the key was never inserted.
+    pub fn contains(&self, key: &str) -> bool {
+        if self.legacy {
+            return true;
+        }
+        self.positions(key).all(|position| self.bits[(position / 8) as usize] & (1 << (position % 8)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(self.bits.len() + 4);
+        let hashes = match self.legacy {
+            true => self.hashes,
+            false => self.hashes | HASHED,
+        };
+        buf.extend_from_slice(&hashes.to_be_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8], offset: u64) -> Result<Self, Error> {
+        if buf.len() < 5 {
+            return Err(Corruption::error(offset, "Invalid bloom filter"));
+        }
+        let hashes = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        Ok(Self {
+            hashes: hashes & !HASHED,
+            bits: buf[4..].to_vec(),
+            legacy: hashes & HASHED == 0,
+        })
+    }
+}
@@ -0,0 +1,436 @@
+mod bloom;
+mod segment;
+
+pub use bloom::Bloom;
+
+use std::{path::{Path, PathBuf}, fs::{self, File, OpenOptions}, io::{Error, ErrorKind, Read, Write}, collections::{BTreeMap, HashSet}, iter::Peekable, mem, sync::{Arc, Mutex, RwLock, atomic::{AtomicU64, Ordering}, mpsc::{self, Sender}}, thread::{self, JoinHandle}};
+
+use log::{debug, info, trace, warn};
+use serde::{Serialize, Deserialize};
+
+use super::{Value, StorageBackend, Wal, SyncPolicy};
+use segment::Segment;
+
+/// Size of the write-ahead log, and so roughly of the memtable, above which
+/// the memtable is flushed into a new segment.
+const MEMTABLE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Number of segments at which the background compaction merges them.
+const COMPACTION_TRIGGER: usize = 4;
+
+const MANIFEST: &str = "MANIFEST";
+
+const WAL: &str = "wal";
+
+/// A single operation on the memtable as it is written to the write-ahead log.
+#[derive(Serialize, Deserialize)]
+enum Mutation {
+    Put { key: String, value: Value },
+    Del { key: String },
+    Clear,
+}
+
+/// The live segments in the order they were written and the next free id.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    segments: Vec<u64>,
+}
+
+type Entries<'a> = Box<dyn Iterator<Item = Result<(String, Option<Value>), Error>> + 'a>;
+
+/// State shared with the background compaction.
+struct Shared {
+    dir: PathBuf,
+    /// Oldest segment first. Flushes only append, so a compaction can replace
+    /// the prefix it merged.
+    segments: RwLock<Vec<Arc<Segment>>>,
+    next_id: AtomicU64,
+    /// Held for a whole compaction, so compactions and `clear` never overlap.
+    compaction: Mutex<()>,
+}
+
+impl Shared {
+
+    fn read_manifest(dir: &Path) -> Result<Manifest, Error> {
+        let path = dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+
+        if buf.len() < 4 || crc32fast::hash(&buf[4..]) != u32::from_be_bytes(buf[0..4].try_into().unwrap()) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid manifest"));
+        }
+        postcard::from_bytes(&buf[4..])
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid manifest"))
+    }
+
+    /// Atomically replaces the manifest with the given segments.
+    fn write_manifest(&self, segments: &[Arc<Segment>]) -> Result<(), Error> {
+        let manifest = Manifest {
+            next_id: self.next_id.load(Ordering::SeqCst),
+            segments: segments.iter().map(|segment| segment.id).collect(),
+        };
+        let payload = postcard::to_allocvec(&manifest)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid manifest"))?;
+
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp_path)?;
+        file.write_all(&crc32fast::hash(&payload).to_be_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, self.dir.join(MANIFEST))?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Merges all segments into one and drops the deleted keys.
+    fn compact(&self) -> Result<(), Error> {
+        let _compaction = self.compaction.lock().unwrap();
+
+        let inputs: Vec<Arc<Segment>> = self.segments.read().unwrap().clone();
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        debug!("Compacting {} segments", inputs.len());
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let capacity = inputs.iter().map(|segment| segment.records()).sum();
+        let sources: Vec<Entries> = inputs.iter().rev().map(|segment| Box::new(segment.iter()) as Entries).collect();
+
+        // All segments take part, so no older value can hide behind a
+        // dropped tombstone.
+        let entries = Merge::new(sources).filter(|entry| !matches!(entry, Ok((_, None))));
+        let segment = Segment::write(&self.dir, id, entries, capacity)?;
+
+        let output = if segment.records() > 0 {
+            vec![Arc::new(segment)]
+        } else {
+            segment.remove()?;
+            Vec::new()
+        };
+
+        {
+            let mut segments = self.segments.write().unwrap();
+            segments.splice(0..inputs.len(), output);
+            self.write_manifest(&segments)?;
+        }
+
+        for input in &inputs {
+            if let Err(e) = input.remove() {
+                warn!("Failed to remove segment {}: {}", input.id, e);
+            }
+        }
+
+        info!("Compacted {} segments into segment {}", inputs.len(), id);
+        Ok(())
+    }
+}
+
+/// Merges sorted sources into one sorted sequence. For keys held by several
+/// sources the entry of the first source wins.
+struct Merge<'a> {
+    sources: Vec<Peekable<Entries<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Entries<'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(|source| source.peekable()).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<(String, Option<Value>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<String> = None;
+        for source in self.sources.iter_mut() {
+            match source.peek() {
+                Some(Ok((key, _))) if min.as_ref().map(|min| key < min).unwrap_or(true) => {
+                    min = Some(key.clone());
+                },
+                Some(Ok(_)) => {},
+                Some(Err(_)) => return source.next(),
+                None => {},
+            }
+        }
+        let min = min?;
+
+        let mut entry: Option<(String, Option<Value>)> = None;
+        for source in self.sources.iter_mut() {
+            if let Some(Ok((key, _))) = source.peek() {
+                if *key == min {
+                    if let Some(Ok(next)) = source.next() {
+                        entry.get_or_insert(next);
+                    }
+                }
+            }
+        }
+        entry.map(Ok)
+    }
+}
+
+/// Log-structured merge-tree in a directory. Writes go to the write-ahead log
+/// and a sorted in-memory memtable, which is flushed into an immutable sorted
+/// segment file once it grows too big. A background thread merges the
+/// segments once there are too many of them.
+pub struct Lsm {
+    shared: Arc<Shared>,
+    wal: Wal<Mutation>,
+    /// `None` marks a deleted key that may still live in a segment.
+    memtable: BTreeMap<String, Option<Value>>,
+    memtable_limit: u64,
+    compaction_trigger: usize,
+    compactor: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Lsm {
+
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(path)?;
+
+        let manifest = Shared::read_manifest(path)?;
+        Self::remove_orphans(path, &manifest)?;
+
+        let mut segments: Vec<Arc<Segment>> = Vec::with_capacity(manifest.segments.len());
+        for id in &manifest.segments {
+            segments.push(Arc::new(Segment::open(path, *id)?));
+        }
+
+        let shared = Arc::new(Shared {
+            dir: path.to_path_buf(),
+            segments: RwLock::new(segments),
+            next_id: AtomicU64::new(manifest.next_id),
+            compaction: Mutex::new(()),
+        });
+
+        let (sender, receiver) = mpsc::channel::<()>();
+        let compactor_shared = shared.clone();
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                while receiver.try_recv().is_ok() {}
+                if let Err(e) = compactor_shared.compact() {
+                    warn!("Failed to compact segments: {}", e);
+                }
+            }
+        });
+
+        let (wal, batches) = Wal::open(&path.join(WAL))?;
+        let mut lsm = Self {
+            shared,
+            wal,
+            memtable: BTreeMap::new(),
+            memtable_limit: MEMTABLE_BYTES,
+            compaction_trigger: COMPACTION_TRIGGER,
+            compactor: Some((sender, handle)),
+        };
+
+        debug!("Replay {} batches", batches.len());
+        for mutation in batches.into_iter().flatten() {
+            lsm.apply(mutation)?;
+        }
+        lsm.flush_if_full()?;
+
+        Ok(lsm)
+    }
+
+    /// Removes segment files a crash left behind before they made it into the
+    /// manifest.
+    fn remove_orphans(dir: &Path, manifest: &Manifest) -> Result<(), Error> {
+        let live: HashSet<u64> = manifest.segments.iter().copied().collect();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|extension| extension != segment::EXTENSION).unwrap_or(true) {
+                continue;
+            }
+            let id = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok());
+            if id.map(|id| !live.contains(&id)).unwrap_or(false) {
+                warn!("Removing orphaned segment {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_memtable_size(&mut self, bytes: u64) {
+        self.memtable_limit = bytes;
+    }
+
+    pub fn set_compaction_trigger(&mut self, segments: usize) {
+        self.compaction_trigger = segments;
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) -> Result<(), Error> {
+        self.wal.set_sync_policy(sync_policy)
+    }
+
+    /// Returns the number of live segments.
+    pub fn segments(&self) -> usize {
+        self.shared.segments.read().unwrap().len()
+    }
+
+    fn apply(&mut self, mutation: Mutation) -> Result<(), Error> {
+        match mutation {
+            Mutation::Put { key, value } => {
+                self.memtable.insert(key, Some(value));
+            },
+            Mutation::Del { key } => {
+                if self.shared.segments.read().unwrap().is_empty() {
+                    self.memtable.remove(&key);
+                } else {
+                    self.memtable.insert(key, None);
+                }
+            },
+            Mutation::Clear => {
+                let _compaction = self.shared.compaction.lock().unwrap();
+                let removed = {
+                    let mut segments = self.shared.segments.write().unwrap();
+                    let removed = mem::take(&mut *segments);
+                    self.shared.write_manifest(&segments)?;
+                    removed
+                };
+                for segment in removed {
+                    segment.remove()?;
+                }
+                self.memtable.clear();
+            },
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, mutation: Mutation) -> Result<(), Error> {
+        self.wal.append(std::slice::from_ref(&mutation))?;
+        self.apply(mutation)?;
+        self.flush_if_full()
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), Error> {
+        if self.wal.len()? < self.memtable_limit {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    /// Writes the memtable into a new segment and drops the write-ahead log.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.memtable.is_empty() {
+            return self.wal.reset();
+        }
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        trace!("Flush {} entries into segment {}", self.memtable.len(), id);
+
+        let entries = self.memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let segment = Segment::write(&self.shared.dir, id, entries, self.memtable.len() as u64)?;
+
+        let segments = {
+            let mut segments = self.shared.segments.write().unwrap();
+            segments.push(Arc::new(segment));
+            self.shared.write_manifest(&segments)?;
+            segments.len()
+        };
+
+        self.wal.reset()?;
+        self.memtable.clear();
+
+        if segments >= self.compaction_trigger {
+            if let Some((sender, _)) = &self.compactor {
+                let _ = sender.send(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the memtable and merges all segments into one.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.shared.compact()
+    }
+}
+
+impl StorageBackend for Lsm {
+
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.log(Mutation::Put { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let segments: Vec<Arc<Segment>> = self.shared.segments.read().unwrap().clone();
+        for segment in segments.iter().rev() {
+            if let Some(value) = segment.get(&key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        self.log(Mutation::Del { key })
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let segments: Vec<Arc<Segment>> = self.shared.segments.read().unwrap().clone();
+
+        let mut sources: Vec<Entries> = Vec::with_capacity(segments.len() + 1);
+        sources.push(Box::new(self.memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone())))));
+        sources.extend(segments.iter().rev().map(|segment| Box::new(segment.iter()) as Entries));
+
+        Merge::new(sources)
+            .filter_map(|entry| match entry {
+                Ok((key, Some(_))) => Some(Ok(key)),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.wal.append(&[Mutation::Clear])?;
+        self.apply(Mutation::Clear)?;
+        self.wal.reset()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        Ok(self.list()?.len())
+    }
+
+    fn defrag(&mut self) -> Result<(), Error> {
+        self.compact()
+    }
+
+    fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        self.wal.set_group_commit(group_commit)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.wal.sync_pending()
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.compactor.take() {
+            drop(sender);
+            if handle.join().is_err() {
+                warn!("Compaction thread panicked");
+            }
+        }
+        if let Err(e) = self.wal.sync() {
+            warn!("Failed to sync write-ahead log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::{path::{Path, PathBuf}, fs::{self, File, OpenOptions}, io::{Error, ErrorKind, Write, BufWriter}, sync::Arc, vec};
+
+use super::super::{Value, Corruption, positional::read_exact_at};
+use super::bloom::Bloom;
+
+pub const EXTENSION: &str = "seg";
+
+const MAGIC: [u8; 4] = *b"vseg";
+
+/// Index offset, bloom offset, record count, checksum and magic.
+const FOOTER_LEN: u64 = 32;
+
+/// Number of records between two entries of the sparse index.
+const BLOCK_RECORDS: u64 = 16;
+
+const TOMBSTONE_RECORD: u8 = 0;
+const VALUE_RECORD: u8 = 1;
+
+/// Tag, key length and value length.
+const RECORD_HEADER_LEN: usize = 9;
+
+/// Immutable file of records sorted by key, where a record either holds a
+/// value or marks the key as deleted.
+///
+/// Every record is a tag, a big-endian u32 key length, a big-endian u32 value
+/// length, the key, the postcard encoded value and a CRC32 of the record. The
+/// records are followed by a sparse index holding the first key of every
+/// block of records, the bloom filter and the footer.
+pub struct Segment {
+    pub id: u64,
+    path: PathBuf,
+    file: File,
+    /// First key and offset of every block.
+    index: Vec<(String, u64)>,
+    /// End of the last block.
+    data_len: u64,
+    bloom: Bloom,
+    records: u64,
+}
+
+impl Segment {
+
+    pub fn path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:016}.{}", id, EXTENSION))
+    }
+
+    /// Writes the sorted `entries` into a new segment. `capacity` is an upper
+    /// bound of the number of entries used to size the bloom filter.
+    pub fn write<I>(dir: &Path, id: u64, entries: I, capacity: u64) -> Result<Self, Error>
+    where
+        I: Iterator<Item = Result<(String, Option<Value>), Error>>,
+    {
+        let path = Self::path(dir, id);
+        let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&path)?;
+        let mut writer = BufWriter::new(file);
+
+        let mut index: Vec<(String, u64)> = Vec::new();
+        let mut bloom = Bloom::with_capacity(capacity);
+        let mut offset: u64 = 0;
+        let mut records: u64 = 0;
+
+        for entry in entries {
+            let (key, value) = entry?;
+            if records.is_multiple_of(BLOCK_RECORDS) {
+                index.push((key.clone(), offset));
+            }
+            bloom.insert(&key);
+
+            let record = Self::record(&key, value.as_ref())?;
+            writer.write_all(&record)?;
+            offset += record.len() as u64;
+            records += 1;
+        }
+
+        let mut meta: Vec<u8> = Vec::new();
+        meta.extend_from_slice(&(index.len() as u64).to_be_bytes());
+        for (key, block_offset) in &index {
+            meta.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            meta.extend_from_slice(key.as_bytes());
+            meta.extend_from_slice(&block_offset.to_be_bytes());
+        }
+        let bloom_offset = offset + meta.len() as u64;
+        meta.extend_from_slice(&bloom.to_bytes());
+
+        writer.write_all(&meta)?;
+        writer.write_all(&offset.to_be_bytes())?;
+        writer.write_all(&bloom_offset.to_be_bytes())?;
+        writer.write_all(&records.to_be_bytes())?;
+        writer.write_all(&crc32fast::hash(&meta).to_be_bytes())?;
+        writer.write_all(&MAGIC)?;
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        Self::open(dir, id)
+    }
+
+    pub fn open(dir: &Path, id: u64) -> Result<Self, Error> {
+        let path = Self::path(dir, id);
+        let file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
+
+        if file_len < FOOTER_LEN {
+            return Err(Corruption::error(0, "Segment too short"));
+        }
+        let footer_offset = file_len - FOOTER_LEN;
+
+        let mut footer: [u8; FOOTER_LEN as usize] = [0; FOOTER_LEN as usize];
+        read_exact_at(&file, &mut footer, footer_offset)?;
+        if footer[28..32] != MAGIC {
+            return Err(Corruption::error(footer_offset, "Invalid segment footer"));
+        }
+
+        let data_len = u64::from_be_bytes(footer[0..8].try_into().unwrap());
+        let bloom_offset = u64::from_be_bytes(footer[8..16].try_into().unwrap());
+        let records = u64::from_be_bytes(footer[16..24].try_into().unwrap());
+        let checksum = u32::from_be_bytes(footer[24..28].try_into().unwrap());
+
+        if data_len > bloom_offset || bloom_offset > footer_offset {
+            return Err(Corruption::error(footer_offset, "Invalid segment footer"));
+        }
+
+        let mut meta: Vec<u8> = vec![0; (footer_offset - data_len) as usize];
+        read_exact_at(&file, &mut meta, data_len)?;
+        if crc32fast::hash(&meta) != checksum {
+            return Err(Corruption::error(data_len, "Checksum mismatch"));
+        }
+
+        let index = Self::parse_index(&meta[..(bloom_offset - data_len) as usize], data_len)?;
+        let bloom = Bloom::from_bytes(&meta[(bloom_offset - data_len) as usize..], bloom_offset)?;
+
+        Ok(Self {
+            id,
+            path,
+            file,
+            index,
+            data_len,
+            bloom,
+            records,
+        })
+    }
+
+    fn parse_index(buf: &[u8], offset: u64) -> Result<Vec<(String, u64)>, Error> {
+        let invalid = || Corruption::error(offset, "Invalid segment index");
+
+        let count = u64::from_be_bytes(buf.get(0..8).ok_or_else(invalid)?.try_into().unwrap());
+        let mut index: Vec<(String, u64)> = Vec::new();
+        let mut position: usize = 8;
+
+        for _ in 0..count {
+            let key_len = u32::from_be_bytes(buf.get(position..position + 4).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+            position += 4;
+            let key = buf.get(position..position + key_len).ok_or_else(invalid)?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| invalid())?;
+            position += key_len;
+            let block_offset = u64::from_be_bytes(buf.get(position..position + 8).ok_or_else(invalid)?.try_into().unwrap());
+            position += 8;
+            index.push((key, block_offset));
+        }
+
+        if position != buf.len() {
+            return Err(invalid());
+        }
+        Ok(index)
+    }
+
+    fn record(key: &str, value: Option<&Value>) -> Result<Vec<u8>, Error> {
+        let (tag, value_buf) = match value {
+            Some(value) => {
+                let value_buf = postcard::to_allocvec(value)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid value"))?;
+                (VALUE_RECORD, value_buf)
+            },
+            None => (TOMBSTONE_RECORD, Vec::new()),
+        };
+
+        let key_len = u32::try_from(key.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Key too big"))?;
+        let value_len = u32::try_from(value_buf.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Value too big"))?;
+
+        let mut buf: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value_buf.len() + 4);
+        buf.push(tag);
+        buf.extend_from_slice(&key_len.to_be_bytes());
+        buf.extend_from_slice(&value_len.to_be_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&value_buf);
+
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+
+        Ok(buf)
+    }
+
+    /// Decodes all records of a block that starts at `offset`.
+    fn parse_block(buf: &[u8], offset: u64) -> Result<Vec<(String, Option<Value>)>, Error> {
+        let mut records: Vec<(String, Option<Value>)> = Vec::new();
+        let mut position: usize = 0;
+
+        while position < buf.len() {
+            let record_offset = offset + position as u64;
+
+            let header = buf.get(position..position + RECORD_HEADER_LEN)
+                .ok_or_else(|| Corruption::error(record_offset, "Record exceeds block"))?;
+            let tag = header[0];
+            let key_len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+            let value_len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+
+            let end = position + RECORD_HEADER_LEN + key_len + value_len;
+            let record = buf.get(position..end + 4)
+                .ok_or_else(|| Corruption::error(record_offset, "Record exceeds block"))?;
+            if crc32fast::hash(&record[..end - position]) != u32::from_be_bytes(buf[end..end + 4].try_into().unwrap()) {
+                return Err(Corruption::error(record_offset, "Checksum mismatch"));
+            }
+
+            let key_buf = &buf[position + RECORD_HEADER_LEN..position + RECORD_HEADER_LEN + key_len];
+            let key = String::from_utf8(key_buf.to_vec())
+                .map_err(|_| Corruption::error(record_offset, "Invalid key"))?;
+
+            let value = match tag {
+                VALUE_RECORD => {
+                    let value_buf = &buf[position + RECORD_HEADER_LEN + key_len..end];
+                    let value = postcard::from_bytes(value_buf)
+                        .map_err(|_| Corruption::error(record_offset, "Invalid value"))?;
+                    Some(value)
+                },
+                TOMBSTONE_RECORD => None,
+                tag => return Err(Corruption::error(record_offset, format!("Unknown record tag {}", tag))),
+            };
+
+            records.push((key, value));
+            position = end + 4;
+        }
+
+        Ok(records)
+    }
+
+    fn block(&self, block: usize) -> Result<Vec<(String, Option<Value>)>, Error> {
+        let start = self.index[block].1;
+        let end = self.index.get(block + 1).map(|(_, offset)| *offset).unwrap_or(self.data_len);
+        if start > end || end > self.data_len {
+            return Err(Corruption::error(start, "Invalid segment index"));
+        }
+
+        let mut buf: Vec<u8> = vec![0; (end - start) as usize];
+        read_exact_at(&self.file, &mut buf, start)?;
+        Self::parse_block(&buf, start)
+    }
+
+    /// Returns `None` if the segment knows nothing about the key and
+    /// `Some(None)` if it marks the key as deleted.
+    pub fn get(&self, key: &str) -> Result<Option<Option<Value>>, Error> {
+        if !self.bloom.contains(key) {
+            return Ok(None);
+        }
+
+        let block = match self.index.partition_point(|(first_key, _)| first_key.as_str() <= key) {
+            0 => return Ok(None),
+            block => block - 1,
+        };
+
+        Ok(self.block(block)?
+            .into_iter()
+            .find(|(record_key, _)| record_key == key)
+            .map(|(_, value)| value))
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Iterates over all records in key order, one block at a time.
+    pub fn iter(self: &Arc<Self>) -> SegmentIter {
+        SegmentIter {
+            segment: self.clone(),
+            block: 0,
+            records: Vec::new().into_iter(),
+        }
+    }
+
+    pub fn remove(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path)
+    }
+}
+
+pub struct SegmentIter {
+    segment: Arc<Segment>,
+    block: usize,
+    records: vec::IntoIter<(String, Option<Value>)>,
+}
+
+impl Iterator for SegmentIter {
+    type Item = Result<(String, Option<Value>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(Ok(record));
+            }
+            if self.block >= self.segment.index.len() {
+                return None;
+            }
+
+            match self.segment.block(self.block) {
+                Ok(records) => {
+                    self.block += 1;
+                    self.records = records.into_iter();
+                },
+                Err(e) => {
+                    self.block = self.segment.index.len();
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, io::Error};
+
+use super::{Value, StorageBackend, expiry};
+
+/// Keeps all entries in memory only, everything is lost when it is dropped.
+#[derive(Default)]
+pub struct Memory {
+    entries: HashMap<String, (u64, Value)>,
+    /// Time every expiring key expires at.
+    expiries: HashMap<String, u64>,
+    /// Insertion counter, so `list` returns the keys in the order they were
+    /// first written like `Disk` does for a fresh file.
+    next: u64,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        expiry::is_expired(self.expiries.get(key).copied(), now)
+    }
+}
+
+impl StorageBackend for Memory {
+
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.put_expiring(key, value, None)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        if self.is_expired(&key, expiry::now()) {
+            return Ok(None);
+        }
+        Ok(self.entries.get(&key).map(|(_, value)| value.clone()))
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        self.entries.remove(&key);
+        self.expiries.remove(&key);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let now = expiry::now();
+        let mut entries: Vec<(&String, &u64)> = self.entries.iter()
+            .filter(|(key, _)| !self.is_expired(key, now))
+            .map(|(key, (order, _))| (key, order))
+            .collect();
+        entries.sort_by_key(|(_, order)| **order);
+        Ok(entries.into_iter().map(|(key, _)| key.clone()).collect())
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.entries.clear();
+        self.expiries.clear();
+        self.next = 0;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        let now = expiry::now();
+        Ok(self.entries.keys().filter(|key| !self.is_expired(key, now)).count())
+    }
+
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+            None => self.expiries.remove(&key),
+        };
+        match self.entries.get_mut(&key) {
+            Some(entry) => entry.1 = value,
+            None => {
+                self.entries.insert(key, (self.next, value));
+                self.next += 1;
+            },
+        }
+        Ok(())
+    }
+
+    fn supports_expiry(&self) -> bool {
+        true
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>, Error> {
+        if self.is_expired(&key, expiry::now()) {
+            return Ok(None);
+        }
+        Ok(self.expiries.get(&key).copied())
+    }
+
+    fn reap(&mut self) -> Result<usize, Error> {
+        let now = expiry::now();
+        let expired: Vec<String> = self.expiries.iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.del(key.clone())?;
+        }
+        Ok(expired.len())
+    }
+}
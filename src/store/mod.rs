@@ -1,9 +1,44 @@
 mod value;
+mod backend;
 mod disk;
+mod data_dir;
+mod free;
+mod positional;
+mod memory;
+mod lsm;
+pub mod frame;
+mod header;
+mod wal;
+mod corruption;
+mod cipher;
 mod engine;
+mod actor;
+mod stripes;
+mod snapshot;
+mod ndjson;
 mod weight;
+mod cache_policy;
+mod primary;
+mod write_back;
+mod expiry;
+mod condition;
+mod lock;
 
 pub use value::Value;
+pub use backend::StorageBackend;
 pub use disk::Disk;
+pub use data_dir::DataDir;
+pub use memory::Memory;
+pub use lsm::{Lsm, Bloom};
+pub use wal::{Wal, WalWrite, SyncPolicy};
+pub use corruption::Corruption;
+pub use cipher::Cipher;
+pub use header::{FileHeader, FORMAT_VERSION, FLAG_CHECKSUMS, FLAG_ENCRYPTED};
 pub use engine::Engine;
-pub use weight::weight;
\ No newline at end of file
+pub use snapshot::{verify_snapshot, restore_snapshot};
+pub use ndjson::{ConflictPolicy, ImportReport, ImportFailure, EXPORT_BATCH, read_records};
+pub use weight::weight;
+pub use cache_policy::{CacheMode, CacheRule, CachePolicy};
+pub use primary::{Primary, CacheStats};
+pub use condition::{Condition, Conflict};
+pub use lock::lock_data;
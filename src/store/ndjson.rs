@@ -0,0 +1,104 @@
+use std::{fmt, io::{BufRead, Error, ErrorKind}, str::FromStr};
+
+use serde::{Serialize, Deserialize};
+
+use super::Value;
+
+/// Number of entries an export reads from the secondary storage at a time.
+pub const EXPORT_BATCH: usize = 256;
+
+/// One line of an export, the value in the same tagged encoding as the HTTP
+/// protocol.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: Value,
+}
+
+/// What an import does with a key that already exists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Skip,
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "fail" => Ok(Self::Fail),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid conflict policy {:?}, must be one of overwrite, skip or fail", s))),
+        }
+    }
+}
+
+/// Number of entries an import wrote and left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// An import that stopped part way, carried inside an `std::io::Error` of
+/// the same kind as its cause.
+#[derive(Debug)]
+pub struct ImportFailure {
+    /// What the import did before it stopped, which stays stored.
+    pub report: ImportReport,
+    pub cause: Error,
+}
+
+impl ImportFailure {
+    pub fn error(report: ImportReport, cause: Error) -> Error {
+        Error::new(cause.kind(), Self { report, cause })
+    }
+
+    /// Returns the failure carried by an error, if any.
+    pub fn find(error: &Error) -> Option<&Self> {
+        error.get_ref().and_then(|e| e.downcast_ref::<Self>())
+    }
+}
+
+impl fmt::Display for ImportFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {} entries were imported and {} skipped", self.cause, self.report.imported, self.report.skipped)
+    }
+}
+
+impl std::error::Error for ImportFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// Appends `key` and `value` to `buf` as one line of NDJSON.
+pub fn write_record(buf: &mut Vec<u8>, key: &str, value: &Value) -> Result<(), Error> {
+    let record = Record {
+        key: key.to_string(),
+        value: value.clone(),
+    };
+    serde_json::to_writer(&mut *buf, &record)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+/// Reads every line of NDJSON from `reader`. Empty lines are skipped, any
+/// other line that is not a record fails the whole read.
+pub fn read_records<R: BufRead>(reader: R) -> Result<Vec<(String, Value)>, Error> {
+    let mut records: Vec<(String, Value)> = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid record on line {}: {}", number + 1, e)))?;
+        records.push((record.key, record.value));
+    }
+    Ok(records)
+}
@@ -0,0 +1,65 @@
+use std::{fs::File, io::{Error, ErrorKind, Read, Seek, SeekFrom}};
+
+/// Reads at `offset` without moving the shared cursor of `file`, so a file
+/// can be read from several threads at once.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file from a position of its own instead of the shared cursor.
+pub struct PositionalReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl<'a> PositionalReader<'a> {
+    pub fn new(file: &'a File, offset: u64) -> Self {
+        Self {
+            file,
+            offset,
+        }
+    }
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = read_at(self.file, buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PositionalReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.file.metadata()?.len().checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.offset)
+    }
+}
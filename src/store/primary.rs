@@ -0,0 +1,313 @@
+use std::{collections::HashMap, future::Future, io::Error, sync::{Arc, PoisonError, RwLock, RwLockWriteGuard, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use moka::{Expiry, future::Cache, notification::RemovalCause};
+
+use super::{Value, Corruption, weight, expiry, cache_policy::{CacheMode, CachePolicy}};
+
+/// Counters of the cache since it was built, and what it holds right now.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room.
+    pub evictions: u64,
+    /// Entries dropped because they expired.
+    pub expirations: u64,
+    /// Entries held, pinned ones included.
+    pub entries: u64,
+    pub pinned: u64,
+    /// Bytes the entries take up, as weighed by `weight`.
+    pub bytes: u64,
+    /// Bytes the pinned entries take up, counted in `bytes` as well.
+    pub pinned_bytes: u64,
+    /// Bytes the pinned entries may take up at most.
+    pub pinned_capacity: u64,
+    /// Pinned entries dropped to make room, counted in `evictions` as well.
+    pub pinned_evictions: u64,
+    /// Writes not flushed to the secondary storage yet in write-back mode.
+    pub unflushed: u64,
+    /// Bytes the unflushed writes take up, as weighed by `weight`.
+    pub unflushed_bytes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    pinned_evictions: AtomicU64,
+}
+
+/// Time every cached key that expires on its own expires at, in
+/// milliseconds since the unix epoch.
+type Deadlines = Arc<RwLock<HashMap<String, u64>>>;
+
+/// Lets entries expire as the `CachePolicy` has it for their key, but never
+/// after the key itself expires.
+struct PolicyExpiry(Arc<CachePolicy>, Deadlines);
+
+impl PolicyExpiry {
+    fn remaining(&self, key: &str, age: Duration) -> Option<Duration> {
+        let remaining = match self.0.expiry(key) {
+            (Some(ttl), Some(tti)) => Some(ttl.saturating_sub(age).min(tti)),
+            (Some(ttl), None) => Some(ttl.saturating_sub(age)),
+            (None, tti) => tti,
+        };
+        let deadline = self.1.read().unwrap_or_else(PoisonError::into_inner).get(key).copied();
+        match deadline {
+            Some(deadline) => {
+                let left = Duration::from_millis(deadline.saturating_sub(expiry::now()));
+                Some(remaining.map_or(left, |remaining| remaining.min(left)))
+            },
+            None => remaining,
+        }
+    }
+}
+
+impl Expiry<String, Option<Value>> for PolicyExpiry {
+    fn expire_after_create(&self, key: &String, _value: &Option<Value>, _created_at: Instant) -> Option<Duration> {
+        self.remaining(key, Duration::ZERO)
+    }
+
+    fn expire_after_read(&self, key: &String, _value: &Option<Value>, read_at: Instant, _duration_until_expiry: Option<Duration>, last_modified_at: Instant) -> Option<Duration> {
+        self.remaining(key, read_at.saturating_duration_since(last_modified_at))
+    }
+
+    fn expire_after_update(&self, key: &String, _value: &Option<Value>, _updated_at: Instant, _duration_until_expiry: Option<Duration>) -> Option<Duration> {
+        self.remaining(key, Duration::ZERO)
+    }
+}
+
+/// The cache in front of the secondary storage of the `Engine`. Entries of
+/// pinned keys live in a cache of their own that only drops them once they
+/// outgrow its capacity, entries of skipped keys are not held at all.
+#[derive(Clone)]
+pub struct Primary {
+    cache: Cache<String, Option<Value>>,
+    pinned: Cache<String, Option<Value>>,
+    pinned_capacity: u64,
+    policy: Arc<CachePolicy>,
+    counters: Arc<Counters>,
+    deadlines: Deadlines,
+}
+
+impl Primary {
+    /// Builds a cache of at most `capacity` bytes that follows `policy`, and
+    /// may hold up to as many bytes of pinned entries on top.
+    pub fn new(capacity: u64, policy: CachePolicy) -> Self {
+        Self::with_pinned_capacity(capacity, capacity, policy)
+    }
+
+    /// Builds a cache like `new`, holding at most `pinned_capacity` bytes of
+    /// pinned entries.
+    pub fn with_pinned_capacity(capacity: u64, pinned_capacity: u64, policy: CachePolicy) -> Self {
+        let policy = Arc::new(policy);
+        let counters = Arc::new(Counters::default());
+        let deadlines = Deadlines::default();
+
+        let listener_counters = counters.clone();
+        let cache = Cache::builder()
+            .max_capacity(capacity)
+            .weigher(weight)
+            .expire_after(PolicyExpiry(policy.clone(), deadlines.clone()))
+            .eviction_listener(move |_, _, cause| match cause {
+                RemovalCause::Size => {
+                    listener_counters.evictions.fetch_add(1, Ordering::Relaxed);
+                },
+                RemovalCause::Expired => {
+                    listener_counters.expirations.fetch_add(1, Ordering::Relaxed);
+                },
+                _ => {},
+            })
+            .build();
+
+        let listener_counters = counters.clone();
+        let pinned = Cache::builder()
+            .max_capacity(pinned_capacity)
+            .weigher(weight)
+            .eviction_listener(move |_, _, cause| {
+                if cause == RemovalCause::Size {
+                    listener_counters.evictions.fetch_add(1, Ordering::Relaxed);
+                    listener_counters.pinned_evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .build();
+
+        Self {
+            cache,
+            pinned,
+            pinned_capacity,
+            policy,
+            counters,
+            deadlines,
+        }
+    }
+
+    fn deadlines(&self) -> RwLockWriteGuard<'_, HashMap<String, u64>> {
+        self.deadlines.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Drops the entry of `key` if the key has expired.
+    async fn expire(&self, key: &str, cache: &Cache<String, Option<Value>>) {
+        let deadline = self.deadlines.read().unwrap_or_else(PoisonError::into_inner).get(key).copied();
+        if !expiry::is_expired(deadline, expiry::now()) {
+            return;
+        }
+        cache.invalidate(key).await;
+        // A later write may have set a new deadline meanwhile.
+        let mut deadlines = self.deadlines();
+        if deadlines.get(key).copied() == deadline {
+            deadlines.remove(key);
+        }
+    }
+
+    fn cache_of(&self, key: &str) -> Option<&Cache<String, Option<Value>>> {
+        match self.policy.mode(key) {
+            CacheMode::Cache => Some(&self.cache),
+            CacheMode::Pin => Some(&self.pinned),
+            CacheMode::Skip => None,
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Option<Value>> {
+        let cache = self.cache_of(key)?;
+        self.expire(key, cache).await;
+        cache.get(key).await
+    }
+
+    /// Returns the entry of `key` like `get` and counts it as a hit or miss.
+    pub async fn lookup(&self, key: &str) -> Option<Option<Value>> {
+        let entry = self.get(key).await;
+        let counter = match entry {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        entry
+    }
+
+    pub async fn insert(&self, key: String, value: Option<Value>) {
+        self.insert_expiring(key, value, None).await
+    }
+
+    /// Caches `value` for `key` like `insert`, but at most until
+    /// `expires_at`.
+    pub async fn insert_expiring(&self, key: String, value: Option<Value>, expires_at: Option<u64>) {
+        let cache = match self.cache_of(&key) {
+            Some(cache) => cache,
+            None => return,
+        };
+        if value.is_none() && !self.policy.caches_absence(&key) {
+            cache.invalidate(&key).await;
+            self.deadlines().remove(&key);
+            return;
+        }
+        // The deadline is in place before the entry can be read, and is only
+        // dropped once the entry without one replaced it.
+        match expires_at {
+            Some(expires_at) => {
+                self.deadlines().insert(key.clone(), expires_at);
+                cache.insert(key, value).await;
+            },
+            None => {
+                cache.insert(key.clone(), value).await;
+                self.deadlines().remove(&key);
+            },
+        }
+    }
+
+    /// Returns the entry of `key`, reading it and the time it expires at
+    /// with `read` and caching it if it is not cached. Concurrent calls for a
+    /// cached key share one read.
+    pub async fn get_with<F>(&self, key: &str, read: F) -> Result<Option<Value>, Error>
+    where
+        F: Future<Output = Result<(Option<Value>, Option<u64>), Error>>,
+    {
+        let cache = match self.cache_of(key) {
+            Some(cache) => cache,
+            None => return Ok(read.await?.0),
+        };
+        let value = cache.try_get_with(key.to_string(), async {
+            let (value, expires_at) = read.await?;
+            if let Some(expires_at) = expires_at {
+                self.deadlines().insert(key.to_string(), expires_at);
+            }
+            Ok(value)
+        }).await.map_err(Self::unshare)?;
+        if value.is_none() && !self.policy.caches_absence(key) {
+            cache.invalidate(key).await;
+        }
+        Ok(value)
+    }
+
+    /// Turns the error of a read shared by all calls waiting for it back
+    /// into an error of its own.
+    fn unshare(error: Arc<Error>) -> Error {
+        Arc::try_unwrap(error).unwrap_or_else(|error| match Corruption::find(&error) {
+            Some(corruption) => Corruption::error(corruption.offset, corruption.reason.clone()),
+            None => Error::new(error.kind(), error.to_string()),
+        })
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        if let Some(cache) = self.cache_of(key) {
+            cache.invalidate(key).await;
+            self.deadlines().remove(key);
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+        self.pinned.invalidate_all();
+        self.deadlines().clear();
+    }
+
+    /// Drops the entries of all keys that have expired.
+    pub async fn expire_all(&self) {
+        let now = expiry::now();
+        let expired: Vec<String> = self.deadlines.read().unwrap_or_else(PoisonError::into_inner).iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(cache) = self.cache_of(&key) {
+                self.expire(&key, cache).await;
+            }
+        }
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        self.cache.run_pending_tasks().await;
+        self.pinned.run_pending_tasks().await;
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+            entries: self.cache.entry_count() + self.pinned.entry_count(),
+            pinned: self.pinned.entry_count(),
+            bytes: self.cache.weighted_size() + self.pinned.weighted_size(),
+            pinned_bytes: self.pinned.weighted_size(),
+            pinned_capacity: self.pinned_capacity,
+            pinned_evictions: self.counters.pinned_evictions.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        }
+    }
+}
+
+impl From<Cache<String, Option<Value>>> for Primary {
+    /// Uses a cache built elsewhere as it is, following the default
+    /// `CachePolicy`, which pins nothing. Its evictions and expirations are
+    /// not counted.
+    fn from(cache: Cache<String, Option<Value>>) -> Self {
+        Self {
+            cache,
+            pinned: Cache::builder().max_capacity(0).weigher(weight).build(),
+            pinned_capacity: 0,
+            policy: Arc::new(CachePolicy::default()),
+            counters: Arc::new(Counters::default()),
+            deadlines: Deadlines::default(),
+        }
+    }
+}
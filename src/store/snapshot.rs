@@ -0,0 +1,333 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{Error, ErrorKind, Read}, path::{Path, PathBuf}};
+
+use log::{debug, info, warn};
+
+use super::{frame::{Frame, FrameReader}, Cipher, Corruption, Disk, FileHeader, StorageBackend, SyncPolicy, Value};
+
+/// A snapshot that is being written, holding the keys whose value as of the
+/// start of the snapshot is not in the snapshot file yet.
+struct SnapshotCopy {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    target: Disk,
+    pending: HashSet<String>,
+    copied: usize,
+}
+
+/// Wraps the `StorageBackend` of the `Engine` to take snapshots while writes
+/// keep coming in. Before a write changes a key the snapshot has not copied
+/// yet, its old value is copied first, so the snapshot holds every key as it
+/// was when the snapshot started.
+pub struct Snapshotting {
+    inner: Box<dyn StorageBackend>,
+    copy: Option<SnapshotCopy>,
+    failure: Option<String>,
+}
+
+impl Snapshotting {
+    pub fn new(inner: Box<dyn StorageBackend>) -> Self {
+        Self {
+            inner,
+            copy: None,
+            failure: None,
+        }
+    }
+
+    /// Starts a snapshot into `path`, which must not exist yet. The file is
+    /// written under a temporary name until the snapshot is finished.
+    pub fn begin(&mut self, path: &Path) -> Result<(), Error> {
+        if self.copy.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "A snapshot is already running"));
+        }
+        if path.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Snapshot {:?} already exists", path)));
+        }
+
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".tmp");
+        let tmp_path = path.with_file_name(file_name);
+        Self::remove_with_wal(&tmp_path)?;
+
+        let mut target = self.inner.snapshot_file(&tmp_path)?;
+        // The snapshot is synced once when it is finished.
+        target.set_sync_policy(SyncPolicy::Never)?;
+
+        let pending: HashSet<String> = self.inner.list()?.into_iter().collect();
+        self.failure = None;
+        debug!("Snapshot of {} keys into {:?}", pending.len(), path);
+
+        self.copy = Some(SnapshotCopy {
+            path: path.to_path_buf(),
+            tmp_path,
+            target,
+            pending,
+            copied: 0,
+        });
+        Ok(())
+    }
+
+    /// Copies up to `limit` keys into the running snapshot. Returns whether
+    /// every key has been copied.
+    pub fn copy_batch(&mut self, limit: usize) -> Result<bool, Error> {
+        let keys: Vec<String> = match &self.copy {
+            Some(copy) => copy.pending.iter().take(limit).cloned().collect(),
+            None => return Err(self.not_running()),
+        };
+        for key in keys {
+            self.copy_key(&key)?;
+        }
+        Ok(self.copy.as_ref().is_some_and(|copy| copy.pending.is_empty()))
+    }
+
+    /// Syncs the snapshot and moves it to its final path. Returns the number
+    /// of keys in the snapshot.
+    pub fn finish(&mut self) -> Result<usize, Error> {
+        let copy = match self.copy.take() {
+            Some(copy) => copy,
+            None => return Err(self.not_running()),
+        };
+        if !copy.pending.is_empty() {
+            self.copy = Some(copy);
+            return Err(Error::new(ErrorKind::InvalidInput, "Snapshot is not copied completely"));
+        }
+
+        let SnapshotCopy { path, tmp_path, target, copied, .. } = copy;
+        if let Err(e) = Self::publish(target, &tmp_path, &path) {
+            if let Err(e) = Self::remove_with_wal(&tmp_path) {
+                warn!("Failed to remove {:?}: {}", tmp_path, e);
+            }
+            return Err(e);
+        }
+
+        info!("Snapshot of {} keys written to {:?}", copied, path);
+        Ok(copied)
+    }
+
+    /// Syncs the snapshot at `tmp_path`, drops its write-ahead log and
+    /// atomically moves it to `path`.
+    fn publish(mut target: Disk, tmp_path: &Path, path: &Path) -> Result<(), Error> {
+        target.checkpoint()?;
+        drop(target);
+        fs::remove_file(Self::wal_path(tmp_path))?;
+
+        fs::rename(tmp_path, path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    /// Gives up the running snapshot and removes its temporary file.
+    pub fn abort(&mut self) {
+        if let Some(copy) = self.copy.take() {
+            warn!("Aborting snapshot into {:?}", copy.path);
+            let tmp_path = copy.tmp_path.clone();
+            drop(copy);
+            if let Err(e) = Self::remove_with_wal(&tmp_path) {
+                warn!("Failed to remove {:?}: {}", tmp_path, e);
+            }
+        }
+    }
+
+    /// Aborts the running snapshot because copying a key before a write
+    /// failed. The write goes ahead, the snapshot reports the failure.
+    fn fail(&mut self, e: Error) {
+        warn!("Snapshot failed: {}", e);
+        self.failure = Some(e.to_string());
+        self.abort();
+    }
+
+    fn not_running(&mut self) -> Error {
+        match self.failure.take() {
+            Some(failure) => Error::other(format!("Snapshot was aborted: {}", failure)),
+            None => Error::new(ErrorKind::NotFound, "No snapshot is running"),
+        }
+    }
+
+    /// Copies the current value of `key` into the snapshot if it is still
+    /// pending.
+    fn copy_key(&mut self, key: &str) -> Result<(), Error> {
+        let pending = self.copy.as_ref().is_some_and(|copy| copy.pending.contains(key));
+        if !pending {
+            return Ok(());
+        }
+
+        let value = self.inner.get(key.to_string())?;
+        let expires_at = self.inner.expires_at(key.to_string())?;
+        let copy = self.copy.as_mut().unwrap();
+        if let Some(value) = value {
+            copy.target.put_expiring(key.to_string(), value, expires_at)?;
+            copy.copied += 1;
+        }
+        copy.pending.remove(key);
+        Ok(())
+    }
+
+    fn wal_path(path: &Path) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".wal");
+        path.with_file_name(file_name)
+    }
+
+    fn remove_with_wal(path: &Path) -> Result<(), Error> {
+        for path in [path.to_path_buf(), Self::wal_path(path)] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for Snapshotting {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        if let Err(e) = self.copy_key(&key) {
+            self.fail(e);
+        }
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        self.inner.get(key)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        if let Err(e) = self.copy_key(&key) {
+            self.fail(e);
+        }
+        self.inner.del(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.inner.list()
+    }
+
+    fn put_expiring(&mut self, key: String, value: Value, expires_at: Option<u64>) -> Result<(), Error> {
+        if let Err(e) = self.copy_key(&key) {
+            self.fail(e);
+        }
+        self.inner.put_expiring(key, value, expires_at)
+    }
+
+    fn supports_expiry(&self) -> bool {
+        self.inner.supports_expiry()
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>, Error> {
+        self.inner.expires_at(key)
+    }
+
+    /// Expired keys read as missing, so the snapshot leaves them out whether
+    /// they are reaped before they are copied or not.
+    fn reap(&mut self) -> Result<usize, Error> {
+        self.inner.reap()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        if self.copy.is_some() {
+            if let Err(e) = self.copy_batch(usize::MAX) {
+                self.fail(e);
+            }
+        }
+        self.inner.clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        self.inner.is_empty()
+    }
+
+    fn defrag(&mut self) -> Result<(), Error> {
+        self.inner.defrag()
+    }
+
+    fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        self.inner.set_group_commit(group_commit)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+
+    fn snapshot_file(&mut self, path: &Path) -> Result<Disk, Error> {
+        self.inner.snapshot_file(path)
+    }
+}
+
+/// Reads every entry of the snapshot at `path` with the time it expires at,
+/// decrypting it with `cipher` if the snapshot is encrypted. Fails on the
+/// first frame that is damaged or cannot be read.
+fn read_snapshot(path: &Path, cipher: Option<&Cipher>, mut f: impl FnMut(String, Value, Option<u64>) -> Result<(), Error>) -> Result<usize, Error> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut buf: [u8; 16] = [0; 16];
+    file.read_exact(&mut buf)?;
+    let header = FileHeader::from_bytes(buf)?;
+    header.check_supported()?;
+    if header.is_encrypted() {
+        match cipher {
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Snapshot is encrypted, but no encryption key was given")),
+            Some(cipher) if cipher.key_check() != header.key_check => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Wrong encryption key for snapshot"));
+            },
+            Some(_) => {},
+        }
+    }
+    let cipher = cipher.filter(|_| header.is_encrypted());
+
+    let mut entries: usize = 0;
+    let mut frames = FrameReader::new(file, file_len, header.version)?;
+    while let Some(frame) = frames.next_frame()? {
+        let entry = match frame {
+            Frame::Entry(entry) => entry,
+            Frame::Gap(_) => continue,
+        };
+        if !entry.intact {
+            return Err(Corruption::error(entry.header.offset, "Checksum mismatch"));
+        }
+        let entry = entry.decrypt(cipher)?;
+        f(entry.key()?, entry.value()?, entry.expires_at())?;
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// Checks that every entry of the snapshot at `path` is intact and readable
+/// with `cipher`. Returns the number of entries.
+pub fn verify_snapshot(path: &Path, cipher: Option<&Cipher>) -> Result<usize, Error> {
+    check_snapshot(path, cipher, true)
+}
+
+/// Verifies the snapshot at `path` like `verify_snapshot`, and that it holds
+/// no expiring entries unless `supports_expiry` is set.
+fn check_snapshot(path: &Path, cipher: Option<&Cipher>, supports_expiry: bool) -> Result<usize, Error> {
+    let mut keys: HashMap<String, u64> = HashMap::new();
+    let entries = read_snapshot(path, cipher, |key, _, expires_at| {
+        if expires_at.is_some() && !supports_expiry {
+            return Err(Error::new(ErrorKind::Unsupported, format!("Snapshot holds the expiring key {:?}, which this storage backend cannot store", key)));
+        }
+        *keys.entry(key).or_default() += 1;
+        Ok(())
+    })?;
+    if let Some((key, _)) = keys.iter().find(|(_, count)| **count > 1) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Snapshot holds key {:?} more than once", key)));
+    }
+    Ok(entries)
+}
+
+/// Replaces everything in `backend` with the snapshot at `path` once it has
+/// been verified and every entry is known to fit `backend`, so a snapshot
+/// that cannot be restored leaves `backend` as it is. Returns the number of
+/// entries restored.
+pub fn restore_snapshot(path: &Path, cipher: Option<&Cipher>, backend: &mut dyn StorageBackend) -> Result<usize, Error> {
+    let entries = check_snapshot(path, cipher, backend.supports_expiry())?;
+    info!("Restoring {} entries from {:?}", entries, path);
+
+    backend.clear()?;
+    read_snapshot(path, cipher, |key, value, expires_at| backend.put_expiring(key, value, expires_at))
+}
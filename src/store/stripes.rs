@@ -0,0 +1,42 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Locks keys by spreading them over a fixed number of locks. Requests on keys
+/// that share a lock wait for each other, all others never do.
+pub struct Stripes {
+    locks: Vec<RwLock<()>>,
+    hasher: RandomState,
+}
+
+impl Stripes {
+    pub fn new(stripes: usize) -> Self {
+        Self {
+            locks: (0..stripes.max(1)).map(|_| RwLock::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn lock_of(&self, key: &str) -> &RwLock<()> {
+        let stripe = self.hasher.hash_one(key) % self.locks.len() as u64;
+        &self.locks[stripe as usize]
+    }
+
+    pub async fn read(&self, key: &str) -> RwLockReadGuard<'_, ()> {
+        self.lock_of(key).read().await
+    }
+
+    pub async fn write(&self, key: &str) -> RwLockWriteGuard<'_, ()> {
+        self.lock_of(key).write().await
+    }
+
+    /// Locks every key. The locks are taken in order, so this never waits in
+    /// a cycle with another call.
+    pub async fn write_all(&self) -> Vec<RwLockWriteGuard<'_, ()>> {
+        let mut guards = Vec::with_capacity(self.locks.len());
+        for lock in &self.locks {
+            guards.push(lock.write().await);
+        }
+        guards
+    }
+}
@@ -0,0 +1,215 @@
+use std::{path::Path, fs::{File, OpenOptions}, io::{Error, ErrorKind, Read, Seek, SeekFrom, Write}, time::Duration, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread, marker::PhantomData};
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+/// When the write-ahead log is flushed to stable storage.
+///
+/// Only `Always` keeps the data file consistent through an operating system
+/// crash or power loss. With the others the changes to the data file are
+/// not held back until their record is synced, so the operating system may
+/// write them out first. A change cut short then has no record to repair it
+/// from, and shows up as a frame that fails its checksum. A crash of the
+/// process alone loses nothing with any policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Every record is synced before the operation returns.
+    Always,
+    /// Records are synced by a background thread on every tick of the interval.
+    Interval(Duration),
+    /// Records are never synced explicitly.
+    Never,
+}
+
+/// A single physical change to the data file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WalWrite {
+    Write { offset: u64, bytes: Vec<u8> },
+    Truncate { len: u64 },
+}
+
+/// Append-only log of the physical writes of every operation on the data
+/// file. A batch is only applied to the data file after it has been logged, so
+/// replaying all complete batches repairs any torn write in the data file.
+/// Other backends log their own record type `T`.
+///
+/// Every batch is stored as a big-endian u32 payload length, a big-endian u32
+/// CRC32 of the payload and the postcard encoded payload.
+pub struct Wal<T = WalWrite> {
+    file: File,
+    sync_policy: SyncPolicy,
+    /// Stops the background sync thread of the interval policy.
+    sync_stop: Option<Arc<AtomicBool>>,
+    /// Holds back the sync of the always policy until `sync_pending`, so a
+    /// group of batches shares a single sync.
+    group_commit: bool,
+    /// Whether batches were appended since the last sync.
+    unsynced: bool,
+    record: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize + DeserializeOwned> Wal<T> {
+
+    pub fn open(path: &Path) -> Result<(Self, Vec<Vec<T>>), Error> {
+        let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .read(true)
+                .open(path)?;
+
+        let batches = Self::read_batches(&mut file)?;
+
+        let wal = Self {
+            file,
+            sync_policy: SyncPolicy::Always,
+            sync_stop: None,
+            group_commit: false,
+            unsynced: false,
+            record: PhantomData,
+        };
+        Ok((wal, batches))
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) -> Result<(), Error> {
+        if let Some(sync_stop) = self.sync_stop.take() {
+            sync_stop.store(true, Ordering::Relaxed);
+        }
+
+        if let SyncPolicy::Interval(interval) = sync_policy {
+            let file = self.file.try_clone()?;
+            let sync_stop = Arc::new(AtomicBool::new(false));
+            let sync_stop_clone = sync_stop.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    if sync_stop_clone.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Err(e) = file.sync_data() {
+                        warn!("Failed to sync write-ahead log: {}", e);
+                    }
+                }
+            });
+            self.sync_stop = Some(sync_stop);
+        }
+
+        self.sync_policy = sync_policy;
+        Ok(())
+    }
+
+    pub fn set_group_commit(&mut self, group_commit: bool) -> Result<(), Error> {
+        self.group_commit = group_commit;
+        if !group_commit {
+            self.sync_pending()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_group_commit(&self) -> bool {
+        self.group_commit
+    }
+
+    fn read_batches(file: &mut File) -> Result<Vec<Vec<T>>, Error> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut buf: Vec<u8> = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut batches: Vec<Vec<T>> = Vec::new();
+        let mut offset: usize = 0;
+
+        while offset < buf.len() {
+            if buf.len() - offset < 8 {
+                break;
+            }
+            let len = u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(buf[offset + 4..offset + 8].try_into().unwrap());
+
+            if buf.len() - offset - 8 < len {
+                break;
+            }
+            let payload = &buf[offset + 8..offset + 8 + len];
+            if crc32fast::hash(payload) != checksum {
+                break;
+            }
+            let batch: Vec<T> = match postcard::from_bytes(payload) {
+                Ok(batch) => batch,
+                Err(_) => break,
+            };
+
+            batches.push(batch);
+            offset += 8 + len;
+        }
+
+        if offset < buf.len() {
+            warn!("Discarding {} bytes of incomplete write-ahead log", buf.len() - offset);
+            file.set_len(offset as u64)?;
+        }
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        debug!("Read {} batches from write-ahead log", batches.len());
+        Ok(batches)
+    }
+
+    pub fn append(&mut self, batch: &[T]) -> Result<(), Error> {
+        let payload = postcard::to_allocvec(batch)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid write-ahead log batch"))?;
+
+        let len = u32::try_from(payload.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Write-ahead log batch too big"))?;
+
+        let mut buf: Vec<u8> = Vec::with_capacity(payload.len() + 8);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        buf.extend_from_slice(&payload);
+
+        self.file.write_all(&buf)?;
+
+        if self.sync_policy == SyncPolicy::Always {
+            if self.group_commit {
+                self.unsynced = true;
+            } else {
+                self.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Syncs the batches the always policy held back for group commit.
+    pub fn sync_pending(&mut self) -> Result<(), Error> {
+        if self.unsynced {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<u64, Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Drops all batches. Must only be called once the data file is synced.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.sync()
+    }
+}
+
+impl<T> Drop for Wal<T> {
+    fn drop(&mut self) {
+        if let Some(sync_stop) = self.sync_stop.take() {
+            sync_stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -1,8 +1,30 @@
+use std::mem::size_of;
+
 use super::Value;
 
+/// Estimates the bytes an entry of the cache takes up, the key and value
+/// themselves and everything they hold on the heap.
 pub fn weight(key: &String, value: &Option<Value>) -> u32 {
-    let mut weight = 0;
-    weight += std::mem::size_of_val(&key);
-    weight += std::mem::size_of_val(&value);
-    weight as u32
-}
\ No newline at end of file
+    let mut weight = size_of::<String>() + size_of::<Option<Value>>();
+    weight += key.capacity();
+    if let Some(value) = value {
+        weight += heap_size(value);
+    }
+    u32::try_from(weight).unwrap_or(u32::MAX)
+}
+
+/// Returns the bytes `value` holds on the heap, not counting itself.
+fn heap_size(value: &Value) -> usize {
+    match value {
+        Value::Text(text) => text.capacity(),
+        Value::Number(_) | Value::Boolean(_) => 0,
+        Value::Array(values) => {
+            values.capacity() * size_of::<Value>()
+                + values.iter().map(heap_size).sum::<usize>()
+        },
+        Value::Map(entries) => {
+            entries.capacity() * size_of::<(String, Value)>()
+                + entries.iter().map(|(key, value)| key.capacity() + heap_size(value)).sum::<usize>()
+        },
+    }
+}
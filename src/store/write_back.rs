@@ -0,0 +1,173 @@
+use std::{collections::HashMap, io::Error, sync::{Arc, Mutex, MutexGuard, PoisonError, Weak}, time::Duration};
+
+use log::{debug, error, info, warn};
+use tokio::sync::Notify;
+
+use super::{Value, StorageBackend, weight, expiry, actor::StorageActor, snapshot::Snapshotting};
+
+struct Dirty {
+    /// Tells a write apart from later writes of the same key.
+    sequence: u64,
+    /// `None` for a delete.
+    value: Option<Value>,
+    expires_at: Option<u64>,
+}
+
+#[derive(Default)]
+struct Entries {
+    dirty: HashMap<String, Dirty>,
+    sequence: u64,
+}
+
+/// Writes of the `Engine` that are cached but not yet in the secondary
+/// storage. They are flushed in batches, every `interval` and whenever
+/// `limit` keys are dirty.
+pub struct WriteBack {
+    entries: Mutex<Entries>,
+    limit: usize,
+    filled: Arc<Notify>,
+    /// Kept so the writes left over when the `WriteBack` is dropped can
+    /// still be flushed.
+    secondary: Arc<StorageActor>,
+    /// Held while a batch is written, so an older batch never lands after a
+    /// newer one.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl WriteBack {
+    /// Starts flushing the writes to `secondary` in the background until the
+    /// `WriteBack` is dropped, which flushes the rest.
+    pub fn spawn(secondary: &Arc<StorageActor>, interval: Duration, limit: usize) -> Arc<Self> {
+        let filled = Arc::new(Notify::new());
+        let write_back = Arc::new(Self {
+            entries: Mutex::new(Entries::default()),
+            limit: limit.max(1),
+            filled: filled.clone(),
+            secondary: secondary.clone(),
+            flushing: tokio::sync::Mutex::new(()),
+        });
+        tokio::spawn(Self::flusher(Arc::downgrade(&write_back), filled, interval));
+        write_back
+    }
+
+    /// Only holds on to the `WriteBack` while it flushes, so dropping it is
+    /// not held up until the next interval.
+    async fn flusher(write_back: Weak<Self>, filled: Arc<Notify>, interval: Duration) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = filled.notified() => {},
+            }
+
+            let write_back = match write_back.upgrade() {
+                Some(write_back) => write_back,
+                None => break,
+            };
+            if let Err(e) = write_back.flush(&write_back.secondary).await {
+                warn!("Failed to flush writes, retrying with the next batch: {}", e);
+            }
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the value written to `key` that is not flushed yet, `Some(None)`
+    /// if it was deleted or has expired.
+    pub fn get(&self, key: &str) -> Option<Option<Value>> {
+        let entries = self.entries();
+        let dirty = entries.dirty.get(key)?;
+        if expiry::is_expired(dirty.expires_at, expiry::now()) {
+            return Some(None);
+        }
+        Some(dirty.value.clone())
+    }
+
+    /// Records a write of `value` to `key` that expires at `expires_at`, a
+    /// delete if `value` is `None`.
+    pub fn mark(&self, key: String, value: Option<Value>, expires_at: Option<u64>) {
+        let mut entries = self.entries();
+        entries.sequence += 1;
+        let sequence = entries.sequence;
+        entries.dirty.insert(key, Dirty { sequence, value, expires_at });
+        if entries.dirty.len() >= self.limit {
+            self.filled.notify_one();
+        }
+    }
+
+    /// Returns the number of dirty keys and the bytes they take up, which are
+    /// lost if the process stops before they are flushed.
+    pub fn unflushed(&self) -> (u64, u64) {
+        let entries = self.entries();
+        let bytes = entries.dirty.iter()
+            .map(|(key, dirty)| weight(key, &dirty.value) as u64)
+            .sum();
+        (entries.dirty.len() as u64, bytes)
+    }
+
+    /// Writes every dirty key to `secondary` as one group and returns how
+    /// many there were. Keys written again meanwhile stay dirty.
+    pub async fn flush(&self, secondary: &StorageActor) -> Result<usize, Error> {
+        let _flushing = self.flushing.lock().await;
+
+        let batch: Vec<(String, u64, Option<Value>, Option<u64>)> = self.entries().dirty.iter()
+            .map(|(key, dirty)| (key.clone(), dirty.sequence, dirty.value.clone(), dirty.expires_at))
+            .collect();
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        debug!("Flushing {} writes", batch.len());
+
+        let writes: Vec<(String, Option<Value>, Option<u64>)> = batch.iter()
+            .map(|(key, _, value, expires_at)| (key.clone(), value.clone(), *expires_at))
+            .collect();
+        secondary.write(move |secondary| Self::store(secondary, writes)).await?;
+
+        let mut entries = self.entries();
+        for (key, sequence, _, _) in &batch {
+            if entries.dirty.get(key).is_some_and(|dirty| dirty.sequence == *sequence) {
+                entries.dirty.remove(key);
+            }
+        }
+        Ok(batch.len())
+    }
+
+    fn store(secondary: &mut Snapshotting, writes: Vec<(String, Option<Value>, Option<u64>)>) -> Result<(), Error> {
+        for (key, value, expires_at) in writes {
+            match value {
+                Some(value) => secondary.put_expiring(key, value, expires_at)?,
+                None if secondary.get(key.clone())?.is_some() => secondary.del(key)?,
+                None => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every dirty key once a running flush is done, and keeps others
+    /// from starting until the returned guard is dropped.
+    pub async fn discard(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        let flushing = self.flushing.lock().await;
+        self.entries().dirty.clear();
+        flushing
+    }
+}
+
+impl Drop for WriteBack {
+    /// Flushes the writes that are still dirty on the calling thread, as the
+    /// storage thread may not be waited for here.
+    fn drop(&mut self) {
+        self.filled.notify_one();
+        let writes: Vec<(String, Option<Value>, Option<u64>)> = self.entries().dirty.drain()
+            .map(|(key, dirty)| (key, dirty.value, dirty.expires_at))
+            .collect();
+        if writes.is_empty() {
+            return;
+        }
+        let count = writes.len();
+        match self.secondary.write_now(|secondary| Self::store(secondary, writes)) {
+            Ok(()) => info!("Flushed {} writes on shutdown", count),
+            Err(e) => error!("Lost {} acknowledged writes, failed to flush them on shutdown: {}", count, e),
+        }
+    }
+}
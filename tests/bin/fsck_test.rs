@@ -0,0 +1,128 @@
+use std::{path::Path, process::Command};
+
+use varia_db::store::{Cipher, Disk, StorageBackend, Value, lock_data};
+use std::fs;
+
+fn setup(test_name: &str) -> Disk {
+    Disk::new(Path::new(
+        format!("./target/tmp/fsck_test_{}.bin", test_name).as_str(),
+    )).unwrap()
+}
+
+fn teardown(test_name: &str) {
+    for suffix in [".bin", ".bin.wal", ".lock", ".salvage.bin", ".salvage.bin.wal"] {
+        let path = format!("./target/tmp/fsck_test_{}{}", test_name, suffix);
+        if Path::new(&path).exists() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+fn fsck(args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_varia-fsck")).args(args).output().unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn test_clean_file() {
+    let mut disk: Disk = setup("test_clean_file");
+
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
+
+    drop(disk);
+
+    let (code, stdout) = fsck(&["./target/tmp/fsck_test_test_clean_file.bin"]);
+
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Entries:        1"));
+    assert!(stdout.contains("Gap frames:     1"));
+    assert!(stdout.contains("Errors:         0"));
+
+    teardown("test_clean_file");
+}
+
+#[test]
+fn test_salvage() {
+    let mut disk: Disk = setup("test_salvage");
+
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".to_string())).unwrap();
+    disk.put("test_key_3".to_string(), Value::Text("test_value_3".to_string())).unwrap();
+
+    drop(disk);
+
+    let path = Path::new("./target/tmp/fsck_test_test_salvage.bin");
+    let mut buf = fs::read(path).unwrap();
+    let position = buf.windows(12).position(|window| window == b"test_value_1").unwrap();
+    buf[position] = b'X';
+    let position = buf.windows(10).position(|window| window == b"test_key_2").unwrap();
+    // Opcode, key length and value length precede the key.
+    buf[position - 3] = 0xff;
+    fs::write(path, buf).unwrap();
+
+    let (code, stdout) = fsck(&[
+        "./target/tmp/fsck_test_test_salvage.bin",
+        "--salvage",
+        "./target/tmp/fsck_test_test_salvage.salvage.bin",
+    ]);
+
+    assert_eq!(code, 1);
+    assert!(stdout.contains("Errors:         2"));
+
+    let disk = Disk::new(Path::new("./target/tmp/fsck_test_test_salvage.salvage.bin")).unwrap();
+    assert_eq!(disk.list().unwrap(), vec!["test_key_3".to_string()]);
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Text("test_value_3".to_string())));
+    drop(disk);
+
+    teardown("test_salvage");
+}
+
+#[test]
+fn test_encrypted_file() {
+    let key_path = "./target/tmp/fsck_test_test_encrypted_file.key";
+    fs::write(key_path, "a".repeat(64)).unwrap();
+
+    let path = Path::new("./target/tmp/fsck_test_test_encrypted_file.bin");
+    let mut disk = Disk::new_encrypted(path, Cipher::from_key_file(Path::new(key_path)).unwrap()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    drop(disk);
+
+    let (code, stdout) = fsck(&["./target/tmp/fsck_test_test_encrypted_file.bin"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("key encrypted"));
+
+    let (code, stdout) = fsck(&["./target/tmp/fsck_test_test_encrypted_file.bin", "--key-file", key_path]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("key \"test_key_1\""));
+    assert!(stdout.contains("Errors:         0"));
+
+    fs::remove_file(key_path).unwrap();
+    teardown("test_encrypted_file");
+}
+
+#[test]
+fn test_salvage_locked() {
+    let mut disk: Disk = setup("test_salvage_locked");
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    drop(disk);
+
+    let path = Path::new("./target/tmp/fsck_test_test_salvage_locked.bin");
+    let salvage = "./target/tmp/fsck_test_test_salvage_locked.salvage.bin";
+
+    // A plain check does not need the lock, a salvage does.
+    let lock = lock_data(path).unwrap();
+    let (code, _) = fsck(&[path.to_str().unwrap()]);
+    assert_eq!(code, 0);
+    let (code, _) = fsck(&[path.to_str().unwrap(), "--salvage", salvage]);
+    assert_eq!(code, 2);
+
+    assert!(!Path::new(salvage).exists());
+
+    drop(lock);
+    let (code, _) = fsck(&[path.to_str().unwrap(), "--salvage", salvage]);
+    assert_eq!(code, 0);
+
+    teardown("test_salvage_locked");
+}
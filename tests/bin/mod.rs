@@ -0,0 +1,5 @@
+pub mod fsck_test;
+
+pub mod ndjson_test;
+
+pub mod rekey_test;
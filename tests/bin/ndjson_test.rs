@@ -0,0 +1,86 @@
+use std::{fs::{self, File}, path::Path, process::{Command, Output}};
+
+fn varia_db(data_dir: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_varia-db"))
+        .args(args)
+        .env("LOG_LEVEL", "error")
+        .env("DATA_DIR", data_dir)
+        .env("PORT", "8654")
+        .env("CACHE_SIZE", "1000")
+        .env("CACHE_TTL", "60")
+        .env("CACHE_TTI", "60")
+        .env("CORS_ALLOWED_ORIGINS", "*")
+        .output()
+        .unwrap()
+}
+
+fn teardown(test_name: &str) {
+    for suffix in ["_source", "_target"] {
+        let path = format!("./target/tmp/ndjson_test_{}{}", test_name, suffix);
+        if Path::new(&path).exists() {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+    let path = format!("./target/tmp/ndjson_test_{}.ndjson", test_name);
+    if Path::new(&path).exists() {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_export_and_import() {
+    let source = "./target/tmp/ndjson_test_test_export_and_import_source";
+    let target = "./target/tmp/ndjson_test_test_export_and_import_target";
+    let file = "./target/tmp/ndjson_test_test_export_and_import.ndjson";
+
+    fs::write(file, "{\"key\":\"a\",\"value\":{\"Text\":\"hello\"}}\n{\"key\":\"b\",\"value\":{\"Number\":42}}\n").unwrap();
+    let output = varia_db(source, &["import", "--input", file]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Imported 2 entries"));
+
+    let output = varia_db(source, &["export", "--prefix", "a"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "{\"key\":\"a\",\"value\":{\"Text\":\"hello\"}}\n");
+
+    fs::remove_file(file).unwrap();
+    let output = varia_db(source, &["export", "--output", file]);
+    assert!(output.status.success());
+
+    let output = varia_db(target, &["import", "--input", file, "--on-conflict", "fail"]);
+    assert!(output.status.success());
+    let output = varia_db(target, &["import", "--input", file, "--on-conflict", "fail"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = varia_db(target, &["export"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), fs::read_to_string(file).unwrap());
+
+    teardown("test_export_and_import");
+}
+
+#[test]
+fn test_refuses_locked_data_dir() {
+    let source = "./target/tmp/ndjson_test_test_refuses_locked_data_dir_source";
+    fs::create_dir_all(source).unwrap();
+
+    // Held like the server holds it.
+    let lock = File::create(Path::new(source).join("LOCK")).unwrap();
+    lock.lock().unwrap();
+    let output = varia_db(source, &["export"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Another process holds the lock"));
+
+    drop(lock);
+    let output = varia_db(source, &["export"]);
+    assert!(output.status.success());
+
+    teardown("test_refuses_locked_data_dir");
+}
+
+#[test]
+fn test_invalid_arguments() {
+    let output = varia_db("./target/tmp/ndjson_test_test_invalid_arguments", &["import", "--on-conflict", "replace"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = varia_db("./target/tmp/ndjson_test_test_invalid_arguments", &["backup"]);
+    assert_eq!(output.status.code(), Some(2));
+}
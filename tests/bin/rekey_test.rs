@@ -0,0 +1,31 @@
+use std::{fs, path::Path, process::{Command, Output}};
+
+use varia_db::store::{DataDir, StorageBackend, Value, lock_data};
+
+fn rekey(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_varia-rekey")).args(args).output().unwrap()
+}
+
+#[test]
+fn test_refuses_locked_data_dir() {
+    let path = Path::new("./target/tmp/rekey_test_test_refuses_locked_data_dir");
+    let key_file = "./target/tmp/rekey_test_test_refuses_locked_data_dir.key";
+    fs::write(key_file, "1".repeat(64)).unwrap();
+
+    let mut data_dir = DataDir::new(path).unwrap();
+    data_dir.put("key".to_string(), Value::Number(1)).unwrap();
+    drop(data_dir);
+
+    // Held like the server holds it.
+    let lock = lock_data(path).unwrap();
+    let output = rekey(&[path.to_str().unwrap(), "--new-key-file", key_file]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Another process holds the lock"));
+
+    drop(lock);
+    let output = rekey(&[path.to_str().unwrap(), "--new-key-file", key_file]);
+    assert!(output.status.success());
+
+    fs::remove_dir_all(path).unwrap();
+    fs::remove_file(key_file).unwrap();
+}
@@ -3,4 +3,7 @@
 mod store;
 
 #[cfg(test)]
-mod server;
\ No newline at end of file
+mod server;
+
+#[cfg(test)]
+mod bin;
@@ -1,29 +1,17 @@
 
-use std::{path::Path, vec};
+use std::vec;
 
 use moka::future::Cache;
-use varia_db::{store::{Disk, Engine}, server::EngineService}; 
-use std::fs;
+use varia_db::{store::{Memory, Engine}, server::EngineService}; 
 
 #[allow(dead_code)]
-fn setup(test_name: &str) -> EngineService {
+fn setup() -> EngineService {
     EngineService::new(
-        Engine::new(
-            Disk::new(Path::new(
-                format!("./target/tmp/engine_test_{}.bin", test_name).as_str(),
-            )).unwrap(), Cache::new(1000),
-        ),
+        Engine::new(Memory::new(), Cache::new(1000)),
         vec!["*".to_string()],
     )
 }
 
-#[allow(dead_code)]
-fn teardown(test_name: &str) {
-    fs::remove_file(Path::new(
-        format!("./target/tmp/engine_test_{}.bin", test_name).as_str(),
-    )).unwrap();
-}
-
 #[tokio::test]
 async fn test_preflight() {
     // TODO: Implement
@@ -0,0 +1,455 @@
+use varia_db::store::{StorageBackend, Value};
+
+/// Defines a test for each function of this module that `StorageBackend`s
+/// with files have to pass. `$setup` opens the backend of a test by its name,
+/// `$teardown` removes its files once the backend is dropped.
+macro_rules! backend_tests {
+    ($setup:ident, $teardown:ident) => {
+        backend_tests!(@tests $setup, $teardown,
+            test_put_and_get => put_and_get,
+            test_put_and_delete => put_and_delete,
+            test_put_and_update => put_and_update,
+            test_empty_key_and_value_1 => empty_key_and_value_1,
+            test_empty_key_and_value_2 => empty_key_and_value_2,
+            test_very_large_value_1 => very_large_value_1,
+            test_very_large_value_2 => very_large_value_2,
+            test_very_large_key_1 => very_large_key_1,
+            test_very_large_key_2 => very_large_key_2,
+            test_gapping => gapping,
+            test_gapping_very_large_values => gapping_very_large_values,
+            test_gapping_very_small_values => gapping_very_small_values,
+            test_list_small_keys => list_small_keys,
+            test_list_large_keys => list_large_keys,
+            test_list_gapping => list_gapping,
+            test_list_gapping_very_large_values => list_gapping_very_large_values,
+            test_clear => clear,
+            test_clear_gapping => clear_gapping,
+            test_len => len,
+            test_is_empty => is_empty,
+            test_defrag => defrag,
+            test_reopen => reopen
+        );
+    };
+    (@tests $setup:ident, $teardown:ident, $($test:ident => $run:ident),*) => {
+        $(
+            #[test]
+            fn $test() {
+                $crate::store::backend_test::$run(|| $setup(stringify!($test)));
+                $teardown(stringify!($test));
+            }
+        )*
+    };
+}
+
+pub(crate) use backend_tests;
+
+pub fn put_and_get<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn put_and_delete<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    backend.del(key.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+}
+
+pub fn put_and_update<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    let value = Value::Text("test_value_2".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn empty_key_and_value_1<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "".to_string();
+    let value = Value::Text("".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn empty_key_and_value_2<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "".to_string();
+    let value = Value::Text("".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn very_large_value_1<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn very_large_value_2<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    let value = Value::Text("test_value_2".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn very_large_key_1<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".repeat(100000);
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn very_large_key_2<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".repeat(100000);
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    let value = Value::Text("test_value_2".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn gapping<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    backend.del(key.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+
+    let key = "test_key_2".to_string();
+        let value = Value::Text("test_value_2".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn gapping_very_large_values<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    backend.del(key.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn gapping_very_small_values<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(10));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    backend.del(key.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".repeat(10));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+pub fn list_small_keys<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.list().unwrap();
+
+    assert_eq!(result, vec![key.clone()]);
+}
+
+pub fn list_large_keys<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".repeat(100000);
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.list().unwrap();
+
+    assert_eq!(result, vec![key.clone()]);
+}
+
+pub fn list_gapping<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.del(key.clone()).unwrap();
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.list().unwrap();
+
+    assert_eq!(result, vec![key.clone()]);
+}
+
+pub fn list_gapping_very_large_values<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.del(key.clone()).unwrap();
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    let result = backend.list().unwrap();
+
+    assert_eq!(result, vec![key.clone()]);
+}
+
+pub fn clear<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.clear().unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+}
+
+pub fn clear_gapping<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.del(key.clone()).unwrap();
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.clear().unwrap();
+
+    let result = backend.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+}
+
+pub fn len<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    assert_eq!(backend.len().unwrap(), 0);
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    assert_eq!(backend.len().unwrap(), 1);
+
+    backend.del(key.clone()).unwrap();
+
+    assert_eq!(backend.len().unwrap(), 0);
+}
+
+pub fn is_empty<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    assert!(backend.is_empty().unwrap());
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    assert!(!backend.is_empty().unwrap());
+
+    backend.del(key.clone()).unwrap();
+
+    assert!(backend.is_empty().unwrap());
+}
+
+pub fn defrag<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+    backend.del(key.clone()).unwrap();
+
+    let key = "test_key_2".to_string();
+    let value = Value::Text("test_value_2".repeat(100000));
+
+    backend.put(key.clone(), value.clone()).unwrap();
+
+    backend.defrag().unwrap();
+
+    let result = backend.list().unwrap();
+
+    assert_eq!(result, vec![key.clone()]);
+}
+
+pub fn reopen<B: StorageBackend>(open: impl Fn() -> B) {
+    let mut backend = open();
+
+    backend.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    backend.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100000))).unwrap();
+    backend.put("test_key_3".to_string(), Value::Number(42)).unwrap();
+    backend.del("test_key_1".to_string()).unwrap();
+
+    drop(backend);
+
+    let backend = open();
+
+    assert_eq!(backend.len().unwrap(), 2);
+    assert_eq!(backend.get("test_key_1".to_string()).unwrap(), None);
+    assert_eq!(backend.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(100000))));
+    assert_eq!(backend.get("test_key_3".to_string()).unwrap(), Some(Value::Number(42)));
+    assert_eq!(backend.list().unwrap(), vec!["test_key_2".to_string(), "test_key_3".to_string()]);
+}
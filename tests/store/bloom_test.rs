@@ -0,0 +1,53 @@
+use varia_db::store::Bloom;
+
+/// Bits per key and hashes of the filters of `Lsm` segments.
+const BITS_PER_KEY: f64 = 10.0;
+const HASHES: f64 = 7.0;
+
+fn key(i: u64) -> String {
+    format!("user:{:016x}", i * 2654435761)
+}
+
+fn filled(keys: u64) -> Bloom {
+    let mut bloom = Bloom::with_capacity(keys);
+    for i in 0..keys {
+        bloom.insert(&key(i));
+    }
+    bloom
+}
+
+#[test]
+fn test_no_false_negatives() {
+    let bloom = filled(10000);
+    for i in 0..10000 {
+        assert!(bloom.contains(&key(i)));
+    }
+
+    let bloom = Bloom::from_bytes(&bloom.to_bytes(), 0).unwrap();
+    for i in 0..10000 {
+        assert!(bloom.contains(&key(i)));
+    }
+}
+
+#[test]
+fn test_false_positive_rate() {
+    let bloom = filled(10000);
+
+    let probes = 100000;
+    let false_positives = (0..probes).filter(|i| bloom.contains(&format!("{}.", key(*i)))).count();
+    let rate = false_positives as f64 / probes as f64;
+
+    let target = (1.0 - (-HASHES / BITS_PER_KEY).exp()).powf(HASHES);
+    assert!(rate < target * 1.2, "False positive rate {} above the target {}", rate, target);
+}
+
+#[test]
+fn test_legacy_filter_holds_every_key() {
+    let mut bytes = filled(100).to_bytes();
+    bytes[0] = 0;
+
+    let bloom = Bloom::from_bytes(&bytes, 0).unwrap();
+    for i in 0..1000 {
+        assert!(bloom.contains(&format!("other:{}", i)));
+    }
+}
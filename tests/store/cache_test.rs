@@ -0,0 +1,157 @@
+use std::{io::Error, sync::{Arc, atomic::{AtomicUsize, Ordering}}, thread, time::Duration};
+
+use moka::future::Cache;
+use varia_db::store::{Engine, Memory, StorageBackend, Value};
+
+/// Counts the reads of the keys and makes each of them slow, so concurrent
+/// requests overlap with them.
+struct Slow {
+    inner: Memory,
+    gets: Arc<AtomicUsize>,
+}
+
+impl StorageBackend for Slow {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        self.inner.get(key)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        self.inner.del(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.inner.list()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.inner.clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        self.inner.len()
+    }
+}
+
+fn setup(entries: &[(&str, Value)]) -> (Engine, Arc<AtomicUsize>) {
+    let mut inner = Memory::new();
+    for (key, value) in entries {
+        inner.put(key.to_string(), value.clone()).unwrap();
+    }
+    let gets = Arc::new(AtomicUsize::new(0));
+    let engine = Engine::new(Slow { inner, gets: gets.clone() }, Cache::new(1000));
+    (engine, gets)
+}
+
+/// Returns the value of `key` in the secondary storage, bypassing the cache.
+async fn stored(engine: &Engine, key: &str) -> Option<Value> {
+    let export = engine.export(&[key.to_string()]).await.unwrap();
+    varia_db::store::read_records(export.as_slice()).unwrap().pop().map(|(_, value)| value)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_read_your_writes() {
+    let (engine, _) = setup(&[]);
+
+    let mut handles = Vec::new();
+    for task in 0..8 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for i in 0..20 {
+                let key = format!("key{}", i % 4);
+                let value = Value::Number(task * 100 + i);
+                engine.put(format!("{}x{}", key, task), value.clone()).await.unwrap();
+                assert_eq!(engine.get(format!("{}x{}", key, task)).await.unwrap(), Some(value));
+            }
+            engine.del(format!("key0x{}", task)).await.unwrap();
+            assert_eq!(engine.get(format!("key0x{}", task)).await.unwrap(), None);
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_puts() {
+    let (engine, _) = setup(&[]);
+
+    let mut handles = Vec::new();
+    for i in 0..32 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            engine.put("key".to_string(), Value::Number(i)).await.unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    // The last write wins in both storages.
+    let cached = engine.get("key".to_string()).await.unwrap();
+    assert!(cached.is_some());
+    assert_eq!(cached, stored(&engine, "key").await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_misses_during_puts() {
+    let entries: Vec<(String, Value)> = (0..64).map(|i| (format!("key{}", i), Value::Number(1))).collect();
+    let entries: Vec<(&str, Value)> = entries.iter().map(|(key, value)| (key.as_str(), value.clone())).collect();
+    let (engine, _) = setup(&entries);
+
+    // Each miss reads the old value around the time the put writes the new
+    // one, and must not cache it after the put.
+    let mut handles = Vec::new();
+    for i in 0..64 {
+        let reader = engine.clone();
+        handles.push(tokio::spawn(async move {
+            reader.get(format!("key{}", i)).await.unwrap();
+        }));
+        let writer = engine.clone();
+        handles.push(tokio::spawn(async move {
+            writer.put(format!("key{}", i), Value::Number(2)).await.unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    for i in 0..64 {
+        assert_eq!(engine.get(format!("key{}", i)).await.unwrap(), Some(Value::Number(2)));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_coalesced_misses() {
+    let (engine, gets) = setup(&[("key", Value::Number(1))]);
+
+    let mut handles = Vec::new();
+    for _ in 0..32 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            engine.get("key".to_string()).await.unwrap()
+        }));
+    }
+    for handle in handles {
+        assert_eq!(handle.await.unwrap(), Some(Value::Number(1)));
+    }
+
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_clear_invalidates() {
+    let (engine, _) = setup(&[]);
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Number(1)));
+
+    engine.clear().await.unwrap();
+
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+    assert_eq!(engine.put("key".to_string(), Value::Number(2)).await.unwrap(), None);
+}
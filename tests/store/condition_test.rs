@@ -0,0 +1,111 @@
+use std::{io::ErrorKind, time::Duration};
+
+use moka::future::Cache;
+use varia_db::store::{Condition, Conflict, Engine, Memory, Value};
+
+fn conflict(result: Result<Option<Value>, std::io::Error>) -> Option<Value> {
+    let error = result.err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    Conflict::find(&error).unwrap().current.clone()
+}
+
+#[tokio::test]
+async fn test_put_if_absent() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+
+    assert_eq!(engine.put_if("key".to_string(), Value::Number(1), None, Condition::Absent).await.unwrap(), None);
+    assert_eq!(conflict(engine.put_if("key".to_string(), Value::Number(2), None, Condition::Absent).await), Some(Value::Number(1)));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Number(1)));
+}
+
+#[tokio::test]
+async fn test_put_if_exists() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+
+    assert_eq!(conflict(engine.put_if("key".to_string(), Value::Number(1), None, Condition::Exists).await), None);
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    assert_eq!(engine.put_if("key".to_string(), Value::Number(2), None, Condition::Exists).await.unwrap(), Some(Value::Number(1)));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Number(2)));
+}
+
+#[tokio::test]
+async fn test_put_if_equals() {
+    // Nothing is cached, so the condition is checked against the secondary
+    // storage.
+    let engine = Engine::new(Memory::new(), Cache::new(0));
+    engine.put("key".to_string(), Value::Text("a".to_string())).await.unwrap();
+
+    let stale = Condition::Equals(Value::Text("b".to_string()));
+    assert_eq!(conflict(engine.put_if("key".to_string(), Value::Text("c".to_string()), None, stale).await), Some(Value::Text("a".to_string())));
+
+    let current = Condition::Equals(Value::Text("a".to_string()));
+    assert_eq!(engine.put_if("key".to_string(), Value::Text("c".to_string()), None, current).await.unwrap(), Some(Value::Text("a".to_string())));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Text("c".to_string())));
+}
+
+#[tokio::test]
+async fn test_del_if() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+
+    assert_eq!(conflict(engine.del_if("key".to_string(), Value::Number(2)).await), Some(Value::Number(1)));
+    assert_eq!(engine.del_if("key".to_string(), Value::Number(1)).await.unwrap(), Some(Value::Number(1)));
+    assert_eq!(conflict(engine.del_if("key".to_string(), Value::Number(1)).await), None);
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_conditions_in_write_back() {
+    let mut engine = Engine::new(Memory::new(), Cache::new(0));
+    engine.set_write_back(Duration::from_secs(3600), 1024);
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    assert_eq!(conflict(engine.put_if("key".to_string(), Value::Number(2), None, Condition::Absent).await), Some(Value::Number(1)));
+    assert_eq!(engine.put_if("key".to_string(), Value::Number(2), None, Condition::Equals(Value::Number(1))).await.unwrap(), Some(Value::Number(1)));
+    assert_eq!(engine.del_if("key".to_string(), Value::Number(2)).await.unwrap(), Some(Value::Number(2)));
+
+    engine.flush().await.unwrap();
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_increments() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+    engine.put("counter".to_string(), Value::Number(0)).await.unwrap();
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for _ in 0..25 {
+                let mut current = engine.get("counter".to_string()).await.unwrap();
+                loop {
+                    let next = match &current {
+                        Some(Value::Number(n)) => Value::Number(n + 1),
+                        _ => panic!("Expected a number"),
+                    };
+                    let condition = Condition::Equals(current.clone().unwrap());
+                    match engine.put_if("counter".to_string(), next, None, condition).await {
+                        Ok(_) => break,
+                        Err(e) => current = Conflict::find(&e).unwrap().current.clone(),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    // No increment was lost to another one.
+    assert_eq!(engine.get("counter".to_string()).await.unwrap(), Some(Value::Number(100)));
+}
+
+#[test]
+fn test_parse_condition() {
+    assert_eq!("absent".parse::<Condition>().unwrap(), Condition::Absent);
+    assert_eq!("exists".parse::<Condition>().unwrap(), Condition::Exists);
+    assert!("equals".parse::<Condition>().is_err());
+}
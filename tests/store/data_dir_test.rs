@@ -0,0 +1,183 @@
+use std::path::Path;
+
+use varia_db::store::{DataDir, Disk, StorageBackend, Value};
+use std::fs;
+
+fn setup(test_name: &str) -> DataDir {
+    DataDir::new(Path::new(
+        format!("./target/tmp/data_dir_test_{}", test_name).as_str(),
+    )).unwrap()
+}
+
+fn teardown(test_name: &str) {
+    fs::remove_dir_all(Path::new(
+        format!("./target/tmp/data_dir_test_{}", test_name).as_str(),
+    )).unwrap();
+}
+
+#[test]
+fn test_put_and_get() {
+    let mut data_dir: DataDir = setup("test_put_and_get");
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    data_dir.put(key.clone(), value.clone()).unwrap();
+
+    let result = data_dir.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+
+    teardown("test_put_and_get");
+}
+
+#[test]
+fn test_put_and_delete() {
+    let mut data_dir: DataDir = setup("test_put_and_delete");
+
+    data_dir.put("test_key".to_string(), Value::Text("test_value".to_string())).unwrap();
+    data_dir.del("test_key".to_string()).unwrap();
+
+    assert_eq!(data_dir.get("test_key".to_string()).unwrap(), None);
+    assert!(data_dir.is_empty().unwrap());
+
+    teardown("test_put_and_delete");
+}
+
+#[test]
+fn test_rotation() {
+    let mut data_dir: DataDir = setup("test_rotation");
+    data_dir.set_segment_size(4096);
+
+    for i in 0..20 {
+        data_dir.put(format!("test_key_{:02}", i), Value::Text("test_value".repeat(50))).unwrap();
+    }
+
+    let segments = data_dir.segments();
+    assert!(segments.len() > 2);
+    assert!(segments.iter().all(|path| path.exists()));
+    assert_eq!(data_dir.len().unwrap(), 20);
+    drop(data_dir);
+
+    let data_dir: DataDir = setup("test_rotation");
+    assert_eq!(data_dir.segments(), segments);
+    assert_eq!(data_dir.list().unwrap(), (0..20).map(|i| format!("test_key_{:02}", i)).collect::<Vec<String>>());
+    for i in 0..20 {
+        assert_eq!(data_dir.get(format!("test_key_{:02}", i)).unwrap(), Some(Value::Text("test_value".repeat(50))));
+    }
+
+    teardown("test_rotation");
+}
+
+#[test]
+fn test_update_moves_key() {
+    let mut data_dir: DataDir = setup("test_update_moves_key");
+    data_dir.set_segment_size(1024);
+
+    data_dir.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))).unwrap();
+    data_dir.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100))).unwrap();
+    let segments = data_dir.segments();
+    assert_eq!(segments.len(), 3);
+
+    // Moving the key out empties the first segment, which is removed.
+    data_dir.put("test_key_1".to_string(), Value::Number(42)).unwrap();
+    assert_eq!(data_dir.segments(), segments[1..].to_vec());
+    assert!(!segments[0].exists());
+
+    data_dir.del("test_key_2".to_string()).unwrap();
+    assert_eq!(data_dir.segments(), segments[2..].to_vec());
+    drop(data_dir);
+
+    let data_dir: DataDir = setup("test_update_moves_key");
+    assert_eq!(data_dir.list().unwrap(), vec!["test_key_1".to_string()]);
+    assert_eq!(data_dir.get("test_key_1".to_string()).unwrap(), Some(Value::Number(42)));
+
+    teardown("test_update_moves_key");
+}
+
+#[test]
+fn test_duplicate_after_crash() {
+    let mut data_dir: DataDir = setup("test_duplicate_after_crash");
+    data_dir.set_segment_size(1024);
+
+    data_dir.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))).unwrap();
+    data_dir.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100))).unwrap();
+    let segments = data_dir.segments();
+    drop(data_dir);
+
+    // A crash between writing the new copy and deleting the old one.
+    let mut disk = Disk::new(&segments[2]).unwrap();
+    disk.put("test_key_1".to_string(), Value::Number(42)).unwrap();
+    drop(disk);
+
+    let data_dir: DataDir = setup("test_duplicate_after_crash");
+    assert_eq!(data_dir.len().unwrap(), 2);
+    assert_eq!(data_dir.get("test_key_1".to_string()).unwrap(), Some(Value::Number(42)));
+    assert_eq!(data_dir.segments(), segments[1..].to_vec());
+
+    teardown("test_duplicate_after_crash");
+}
+
+#[test]
+fn test_clear() {
+    let mut data_dir: DataDir = setup("test_clear");
+    data_dir.set_segment_size(1024);
+
+    for i in 0..5 {
+        data_dir.put(format!("test_key_{}", i), Value::Text("test_value".repeat(100))).unwrap();
+    }
+    data_dir.clear().unwrap();
+
+    assert_eq!(data_dir.segments().len(), 1);
+    assert!(data_dir.is_empty().unwrap());
+    assert_eq!(data_dir.list().unwrap(), Vec::<String>::new());
+
+    data_dir.put("test_key".to_string(), Value::Number(42)).unwrap();
+    drop(data_dir);
+
+    let data_dir: DataDir = setup("test_clear");
+    assert_eq!(data_dir.list().unwrap(), vec!["test_key".to_string()]);
+
+    teardown("test_clear");
+}
+
+#[test]
+fn test_adopt_legacy_file() {
+    let dir = Path::new("./target/tmp/data_dir_test_test_adopt_legacy_file");
+    fs::create_dir_all(dir).unwrap();
+
+    let mut disk = Disk::new(&dir.join("varia.bin")).unwrap();
+    disk.put("test_key".to_string(), Value::Text("test_value".to_string())).unwrap();
+    drop(disk);
+
+    let mut data_dir: DataDir = setup("test_adopt_legacy_file");
+    assert!(!dir.join("varia.bin").exists());
+    assert_eq!(data_dir.segments(), vec![dir.join("000000.bin")]);
+    assert_eq!(data_dir.get("test_key".to_string()).unwrap(), Some(Value::Text("test_value".to_string())));
+
+    data_dir.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    drop(data_dir);
+
+    let data_dir: DataDir = setup("test_adopt_legacy_file");
+    assert_eq!(data_dir.len().unwrap(), 2);
+
+    teardown("test_adopt_legacy_file");
+}
+
+#[test]
+fn test_remove_orphans() {
+    let mut data_dir: DataDir = setup("test_remove_orphans");
+    data_dir.put("test_key".to_string(), Value::Number(42)).unwrap();
+    drop(data_dir);
+
+    let orphan = Path::new("./target/tmp/data_dir_test_test_remove_orphans/000007.bin");
+    let mut disk = Disk::new(orphan).unwrap();
+    disk.put("test_key".to_string(), Value::Number(7)).unwrap();
+    drop(disk);
+
+    let data_dir: DataDir = setup("test_remove_orphans");
+    assert!(!orphan.exists());
+    assert_eq!(data_dir.get("test_key".to_string()).unwrap(), Some(Value::Number(42)));
+
+    teardown("test_remove_orphans");
+}
@@ -1,8 +1,10 @@
 
 use std::path::Path;
 
-use varia_db::store::{Disk, Value};
-use std::fs;
+use varia_db::store::{Disk, StorageBackend, Value, Corruption, Cipher, FileHeader, SyncPolicy, FORMAT_VERSION};
+use std::{fs, io::Write, thread};
+
+use super::backend_test::backend_tests;
 
 fn setup(test_name: &str) -> Disk {
     Disk::new(Path::new(
@@ -14,459 +16,642 @@ fn teardown(test_name: &str) {
     fs::remove_file(Path::new(
         format!("./target/tmp/disk_test_{}.bin", test_name).as_str(),
     )).unwrap();
+    fs::remove_file(Path::new(
+        format!("./target/tmp/disk_test_{}.bin.wal", test_name).as_str(),
+    )).unwrap();
 }
 
+backend_tests!(setup, teardown);
+
 #[test]
-fn test_put_and_get() {
-    let mut disk: Disk = setup("test_put_and_get");
+fn test_defrag_shrinks_file() {
+    let mut disk: Disk = setup("test_defrag_shrinks_file");
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+    for i in 0..10 {
+        disk.put(format!("test_key_{}", i), Value::Text("test_value".repeat(1000))).unwrap();
+    }
+    for i in 0..5 {
+        disk.del(format!("test_key_{}", i)).unwrap();
+    }
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    let (live_bytes, gap_bytes) = disk.usage().unwrap();
+    assert!(gap_bytes > 0);
 
-    let result = disk.get(key.clone()).unwrap();
+    disk.defrag().unwrap();
 
-    assert_eq!(result, Some(value));
+    assert_eq!(disk.usage().unwrap(), (live_bytes, 0));
+    assert_eq!(disk.buf_stream.metadata().unwrap().len(), 16 + live_bytes);
 
-    teardown("test_put_and_get");
-}
+    for i in 5..10 {
+        assert_eq!(disk.get(format!("test_key_{}", i)).unwrap(), Some(Value::Text("test_value".repeat(1000))));
+    }
 
-#[test]
-fn test_put_and_delete() {
-    let mut disk: Disk = setup("test_put_and_delete");
+    drop(disk);
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+    let disk: Disk = setup("test_defrag_shrinks_file");
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    assert_eq!(disk.len().unwrap(), 5);
+    assert_eq!(disk.get("test_key_0".to_string()).unwrap(), None);
+    assert_eq!(disk.get("test_key_9".to_string()).unwrap(), Some(Value::Text("test_value".repeat(1000))));
 
-    let result = disk.get(key.clone()).unwrap();
+    teardown("test_defrag_shrinks_file");
+}
 
-    assert_eq!(result, Some(value));
+#[test]
+fn test_defrag_threshold() {
+    let mut disk: Disk = setup("test_defrag_threshold");
+    disk.set_defrag_threshold(Some(0.5));
 
-    disk.del(key.clone()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value".repeat(100000))).unwrap();
+    disk.put("test_key_2".to_string(), Value::Text("test_value".repeat(100000))).unwrap();
 
-    let result = disk.get(key.clone()).unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
 
-    assert_eq!(result, None);
+    let (_, gap_bytes) = disk.usage().unwrap();
+    assert_eq!(gap_bytes, 0);
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value".repeat(100000))));
 
-    teardown("test_put_and_delete");
+    teardown("test_defrag_threshold");
 }
 
 #[test]
-fn test_put_and_update() {
-    let mut disk: Disk = setup("test_put_and_update");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+fn test_replay_torn_write() {
+    let mut disk: Disk = setup("test_replay_torn_write");
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".to_string())).unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
 
-    let result = disk.get(key.clone()).unwrap();
+    std::mem::forget(disk);
 
-    assert_eq!(result, Some(value));
+    let path = Path::new("./target/tmp/disk_test_test_replay_torn_write.bin");
+    let len = fs::metadata(path).unwrap().len();
+    let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+    file.set_len(len - 5).unwrap();
 
-    let value = Value::Text("test_value_2".to_string());
+    let disk: Disk = setup("test_replay_torn_write");
 
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), None);
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".to_string())));
+    assert_eq!(disk.len().unwrap(), 1);
 
-    teardown("test_put_and_update");
+    teardown("test_replay_torn_write");
 }
 
 #[test]
-fn test_empty_key_and_value_1() {
-    let mut disk: Disk = setup("test_empty_key_and_value_1");
+fn test_replay_torn_log() {
+    let mut disk: Disk = setup("test_replay_torn_log");
 
-    let key = "".to_string();
-    let value = Value::Text("".to_string());
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    std::mem::forget(disk);
 
-    let result = disk.get(key.clone()).unwrap();
+    let path = Path::new("./target/tmp/disk_test_test_replay_torn_log.bin.wal");
+    let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(&[0, 0, 1, 0, 42, 42]).unwrap();
 
-    assert_eq!(result, Some(value));
+    let mut disk: Disk = setup("test_replay_torn_log");
 
-    teardown("test_empty_key_and_value_1");
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".to_string())).unwrap();
+    assert_eq!(disk.len().unwrap(), 2);
+
+    teardown("test_replay_torn_log");
 }
 
 #[test]
-fn test_empty_key_and_value_2() {
-    let mut disk: Disk = setup("test_empty_key_and_value_2");
-
-    let key = "".to_string();
-    let value = Value::Text("".to_string());
+fn test_checksum_mismatch() {
+    let mut disk: Disk = setup("test_checksum_mismatch");
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".to_string())).unwrap();
 
-    let result = disk.get(key.clone()).unwrap();
+    drop(disk);
 
-    assert_eq!(result, Some(value));
+    let path = Path::new("./target/tmp/disk_test_test_checksum_mismatch.bin");
+    let mut buf = fs::read(path).unwrap();
+    let position = buf.windows(12).position(|window| window == b"test_value_1").unwrap();
+    buf[position] = b'X';
+    fs::write(path, buf).unwrap();
 
-    let value = Value::Text("test_value".to_string());
+    let mut disk: Disk = setup("test_checksum_mismatch");
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    let error = disk.get("test_key_1".to_string()).unwrap_err();
+    assert!(Corruption::find(&error).is_some());
 
-    let result = disk.get(key.clone()).unwrap();
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".to_string())));
+    assert_eq!(disk.list().unwrap(), vec!["test_key_1".to_string(), "test_key_2".to_string()]);
+    disk.defrag().unwrap_err();
 
-    assert_eq!(result, Some(value));
+    disk.put("test_key_1".to_string(), Value::Text("test_value_3".to_string())).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_3".to_string())));
+    disk.defrag().unwrap();
 
-    teardown("test_empty_key_and_value_2");
+    teardown("test_checksum_mismatch");
 }
 
 #[test]
-fn test_very_large_value_1() {
-    let mut disk: Disk = setup("test_very_large_value_1");
+fn test_unknown_opcode() {
+    let mut disk: Disk = setup("test_unknown_opcode");
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(100000));
+    disk.put("test_key".to_string(), Value::Text("test_value".to_string())).unwrap();
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    drop(disk);
 
-    let result = disk.get(key.clone()).unwrap();
+    let path = Path::new("./target/tmp/disk_test_test_unknown_opcode.bin");
+    let mut buf = fs::read(path).unwrap();
+    buf[16] = 200;
+    fs::write(path, buf).unwrap();
 
-    assert_eq!(result, Some(value));
+    let error = Disk::new(path).err().unwrap();
+    assert_eq!(Corruption::find(&error).unwrap().offset, 16);
 
-    teardown("test_very_large_value_1");
+    teardown("test_unknown_opcode");
 }
 
 #[test]
-fn test_very_large_value_2() {
-    let mut disk: Disk = setup("test_very_large_value_2");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
+fn test_new_file_header() {
+    let disk: Disk = setup("test_new_file_header");
 
-    let result = disk.get(key.clone()).unwrap();
+    drop(disk);
 
-    assert_eq!(result, Some(value));
+    let buf = fs::read("./target/tmp/disk_test_test_new_file_header.bin").unwrap();
+    let header = FileHeader::from_bytes(buf[0..16].try_into().unwrap()).unwrap();
+    assert_eq!(header, FileHeader::current());
 
-    let value = Value::Text("test_value_2".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    teardown("test_very_large_value_2");
+    teardown("test_new_file_header");
 }
 
 #[test]
-fn test_very_large_key_1() {
-    let mut disk: Disk = setup("test_very_large_key_1");
-
-    let key = "test_key".repeat(100000);
-    let value = Value::Text("test_value".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    teardown("test_very_large_key_1");
+fn test_migrate_legacy_file() {
+    let path = Path::new("./target/tmp/disk_test_test_migrate_legacy_file.bin");
+
+    // Entry frames of version 0: opcode 0, u128 key and value lengths, the
+    // postcard key and value and no checksum.
+    let mut buf: Vec<u8> = b"varia---------db".to_vec();
+    for (key, value) in [("test_key_1", Value::Text("test_value_1".to_string())), ("test_key_2", Value::Number(42))] {
+        let key_buf = postcard::to_allocvec(&key).unwrap();
+        let value_buf = postcard::to_allocvec(&value).unwrap();
+        buf.push(0);
+        buf.extend_from_slice(&(key_buf.len() as u128).to_be_bytes());
+        buf.extend_from_slice(&(value_buf.len() as u128).to_be_bytes());
+        buf.extend_from_slice(&key_buf);
+        buf.extend_from_slice(&value_buf);
+    }
+    buf.extend_from_slice(&[3, 0, 0]);
+    fs::write(path, buf).unwrap();
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
+
+    drop(disk);
+
+    let buf = fs::read(path).unwrap();
+    let header = FileHeader::from_bytes(buf[0..16].try_into().unwrap()).unwrap();
+    assert_eq!(header, FileHeader::current());
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.len().unwrap(), 2);
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+
+    drop(disk);
+    teardown("test_migrate_legacy_file");
 }
 
 #[test]
-fn test_very_large_key_2() {
-    let mut disk: Disk = setup("test_very_large_key_2");
-
-    let key = "test_key".repeat(100000);
-    let value = Value::Text("test_value".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    let value = Value::Text("test_value_2".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    teardown("test_very_large_key_2");
+fn test_migrate_v1_file() {
+    let path = Path::new("./target/tmp/disk_test_test_migrate_v1_file.bin");
+
+    // Entry frames of version 1: opcode 18, u128 key and value lengths, the
+    // postcard key and value and a CRC32 of all of it. Gap frames of at least
+    // 17 bytes use opcode 17 and a u128 length.
+    let mut buf: Vec<u8> = b"varia\x01\x00\x01\x00\x00\x00\x00\x00\x00db".to_vec();
+    for (key, value) in [("test_key_1", Value::Text("test_value_1".to_string())), ("test_key_2", Value::Number(42))] {
+        let key_buf = postcard::to_allocvec(&key).unwrap();
+        let value_buf = postcard::to_allocvec(&value).unwrap();
+        let start = buf.len();
+        buf.push(18);
+        buf.extend_from_slice(&(key_buf.len() as u128).to_be_bytes());
+        buf.extend_from_slice(&(value_buf.len() as u128).to_be_bytes());
+        buf.extend_from_slice(&key_buf);
+        buf.extend_from_slice(&value_buf);
+        let checksum = crc32fast::hash(&buf[start..]);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+    }
+    buf.push(17);
+    buf.extend_from_slice(&20u128.to_be_bytes());
+    buf.extend_from_slice(&[0; 3]);
+    let legacy_len = buf.len() as u64;
+    fs::write(path, buf).unwrap();
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
+
+    drop(disk);
+
+    let buf = fs::read(path).unwrap();
+    let header = FileHeader::from_bytes(buf[0..16].try_into().unwrap()).unwrap();
+    assert_eq!(header, FileHeader::current());
+    assert!((buf.len() as u64) < legacy_len - 20);
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.list().unwrap(), vec!["test_key_1".to_string(), "test_key_2".to_string()]);
+
+    drop(disk);
+    teardown("test_migrate_v1_file");
 }
 
 #[test]
-fn test_gapping() {
-    let mut disk: Disk = setup("test_gapping");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+fn test_compact_encoding() {
+    let mut disk: Disk = setup("test_compact_encoding");
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    disk.put("testkey001".to_string(), Value::Number(42)).unwrap();
+    disk.put("testkey002".to_string(), Value::Boolean(true)).unwrap();
 
-    let result = disk.get(key.clone()).unwrap();
+    // Opcode, two one byte lengths, the key, the value and the checksum.
+    let (live_bytes, gap_bytes) = disk.usage().unwrap();
+    assert_eq!(live_bytes, 2 * (1 + 2 + 10 + 2 + 4));
+    assert_eq!(gap_bytes, 0);
 
-    assert_eq!(result, Some(value));
+    disk.del("testkey001".to_string()).unwrap();
+    disk.put("key".to_string(), Value::Number(1)).unwrap();
 
-    disk.del(key.clone()).unwrap();
+    // The new entry fits into the short gap left by the deleted one.
+    let (live_bytes, gap_bytes) = disk.usage().unwrap();
+    assert_eq!(live_bytes + gap_bytes, 2 * (1 + 2 + 10 + 2 + 4));
 
-    let result = disk.get(key.clone()).unwrap();
+    drop(disk);
 
-    assert_eq!(result, None);
+    let disk: Disk = setup("test_compact_encoding");
+    assert_eq!(disk.list().unwrap(), vec!["key".to_string(), "testkey002".to_string()]);
 
-    let key = "test_key_2".to_string();
-        let value = Value::Text("test_value_2".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    teardown("test_gapping");
+    teardown("test_compact_encoding");
 }
 
 #[test]
-fn test_gapping_very_large_values() {
-    let mut disk: Disk = setup("test_gapping_very_large_values");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
+fn test_unsupported_version() {
+    let disk: Disk = setup("test_unsupported_version");
 
-    let result = disk.get(key.clone()).unwrap();
+    drop(disk);
 
-    assert_eq!(result, Some(value));
+    let path = Path::new("./target/tmp/disk_test_test_unsupported_version.bin");
+    let mut buf = fs::read(path).unwrap();
+    buf[5] = FORMAT_VERSION + 1;
+    fs::write(path, &buf).unwrap();
 
-    disk.del(key.clone()).unwrap();
+    let error = Disk::new(path).err().unwrap();
+    assert!(error.to_string().contains("Unsupported data file version"));
 
-    let result = disk.get(key.clone()).unwrap();
+    // The file must be left untouched.
+    assert_eq!(fs::read(path).unwrap(), buf);
 
-    assert_eq!(result, None);
-
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, Some(value));
-
-    teardown("test_gapping_very_large_values");
+    teardown("test_unsupported_version");
 }
 
 #[test]
-fn test_gapping_very_small_values() {
-    let mut disk: Disk = setup("test_gapping_very_small_values");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(10));
+fn test_mmap() {
+    let mut disk: Disk = setup("test_mmap");
+    disk.set_mmap(true);
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
 
-    let result = disk.get(key.clone()).unwrap();
+    // Grows the file behind the current map.
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100000))).unwrap();
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(100000))));
 
-    assert_eq!(result, Some(value));
+    // Overwrites mapped bytes in place.
+    disk.del("test_key_1".to_string()).unwrap();
+    disk.put("test_key_3".to_string(), Value::Number(42)).unwrap();
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Number(42)));
 
-    disk.del(key.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
-
-    assert_eq!(result, None);
-
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".repeat(10));
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
+    // Replaces the file.
+    disk.defrag().unwrap();
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(100000))));
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Number(42)));
 
-    assert_eq!(result, Some(value));
+    // Shrinks the file.
+    disk.clear().unwrap();
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), None);
+    disk.put("test_key_4".to_string(), Value::Boolean(true)).unwrap();
+    assert_eq!(disk.get("test_key_4".to_string()).unwrap(), Some(Value::Boolean(true)));
 
-    teardown("test_gapping_very_small_values");
+    teardown("test_mmap");
 }
 
 #[test]
-fn test_list_small_keys() {
-    let mut disk: Disk = setup("test_list_small_keys");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.list().unwrap();
-
-    assert_eq!(result, vec![key.clone()]);
-
-    teardown("test_list_small_keys");
+fn test_mmap_growth() {
+    let mut disk: Disk = setup("test_mmap_growth");
+    disk.set_mmap(true);
+
+    // Grows the file into the room the map leaves and beyond it, more than
+    // once.
+    for i in 0..3000 {
+        let key = format!("test_key_{}", i);
+        disk.put(key.clone(), Value::Text("x".repeat(1000))).unwrap();
+        assert_eq!(disk.get(key).unwrap(), Some(Value::Text("x".repeat(1000))));
+    }
+    for i in 0..3000 {
+        assert_eq!(disk.get(format!("test_key_{}", i)).unwrap(), Some(Value::Text("x".repeat(1000))));
+    }
+
+    teardown("test_mmap_growth");
 }
 
 #[test]
-fn test_list_large_keys() {
-    let mut disk: Disk = setup("test_list_large_keys");
+fn test_update_in_place() {
+    let mut disk: Disk = setup("test_update_in_place");
 
-    let key = "test_key".repeat(100000);
-    let value = Value::Text("test_value".to_string());
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    let file_len = disk.buf_stream.metadata().unwrap().len();
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_3".to_string())).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("short".to_string())).unwrap();
 
-    let result = disk.list().unwrap();
+    assert_eq!(disk.buf_stream.metadata().unwrap().len(), file_len);
+    assert_eq!(disk.list().unwrap(), vec!["test_key_1".to_string(), "test_key_2".to_string()]);
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("short".to_string())));
+    drop(disk);
 
-    assert_eq!(result, vec![key.clone()]);
+    let disk: Disk = setup("test_update_in_place");
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("short".to_string())));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
 
-    teardown("test_list_large_keys");
+    teardown("test_update_in_place");
 }
 
 #[test]
-fn test_list_gapping() {
-    let mut disk: Disk = setup("test_list_gapping");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+fn test_coalesce_gaps() {
+    let mut disk: Disk = setup("test_coalesce_gaps");
 
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.del(key.clone()).unwrap();
+    for i in 0..4 {
+        disk.put(format!("test_key_{}", i), Value::Text("test_value".repeat(10))).unwrap();
+    }
+    let file_len = disk.buf_stream.metadata().unwrap().len();
 
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".to_string());
+    disk.del("test_key_0".to_string()).unwrap();
+    disk.del("test_key_2".to_string()).unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
 
-    disk.put(key.clone(), value.clone()).unwrap();
+    // Fits only into the three merged gaps.
+    disk.put("test_key_4".to_string(), Value::Text("test_value".repeat(25))).unwrap();
 
-    let result = disk.list().unwrap();
+    assert_eq!(disk.buf_stream.metadata().unwrap().len(), file_len);
+    assert_eq!(disk.list().unwrap(), vec!["test_key_4".to_string(), "test_key_3".to_string()]);
+    drop(disk);
 
-    assert_eq!(result, vec![key.clone()]);
+    let disk: Disk = setup("test_coalesce_gaps");
+    assert_eq!(disk.get("test_key_4".to_string()).unwrap(), Some(Value::Text("test_value".repeat(25))));
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Text("test_value".repeat(10))));
 
-    teardown("test_list_gapping");
+    teardown("test_coalesce_gaps");
 }
 
 #[test]
-fn test_list_gapping_very_large_values() {
-    let mut disk: Disk = setup("test_list_gapping_very_large_values");
+fn test_truncate_trailing_gap() {
+    let mut disk: Disk = setup("test_truncate_trailing_gap");
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(100000));
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    let file_len = disk.buf_stream.metadata().unwrap().len();
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100))).unwrap();
+    disk.put("test_key_3".to_string(), Value::Text("test_value_3".repeat(100))).unwrap();
 
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.del(key.clone()).unwrap();
+    disk.del("test_key_2".to_string()).unwrap();
+    disk.del("test_key_3".to_string()).unwrap();
 
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".repeat(100000));
+    assert_eq!(disk.buf_stream.metadata().unwrap().len(), file_len);
+    assert_eq!(disk.usage().unwrap().1, 0);
 
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    let result = disk.list().unwrap();
-
-    assert_eq!(result, vec![key.clone()]);
-
-    teardown("test_list_gapping_very_large_values");
+    teardown("test_truncate_trailing_gap");
 }
 
 #[test]
-fn test_clear() {
-    let mut disk: Disk = setup("test_clear");
+fn test_smallest_fitting_gap() {
+    let mut disk: Disk = setup("test_smallest_fitting_gap");
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+    disk.put("test_key_1".to_string(), Value::Text("test_value".repeat(50))).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(1)).unwrap();
+    disk.put("test_key_3".to_string(), Value::Text("test_value".repeat(5))).unwrap();
+    disk.put("test_key_4".to_string(), Value::Number(2)).unwrap();
+    let file_len = disk.buf_stream.metadata().unwrap().len();
 
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.clear().unwrap();
-
-    let result = disk.get(key.clone()).unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
+    disk.del("test_key_3".to_string()).unwrap();
+    disk.put("test_key_5".to_string(), Value::Text("test_value".repeat(4))).unwrap();
 
-    assert_eq!(result, None);
+    assert_eq!(disk.buf_stream.metadata().unwrap().len(), file_len);
+    assert_eq!(disk.list().unwrap(), vec!["test_key_2".to_string(), "test_key_5".to_string(), "test_key_4".to_string()]);
 
-    teardown("test_clear");
+    teardown("test_smallest_fitting_gap");
 }
 
 #[test]
-fn test_clear_gapping() {
-    let mut disk: Disk = setup("test_clear_gapping");
+fn test_compression() {
+    let mut disk: Disk = setup("test_compression");
 
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(1000))).unwrap();
+    let (live_bytes, _) = disk.usage().unwrap();
+    assert_eq!(disk.compression(), (0, 0));
 
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.del(key.clone()).unwrap();
+    disk.set_compression_threshold(Some(1024));
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(1000))).unwrap();
+    disk.put("test_key_3".to_string(), Value::Text("test_value_3".to_string())).unwrap();
 
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".to_string());
+    let (frames, saved_bytes) = disk.compression();
+    assert_eq!(frames, 1);
+    assert!(saved_bytes > 0);
+    assert!(disk.usage().unwrap().0 < 2 * live_bytes);
 
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.clear().unwrap();
+    disk.del("test_key_1".to_string()).unwrap();
+    disk.defrag().unwrap();
+    assert_eq!(disk.compression(), (frames, saved_bytes));
+    drop(disk);
 
-    let result = disk.get(key.clone()).unwrap();
+    let mut disk: Disk = setup("test_compression");
+    assert_eq!(disk.compression(), (frames, saved_bytes));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(1000))));
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Text("test_value_3".to_string())));
 
-    assert_eq!(result, None);
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    assert_eq!(disk.compression(), (0, 0));
 
-    teardown("test_clear_gapping");
+    teardown("test_compression");
 }
 
-#[test]
-fn test_len() {
-    let mut disk: Disk = setup("test_len");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
-
-    assert_eq!(disk.len().unwrap(), 0);
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    assert_eq!(disk.len().unwrap(), 1);
-
-    disk.del(key.clone()).unwrap();
-
-    assert_eq!(disk.len().unwrap(), 0);
+fn cipher(digit: char) -> Cipher {
+    Cipher::from_hex(&digit.to_string().repeat(64)).unwrap()
+}
 
-    teardown("test_len");
+fn contains(buf: &[u8], needle: &[u8]) -> bool {
+    buf.windows(needle.len()).any(|window| window == needle)
 }
 
 #[test]
-fn test_is_empty() {
-    let mut disk: Disk = setup("test_is_empty");
-
-    assert_eq!(disk.is_empty().unwrap(), true);
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".to_string());
-
-    disk.put(key.clone(), value.clone()).unwrap();
-
-    assert_eq!(disk.is_empty().unwrap(), false);
-
-    disk.del(key.clone()).unwrap();
-
-    assert_eq!(disk.is_empty().unwrap(), true);
-
-    teardown("test_is_empty");
+fn test_encryption() {
+    let path = Path::new("./target/tmp/disk_test_test_encryption.bin");
+    let mut disk = Disk::new_encrypted(path, cipher('a')).unwrap();
+
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_2".to_string(), Value::Text("test_value_2".repeat(100))).unwrap();
+    disk.del("test_key_2".to_string()).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+    drop(disk);
+
+    let buf = fs::read(path).unwrap();
+    assert!(FileHeader::from_bytes(buf[0..16].try_into().unwrap()).unwrap().is_encrypted());
+    assert!(!contains(&buf, b"test_key_1"));
+    assert!(!contains(&buf, b"test_value_1"));
+
+    let error = Disk::new_encrypted(path, cipher('b')).err().unwrap();
+    assert!(error.to_string().contains("Wrong encryption key"));
+    let error = Disk::new(path).err().unwrap();
+    assert!(error.to_string().contains("no encryption key was given"));
+    assert_eq!(fs::read(path).unwrap(), buf);
+
+    let disk = Disk::new_encrypted(path, cipher('a')).unwrap();
+    assert_eq!(disk.list().unwrap(), vec!["test_key_1".to_string()]);
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".to_string())));
+    drop(disk);
+
+    teardown("test_encryption");
 }
 
 #[test]
-fn test_defrag() {
-    let mut disk: Disk = setup("test_defrag");
-
-    let key = "test_key".to_string();
-    let value = Value::Text("test_value".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
-    disk.del(key.clone()).unwrap();
-
-    let key = "test_key_2".to_string();
-    let value = Value::Text("test_value_2".repeat(100000));
-
-    disk.put(key.clone(), value.clone()).unwrap();
+fn test_encrypt_existing_file() {
+    let mut disk: Disk = setup("test_encrypt_existing_file");
+    disk.set_compression_threshold(Some(64));
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    drop(disk);
+
+    let path = Path::new("./target/tmp/disk_test_test_encrypt_existing_file.bin");
+    let disk = Disk::new_encrypted(path, cipher('a')).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".repeat(100))));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
+    drop(disk);
+
+    assert!(!contains(&fs::read(path).unwrap(), b"test_key_1"));
+
+    teardown("test_encrypt_existing_file");
+}
 
-    disk.defrag().unwrap();
+#[test]
+fn test_rekey() {
+    let path = Path::new("./target/tmp/disk_test_test_rekey.bin");
+    let mut disk = Disk::new_encrypted(path, cipher('a')).unwrap();
+    disk.set_compression_threshold(Some(64));
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))).unwrap();
+
+    disk.rekey(Some(cipher('b'))).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    assert_eq!(disk.compression().0, 1);
+    drop(disk);
+
+    assert!(Disk::new_encrypted(path, cipher('a')).is_err());
+    let mut disk = Disk::new_encrypted(path, cipher('b')).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_1".repeat(100))));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
+
+    disk.rekey(None).unwrap();
+    drop(disk);
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(42)));
+    assert!(contains(&fs::read(path).unwrap(), b"test_key_2"));
+    drop(disk);
+
+    teardown("test_rekey");
+}
 
-    let result = disk.list().unwrap();
+#[test]
+fn test_group_commit() {
+    let mut disk: Disk = setup("test_group_commit");
+    disk.set_group_commit(true).unwrap();
+
+    disk.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    disk.put("test_key_1".to_string(), Value::Text("test_value_2".repeat(100))).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    disk.del("test_key_2".to_string()).unwrap();
+
+    // Writes of the group are readable before they are synced.
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(100))));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), None);
+
+    disk.sync().unwrap();
+    disk.put("test_key_3".to_string(), Value::Boolean(true)).unwrap();
+    disk.set_group_commit(false).unwrap();
+    drop(disk);
+
+    let disk: Disk = setup("test_group_commit");
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Text("test_value_2".repeat(100))));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), None);
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Boolean(true)));
+    drop(disk);
+
+    teardown("test_group_commit");
+}
 
-    assert_eq!(result, vec![key.clone()]);
+#[test]
+fn test_concurrent_reads() {
+    for mmap in [false, true] {
+        let mut disk: Disk = setup("test_concurrent_reads");
+        disk.set_mmap(mmap);
+        for i in 0..100 {
+            disk.put(format!("test_key_{}", i), Value::Text(format!("test_value_{}", i).repeat(i))).unwrap();
+        }
+
+        let disk = &disk;
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        assert_eq!(disk.get(format!("test_key_{}", i)).unwrap(), Some(Value::Text(format!("test_value_{}", i).repeat(i))));
+                    }
+                    assert_eq!(disk.list().unwrap().len(), 100);
+                });
+            }
+        });
+    }
+
+    teardown("test_concurrent_reads");
+}
 
-    teardown("test_defrag");
-}
\ No newline at end of file
+#[cfg(target_os = "linux")]
+#[test]
+fn test_failed_commit() {
+    let path = Path::new("./target/tmp/disk_test_test_failed_commit.bin");
+    let wal_path = Path::new("./target/tmp/disk_test_test_failed_commit.bin.wal");
+
+    let mut disk: Disk = setup("test_failed_commit");
+    disk.put("test_key_1".to_string(), Value::Number(1)).unwrap();
+    disk.put("test_key_2".to_string(), Value::Number(2)).unwrap();
+    drop(disk);
+
+    // Syncing /dev/null fails, so no batch can be logged.
+    fs::remove_file(wal_path).unwrap();
+    std::os::unix::fs::symlink("/dev/null", wal_path).unwrap();
+
+    let mut disk: Disk = setup("test_failed_commit");
+    let usage = disk.usage().unwrap();
+
+    // Moving the entry frame to the end would leave a gap where it was.
+    assert!(disk.put("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))).is_err());
+    assert!(disk.del("test_key_2".to_string()).is_err());
+    assert_eq!(disk.usage().unwrap(), usage);
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Number(1)));
+
+    // A frame of the same length must not be placed over the live one.
+    disk.set_sync_policy(SyncPolicy::Never).unwrap();
+    disk.put("test_key_3".to_string(), Value::Number(3)).unwrap();
+    assert_eq!(disk.get("test_key_1".to_string()).unwrap(), Some(Value::Number(1)));
+    assert_eq!(disk.get("test_key_2".to_string()).unwrap(), Some(Value::Number(2)));
+    assert_eq!(disk.get("test_key_3".to_string()).unwrap(), Some(Value::Number(3)));
+    drop(disk);
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(wal_path).unwrap();
+}
@@ -1,7 +1,7 @@
-use std::path::Path;
+use std::{io::Error, path::Path};
 
 use moka::future::Cache;
-use varia_db::store::{Disk, Engine, Value}; 
+use varia_db::store::{Disk, Memory, Engine, Value, Corruption, StorageBackend};
 use std::fs;
 
 fn setup(test_name: &str) -> Engine {
@@ -16,6 +16,9 @@ fn teardown(test_name: &str) {
     fs::remove_file(Path::new(
         format!("./target/tmp/engine_test_{}.bin", test_name).as_str(),
     )).unwrap();
+    fs::remove_file(Path::new(
+        format!("./target/tmp/engine_test_{}.bin.wal", test_name).as_str(),
+    )).unwrap();
 }
 
 #[tokio::test]
@@ -66,4 +69,153 @@ async fn test_empty_key() {
     let engine = setup("test_empty_key");
     engine.put("".to_string(), Value::Text("bar".to_string())).await.expect_err("Empty key");
     teardown("test_empty_key");
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_defrag() {
+    let engine = setup("test_defrag");
+    engine.put("old".to_string(), Value::Text("bar".repeat(1000))).await.unwrap();
+    engine.put("key".to_string(), Value::Text("baz".to_string())).await.unwrap();
+    engine.del("old".to_string()).await.unwrap();
+    engine.defrag().await.unwrap();
+    let opt = engine.get("key".to_string()).await.unwrap();
+    assert_eq!(opt, Some(Value::Text("baz".to_string())));
+    teardown("test_defrag");
+}
+
+#[tokio::test]
+async fn test_corruption() {
+    let engine = setup("test_corruption");
+    engine.put("key".to_string(), Value::Text("bar".to_string())).await.unwrap();
+    drop(engine);
+
+    let path = Path::new("./target/tmp/engine_test_test_corruption.bin");
+    let mut buf = fs::read(path).unwrap();
+    let position = buf.windows(3).position(|window| window == b"bar").unwrap();
+    buf[position] = b'X';
+    fs::write(path, buf).unwrap();
+
+    let engine = setup("test_corruption");
+    let error = engine.get("key".to_string()).await.unwrap_err();
+    assert!(Corruption::find(&error).is_some());
+    teardown("test_corruption");
+}
+
+#[tokio::test]
+async fn test_memory_backend() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+    let opt = engine.put("key".to_string(), Value::Text("bar".to_string())).await.unwrap();
+    assert_eq!(opt, None);
+    let opt = engine.del("key".to_string()).await.unwrap();
+    assert_eq!(opt, Some(Value::Text("bar".to_string())));
+    engine.put("other".to_string(), Value::Text("baz".to_string())).await.unwrap();
+    let list = engine.list().await.unwrap();
+    assert_eq!(list, vec!["other".to_string()]);
+    engine.defrag().await.unwrap();
+}
+
+/// Panics on every write to the key `panic`.
+struct Panicking(Memory);
+
+impl StorageBackend for Panicking {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        if key == "panic" {
+            panic!("Write to {}", key);
+        }
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        self.0.get(key)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        self.0.del(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.0.list()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.0.clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        self.0.len()
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_writes() {
+    let engine = setup("test_concurrent_writes");
+    let mut handles = Vec::new();
+    for i in 0..200 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            engine.put(format!("key{}", i), Value::Number(i)).await
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+    drop(engine);
+
+    // Every acknowledged write is on disk once the engine is gone.
+    let disk = Disk::new(Path::new("./target/tmp/engine_test_test_concurrent_writes.bin")).unwrap();
+    assert_eq!(disk.len().unwrap(), 200);
+    for i in 0..200 {
+        assert_eq!(disk.get(format!("key{}", i)).unwrap(), Some(Value::Number(i)));
+    }
+    drop(disk);
+    teardown("test_concurrent_writes");
+}
+
+#[tokio::test]
+async fn test_panic_recovery() {
+    let engine = Engine::new(Panicking(Memory::new()), Cache::new(0));
+    engine.put("key".to_string(), Value::Text("bar".to_string())).await.unwrap();
+
+    let error = engine.put("panic".to_string(), Value::Text("bar".to_string())).await.unwrap_err();
+    assert!(error.to_string().contains("panicked"));
+
+    let error = engine.put("other".to_string(), Value::Text("baz".to_string())).await.unwrap_err();
+    assert!(error.to_string().contains("panicked"));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Text("bar".to_string())));
+    assert_eq!(engine.get("other".to_string()).await.unwrap(), None);
+    assert_eq!(engine.list().await.unwrap(), vec!["key".to_string()]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_reads() {
+    let engine = setup("test_concurrent_reads");
+    for i in 0..50 {
+        engine.put(format!("key{}", i), Value::Number(i)).await.unwrap();
+    }
+    drop(engine);
+
+    // Without a cache every read goes to the disk.
+    let engine = Engine::new(
+        Disk::new(Path::new("./target/tmp/engine_test_test_concurrent_reads.bin")).unwrap(),
+        Cache::new(0),
+    );
+
+    let mut handles = Vec::new();
+    for i in 0..50 {
+        let writer = engine.clone();
+        handles.push(tokio::spawn(async move {
+            writer.put(format!("other{}", i), Value::Number(i)).await.unwrap();
+        }));
+        let reader = engine.clone();
+        handles.push(tokio::spawn(async move {
+            assert_eq!(reader.get(format!("key{}", i)).await.unwrap(), Some(Value::Number(i)));
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(engine.list().await.unwrap().len(), 100);
+
+    drop(engine);
+    teardown("test_concurrent_reads");
+}
@@ -0,0 +1,154 @@
+use std::{fs, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use moka::future::Cache;
+use varia_db::store::{Disk, Engine, FileHeader, Lsm, Memory, StorageBackend, Value};
+
+fn setup(test_name: &str) -> Disk {
+    Disk::new(Path::new(
+        format!("./target/tmp/expiry_test_{}.bin", test_name).as_str(),
+    )).unwrap()
+}
+
+fn teardown(test_name: &str) {
+    fs::remove_file(Path::new(
+        format!("./target/tmp/expiry_test_{}.bin", test_name).as_str(),
+    )).unwrap();
+    fs::remove_file(Path::new(
+        format!("./target/tmp/expiry_test_{}.bin.wal", test_name).as_str(),
+    )).unwrap();
+}
+
+/// Milliseconds since the unix epoch `offset` from now.
+fn at(offset: i64) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    (now + offset) as u64
+}
+
+#[test]
+fn test_disk_expiry() {
+    let mut disk: Disk = setup("test_disk_expiry");
+
+    disk.put_expiring("expired".to_string(), Value::Number(1), Some(at(-1000))).unwrap();
+    disk.put_expiring("expiring".to_string(), Value::Number(2), Some(at(60_000))).unwrap();
+    disk.put("permanent".to_string(), Value::Number(3)).unwrap();
+
+    assert_eq!(disk.get("expired".to_string()).unwrap(), None);
+    assert_eq!(disk.get("expiring".to_string()).unwrap(), Some(Value::Number(2)));
+    assert_eq!(disk.list().unwrap(), vec!["expiring".to_string(), "permanent".to_string()]);
+    assert_eq!(disk.len().unwrap(), 2);
+
+    drop(disk);
+
+    let mut disk: Disk = setup("test_disk_expiry");
+    assert_eq!(disk.get("expired".to_string()).unwrap(), None);
+    assert!(disk.expires_at("expiring".to_string()).unwrap().is_some());
+    assert_eq!(disk.expires_at("permanent".to_string()).unwrap(), None);
+
+    // Writing the key again without an expiry makes it permanent.
+    disk.put("expiring".to_string(), Value::Number(4)).unwrap();
+    assert_eq!(disk.expires_at("expiring".to_string()).unwrap(), None);
+
+    drop(disk);
+    teardown("test_disk_expiry");
+}
+
+#[test]
+fn test_disk_reap() {
+    let mut disk: Disk = setup("test_disk_reap");
+
+    disk.put_expiring("expired_1".to_string(), Value::Number(1), Some(at(-1000))).unwrap();
+    disk.put_expiring("expired_2".to_string(), Value::Number(2), Some(at(-1000))).unwrap();
+    disk.put("permanent".to_string(), Value::Number(3)).unwrap();
+
+    assert_eq!(disk.reap().unwrap(), 2);
+    assert_eq!(disk.reap().unwrap(), 0);
+    assert_eq!(disk.keys().collect::<Vec<&String>>(), vec![&"permanent".to_string()]);
+
+    drop(disk);
+    teardown("test_disk_reap");
+}
+
+#[test]
+fn test_memory_expiry() {
+    let mut memory = Memory::new();
+
+    memory.put_expiring("expired".to_string(), Value::Number(1), Some(at(-1000))).unwrap();
+    memory.put_expiring("expiring".to_string(), Value::Number(2), Some(at(60_000))).unwrap();
+
+    assert_eq!(memory.get("expired".to_string()).unwrap(), None);
+    assert_eq!(memory.list().unwrap(), vec!["expiring".to_string()]);
+    assert_eq!(memory.reap().unwrap(), 1);
+    assert_eq!(memory.len().unwrap(), 1);
+}
+
+#[test]
+fn test_migrate_v2_file() {
+    let path = Path::new("./target/tmp/expiry_test_test_migrate_v2_file.bin");
+    let mut disk: Disk = setup("test_migrate_v2_file");
+    disk.put("test_key".to_string(), Value::Number(42)).unwrap();
+    drop(disk);
+
+    // Entry frames of version 2 are those of version 3 without an expiry,
+    // only the header differs.
+    let mut buf = fs::read(path).unwrap();
+    buf[5] = 2;
+    fs::write(path, &buf).unwrap();
+
+    let disk: Disk = setup("test_migrate_v2_file");
+    assert_eq!(disk.get("test_key".to_string()).unwrap(), Some(Value::Number(42)));
+    drop(disk);
+
+    let migrated = fs::read(path).unwrap();
+    let header = FileHeader::from_bytes(migrated[0..16].try_into().unwrap()).unwrap();
+    assert_eq!(header, FileHeader::current());
+    assert_eq!(migrated[16..], buf[16..]);
+
+    teardown("test_migrate_v2_file");
+}
+
+#[tokio::test]
+async fn test_engine_ttl() {
+    let engine = Engine::new(Memory::new(), Cache::new(1000));
+
+    engine.put_with_ttl("expiring".to_string(), Value::Number(1), Some(Duration::from_secs(1))).await.unwrap();
+    engine.put("permanent".to_string(), Value::Number(2)).await.unwrap();
+    assert_eq!(engine.get("expiring".to_string()).await.unwrap(), Some(Value::Number(1)));
+
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    // The cached entry must not outlive the key.
+    assert_eq!(engine.get("expiring".to_string()).await.unwrap(), None);
+    assert_eq!(engine.list().await.unwrap(), vec!["permanent".to_string()]);
+    assert_eq!(engine.reap().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_reaper() {
+    let mut engine = Engine::new(Memory::new(), Cache::new(1000));
+    engine.set_reap_interval(Duration::from_millis(50));
+
+    engine.put_with_ttl("expiring".to_string(), Value::Number(1), Some(Duration::from_millis(10))).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    assert_eq!(engine.reap().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_lsm_write_back_ttl() {
+    let path = Path::new("./target/tmp/expiry_test_test_lsm_write_back_ttl");
+    {
+        let mut engine = Engine::new(Lsm::new(path).unwrap(), Cache::new(1000));
+        engine.set_write_back(Duration::from_secs(3600), 1024);
+
+        // Refused before it is cached, so it never holds up a flush.
+        let error = engine.put_with_ttl("expiring".to_string(), Value::Number(1), Some(Duration::from_secs(60))).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(engine.get("expiring".to_string()).await.unwrap(), None);
+
+        engine.put("permanent".to_string(), Value::Number(2)).await.unwrap();
+        assert_eq!(engine.flush().await.unwrap(), 1);
+        assert_eq!(engine.list().await.unwrap(), vec!["permanent".to_string()]);
+    }
+
+    fs::remove_dir_all(path).unwrap();
+}
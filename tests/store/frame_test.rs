@@ -0,0 +1,218 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use varia_db::store::{Cipher, Corruption, FileHeader, Value, frame::{self, Frame, FrameKind, FrameReader}};
+
+/// Hands out at most one byte per read, like a slow pipe or a network file
+/// system would.
+struct Trickle(Cursor<Vec<u8>>);
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+impl Seek for Trickle {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+fn file(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf: Vec<u8> = FileHeader::current().to_bytes().to_vec();
+    for frame in frames {
+        buf.extend_from_slice(frame);
+    }
+    buf
+}
+
+#[test]
+fn test_short_reads() {
+    let buf = file(&[
+        frame::entry_frame("test_key_1", &Value::Text("test_value_1".repeat(100))).unwrap(),
+        frame::gap_frame(300),
+        frame::gap_frame(5),
+        frame::entry_frame("test_key_2", &Value::Number(42)).unwrap(),
+    ]);
+    let file_len = buf.len() as u64;
+
+    let mut frames = FrameReader::new(Trickle(Cursor::new(buf)), file_len, FileHeader::current().version).unwrap();
+
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    let mut gaps: Vec<(FrameKind, u64)> = Vec::new();
+    while let Some(frame) = frames.next_frame().unwrap() {
+        match frame {
+            Frame::Entry(entry) => {
+                assert!(entry.intact);
+                entries.push((entry.key().unwrap(), entry.value().unwrap()));
+            },
+            Frame::Gap(header) => gaps.push((header.kind, header.len)),
+        }
+    }
+
+    assert_eq!(entries, vec![
+        ("test_key_1".to_string(), Value::Text("test_value_1".repeat(100))),
+        ("test_key_2".to_string(), Value::Number(42)),
+    ]);
+    assert_eq!(gaps, vec![(FrameKind::BigGap, 300), (FrameKind::ShortGap, 5)]);
+    assert_eq!(frames.offset(), file_len);
+}
+
+#[test]
+fn test_next_header() {
+    let first = frame::entry_frame("test_key_1", &Value::Text("test_value_1".to_string())).unwrap();
+    let buf = file(&[
+        first.clone(),
+        frame::gap_frame(1000),
+        frame::entry_frame("test_key_2", &Value::Boolean(true)).unwrap(),
+    ]);
+    let file_len = buf.len() as u64;
+
+    let mut frames = FrameReader::new(Cursor::new(buf), file_len, FileHeader::current().version).unwrap();
+
+    let header = frames.next_header().unwrap().unwrap();
+    assert_eq!(header.kind, FrameKind::CompactEntry);
+    assert_eq!(header.offset, FileHeader::LEN);
+    assert_eq!(header.len, first.len() as u64);
+
+    let header = frames.next_header().unwrap().unwrap();
+    assert_eq!(header.kind, FrameKind::BigGap);
+    assert_eq!(header.len, 1000);
+
+    match frames.next_frame().unwrap().unwrap() {
+        Frame::Entry(entry) => assert_eq!(entry.key().unwrap(), "test_key_2"),
+        Frame::Gap(_) => panic!("Expected entry frame"),
+    }
+    assert!(frames.next_header().unwrap().is_none());
+}
+
+#[test]
+fn test_truncated_frame() {
+    let mut buf = file(&[frame::entry_frame("test_key", &Value::Text("test_value".to_string())).unwrap()]);
+    buf.truncate(buf.len() - 5);
+    let file_len = buf.len() as u64;
+
+    let mut frames = FrameReader::new(Trickle(Cursor::new(buf)), file_len, FileHeader::current().version).unwrap();
+
+    let error = frames.next_frame().err().unwrap();
+    assert_eq!(varia_db::store::Corruption::find(&error).unwrap().offset, FileHeader::LEN);
+}
+
+#[test]
+fn test_invalid_varint() {
+    let cases: [(&[u8], &str); 3] = [
+        // Key length of 5 with a bit beyond 64 bits.
+        (&[0x85, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02], "Varint exceeds 64 bits"),
+        (&[0x85, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01], "Invalid varint"),
+        (&[0x85, 0x00], "Overlong varint"),
+    ];
+    for (key_len, reason) in cases {
+        let mut frame_buf: Vec<u8> = vec![frame::COMPACT_ENTRY_FRAME];
+        frame_buf.extend_from_slice(key_len);
+        frame_buf.extend_from_slice(&[0x01; 16]);
+        let buf = file(&[frame_buf]);
+        let file_len = buf.len() as u64;
+
+        let mut frames = FrameReader::new(Cursor::new(buf), file_len, FileHeader::current().version).unwrap();
+
+        let error = frames.next_header().err().unwrap();
+        let corruption = Corruption::find(&error).unwrap();
+        assert_eq!(corruption.offset, FileHeader::LEN);
+        assert_eq!(corruption.reason, reason);
+    }
+}
+
+#[test]
+fn test_compressed_entry_frame() {
+    let (compressed, saved) = frame::encode_entry_frame("test_key_1", &Value::Text("test_value_1".repeat(100)), None, Some(64), None).unwrap();
+    let (small, small_saved) = frame::encode_entry_frame("test_key_2", &Value::Number(42), None, Some(64), None).unwrap();
+    assert!(saved > 0);
+    assert_eq!(small_saved, 0);
+    assert!(compressed.len() < frame::entry_frame("test_key_1", &Value::Text("test_value_1".repeat(100))).unwrap().len());
+
+    let buf = file(&[compressed, small]);
+    let file_len = buf.len() as u64;
+    let mut frames = FrameReader::new(Cursor::new(buf), file_len, FileHeader::current().version).unwrap();
+
+    let entry = match frames.next_frame().unwrap().unwrap() {
+        Frame::Entry(entry) => entry,
+        Frame::Gap(_) => panic!("Expected entry frame"),
+    };
+    assert!(entry.intact);
+    assert!(entry.is_compressed());
+    assert_eq!(entry.saved_bytes(), saved);
+    assert_eq!(entry.value().unwrap(), Value::Text("test_value_1".repeat(100)));
+
+    let entry = match frames.next_frame().unwrap().unwrap() {
+        Frame::Entry(entry) => entry,
+        Frame::Gap(_) => panic!("Expected entry frame"),
+    };
+    assert!(!entry.is_compressed());
+    assert_eq!(entry.value().unwrap(), Value::Number(42));
+}
+
+#[test]
+fn test_encrypted_entry_frame() {
+    let cipher = Cipher::from_hex(&"a".repeat(64)).unwrap();
+    let (mut buf, _) = frame::encode_entry_frame("test_key", &Value::Text("test_value".to_string()), None, None, Some(&cipher)).unwrap();
+
+    let read = |buf: &[u8]| {
+        let buf = file(&[buf.to_vec()]);
+        let file_len = buf.len() as u64;
+        let mut frames = FrameReader::new(Cursor::new(buf), file_len, FileHeader::current().version).unwrap();
+        match frames.next_frame().unwrap().unwrap() {
+            Frame::Entry(entry) => entry,
+            Frame::Gap(_) => panic!("Expected entry frame"),
+        }
+    };
+
+    let entry = read(&buf);
+    assert!(entry.intact);
+    assert!(entry.is_encrypted());
+    assert!(entry.key().is_err());
+    let entry = entry.decrypt(Some(&cipher)).unwrap();
+    assert_eq!(entry.key().unwrap(), "test_key");
+    assert_eq!(entry.value().unwrap(), Value::Text("test_value".to_string()));
+
+    assert!(read(&buf).decrypt(None).is_err());
+    assert!(read(&buf).decrypt(Some(&Cipher::from_hex(&"b".repeat(64)).unwrap())).is_err());
+
+    // Alter the value and fix up the checksum, only the authentication tag
+    // notices.
+    let len = buf.len();
+    buf[len - 10] ^= 0xff;
+    let checksum = crc32fast::hash(&buf[..len - 4]);
+    buf[len - 4..].copy_from_slice(&checksum.to_be_bytes());
+
+    let entry = read(&buf);
+    assert!(entry.intact);
+    let error = entry.decrypt(Some(&cipher)).err().unwrap();
+    assert_eq!(Corruption::find(&error).unwrap().reason, "Authentication failed");
+}
+
+#[test]
+fn test_expiring_entry_frame() {
+    let (expiring, _) = frame::encode_entry_frame("test_key_1", &Value::Number(1), Some(1_700_000_000_000), None, None).unwrap();
+    let (permanent, _) = frame::encode_entry_frame("test_key_2", &Value::Number(2), None, None, None).unwrap();
+
+    let buf = file(&[expiring, permanent]);
+    let file_len = buf.len() as u64;
+    let mut frames = FrameReader::new(Cursor::new(buf), file_len, FileHeader::current().version).unwrap();
+
+    let entry = match frames.next_frame().unwrap().unwrap() {
+        Frame::Entry(entry) => entry,
+        Frame::Gap(_) => panic!("Expected entry frame"),
+    };
+    assert!(entry.intact);
+    assert_eq!(entry.expires_at(), Some(1_700_000_000_000));
+    assert_eq!(entry.key().unwrap(), "test_key_1");
+    assert_eq!(entry.value().unwrap(), Value::Number(1));
+
+    let entry = match frames.next_frame().unwrap().unwrap() {
+        Frame::Entry(entry) => entry,
+        Frame::Gap(_) => panic!("Expected entry frame"),
+    };
+    assert_eq!(entry.expires_at(), None);
+    assert_eq!(entry.value().unwrap(), Value::Number(2));
+}
@@ -0,0 +1,158 @@
+use std::{path::Path, thread, time::{Duration, Instant}};
+
+use varia_db::store::{Lsm, StorageBackend, Value};
+use std::fs;
+
+use super::backend_test::backend_tests;
+
+fn setup(test_name: &str) -> Lsm {
+    Lsm::new(Path::new(
+        format!("./target/tmp/lsm_test_{}", test_name).as_str(),
+    )).unwrap()
+}
+
+fn teardown(test_name: &str) {
+    fs::remove_dir_all(Path::new(
+        format!("./target/tmp/lsm_test_{}", test_name).as_str(),
+    )).unwrap();
+}
+
+/// Waits for the background compaction to bring the segments down to `segments`.
+fn wait_for_segments(lsm: &Lsm, segments: usize) {
+    let start = Instant::now();
+    while lsm.segments() > segments {
+        assert!(start.elapsed() < Duration::from_secs(10), "Compaction did not finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+backend_tests!(setup, teardown);
+
+#[test]
+fn test_flush() {
+    let mut lsm: Lsm = setup("test_flush");
+
+    lsm.put("test_key_1".to_string(), Value::Text("test_value_1".to_string())).unwrap();
+    lsm.put("test_key_2".to_string(), Value::Number(42)).unwrap();
+    lsm.flush().unwrap();
+
+    assert_eq!(lsm.segments(), 1);
+
+    lsm.put("test_key_1".to_string(), Value::Text("new_test_value_1".to_string())).unwrap();
+    lsm.del("test_key_2".to_string()).unwrap();
+    lsm.flush().unwrap();
+
+    assert_eq!(lsm.segments(), 2);
+    assert_eq!(lsm.get("test_key_1".to_string()).unwrap(), Some(Value::Text("new_test_value_1".to_string())));
+    assert_eq!(lsm.get("test_key_2".to_string()).unwrap(), None);
+    assert_eq!(lsm.list().unwrap(), vec!["test_key_1".to_string()]);
+
+    drop(lsm);
+
+    let lsm: Lsm = setup("test_flush");
+
+    assert_eq!(lsm.segments(), 2);
+    assert_eq!(lsm.get("test_key_1".to_string()).unwrap(), Some(Value::Text("new_test_value_1".to_string())));
+    assert_eq!(lsm.get("test_key_2".to_string()).unwrap(), None);
+
+    drop(lsm);
+    teardown("test_flush");
+}
+
+#[test]
+fn test_memtable_size() {
+    let mut lsm: Lsm = setup("test_memtable_size");
+    lsm.set_memtable_size(1024);
+    lsm.set_compaction_trigger(usize::MAX);
+
+    for i in 0..100 {
+        lsm.put(format!("test_key_{}", i), Value::Text("test_value".repeat(10))).unwrap();
+    }
+
+    assert!(lsm.segments() > 1);
+    assert_eq!(lsm.len().unwrap(), 100);
+    for i in 0..100 {
+        assert_eq!(lsm.get(format!("test_key_{}", i)).unwrap(), Some(Value::Text("test_value".repeat(10))));
+    }
+
+    drop(lsm);
+    teardown("test_memtable_size");
+}
+
+#[test]
+fn test_background_compaction() {
+    let mut lsm: Lsm = setup("test_background_compaction");
+    lsm.set_compaction_trigger(3);
+
+    for i in 0..3 {
+        lsm.put(format!("test_key_{}", i), Value::Number(i)).unwrap();
+        lsm.put("test_key".to_string(), Value::Number(i)).unwrap();
+        lsm.del(format!("test_key_{}", i - 1)).unwrap();
+        lsm.flush().unwrap();
+    }
+
+    wait_for_segments(&lsm, 1);
+
+    assert_eq!(lsm.get("test_key".to_string()).unwrap(), Some(Value::Number(2)));
+    assert_eq!(lsm.get("test_key_1".to_string()).unwrap(), None);
+    assert_eq!(lsm.list().unwrap(), vec!["test_key".to_string(), "test_key_2".to_string()]);
+
+    drop(lsm);
+
+    let lsm: Lsm = setup("test_background_compaction");
+
+    assert_eq!(lsm.segments(), 1);
+    assert_eq!(lsm.len().unwrap(), 2);
+
+    drop(lsm);
+    teardown("test_background_compaction");
+}
+
+#[test]
+fn test_clear_segments() {
+    let mut lsm: Lsm = setup("test_clear_segments");
+
+    lsm.put("test_key_1".to_string(), Value::Number(1)).unwrap();
+    lsm.flush().unwrap();
+    lsm.put("test_key_2".to_string(), Value::Number(2)).unwrap();
+    lsm.clear().unwrap();
+
+    assert_eq!(lsm.segments(), 0);
+    assert!(lsm.is_empty().unwrap());
+
+    drop(lsm);
+
+    let lsm: Lsm = setup("test_clear_segments");
+
+    assert!(lsm.is_empty().unwrap());
+
+    drop(lsm);
+    teardown("test_clear_segments");
+}
+
+#[test]
+fn test_corrupted_segment() {
+    let mut lsm: Lsm = setup("test_corrupted_segment");
+
+    lsm.put("test_key".to_string(), Value::Text("test_value".to_string())).unwrap();
+    lsm.flush().unwrap();
+
+    drop(lsm);
+
+    let path = fs::read_dir("./target/tmp/lsm_test_test_corrupted_segment").unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map(|extension| extension == "seg").unwrap_or(false))
+        .unwrap();
+    let mut buf = fs::read(&path).unwrap();
+    let position = buf.windows(10).position(|window| window == b"test_value").unwrap();
+    buf[position] = b'X';
+    fs::write(&path, buf).unwrap();
+
+    let lsm: Lsm = setup("test_corrupted_segment");
+
+    let error = lsm.get("test_key".to_string()).err().unwrap();
+    assert!(varia_db::store::Corruption::find(&error).is_some());
+
+    drop(lsm);
+    teardown("test_corrupted_segment");
+}
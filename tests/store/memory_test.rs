@@ -0,0 +1,88 @@
+use varia_db::store::{Memory, StorageBackend, Value};
+
+#[test]
+fn test_put_and_get() {
+    let mut memory = Memory::new();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    memory.put(key.clone(), value.clone()).unwrap();
+
+    let result = memory.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(value));
+}
+
+#[test]
+fn test_put_and_delete() {
+    let mut memory = Memory::new();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+
+    memory.put(key.clone(), value.clone()).unwrap();
+    memory.del(key.clone()).unwrap();
+
+    let result = memory.get(key.clone()).unwrap();
+
+    assert_eq!(result, None);
+}
+
+#[test]
+fn test_put_and_update() {
+    let mut memory = Memory::new();
+
+    let key = "test_key".to_string();
+    let value = Value::Text("test_value".to_string());
+    let new_value = Value::Text("new_test_value".to_string());
+
+    memory.put(key.clone(), value.clone()).unwrap();
+    memory.put(key.clone(), new_value.clone()).unwrap();
+
+    let result = memory.get(key.clone()).unwrap();
+
+    assert_eq!(result, Some(new_value));
+}
+
+#[test]
+fn test_list() {
+    let mut memory = Memory::new();
+
+    memory.put("test_key_2".to_string(), Value::Number(2)).unwrap();
+    memory.put("test_key_1".to_string(), Value::Number(1)).unwrap();
+    memory.put("test_key_2".to_string(), Value::Number(3)).unwrap();
+
+    let result = memory.list().unwrap();
+
+    assert_eq!(result, vec!["test_key_2".to_string(), "test_key_1".to_string()]);
+}
+
+#[test]
+fn test_clear() {
+    let mut memory = Memory::new();
+
+    memory.put("test_key_1".to_string(), Value::Number(1)).unwrap();
+    memory.put("test_key_2".to_string(), Value::Number(2)).unwrap();
+
+    memory.clear().unwrap();
+
+    assert_eq!(memory.list().unwrap(), Vec::<String>::new());
+    assert!(memory.is_empty().unwrap());
+}
+
+#[test]
+fn test_len() {
+    let mut memory = Memory::new();
+
+    assert_eq!(memory.len().unwrap(), 0);
+
+    memory.put("test_key_1".to_string(), Value::Number(1)).unwrap();
+    memory.put("test_key_2".to_string(), Value::Number(2)).unwrap();
+
+    assert_eq!(memory.len().unwrap(), 2);
+
+    memory.del("test_key_1".to_string()).unwrap();
+
+    assert_eq!(memory.len().unwrap(), 1);
+}
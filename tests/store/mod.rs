@@ -1,4 +1,31 @@
 
+pub mod backend_test;
+
+pub mod bloom_test;
+
 pub mod disk_test;
 
-pub mod engine_test;
\ No newline at end of file
+pub mod frame_test;
+
+pub mod memory_test;
+
+pub mod lsm_test;
+
+pub mod engine_test;
+
+pub mod data_dir_test;
+pub mod snapshot_test;
+
+pub mod ndjson_test;
+
+pub mod cache_test;
+
+pub mod weight_test;
+
+pub mod primary_test;
+
+pub mod write_back_test;
+
+pub mod expiry_test;
+
+pub mod condition_test;
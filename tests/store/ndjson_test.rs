@@ -0,0 +1,134 @@
+use moka::future::Cache;
+use varia_db::store::{ConflictPolicy, Engine, ImportFailure, ImportReport, Memory, Value, read_records};
+
+fn setup() -> Engine {
+    Engine::new(Memory::new(), Cache::new(1000))
+}
+
+async fn export_all(engine: &Engine, prefix: Option<&str>) -> Vec<u8> {
+    let keys = engine.export_keys(prefix).await.unwrap();
+    engine.export(&keys).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_export() {
+    let engine = setup();
+    engine.put("b".to_string(), Value::Number(42)).await.unwrap();
+    engine.put("a".to_string(), Value::Text("hello".to_string())).await.unwrap();
+
+    let ndjson = String::from_utf8(export_all(&engine, None).await).unwrap();
+
+    assert_eq!(ndjson, "{\"key\":\"a\",\"value\":{\"Text\":\"hello\"}}\n{\"key\":\"b\",\"value\":{\"Number\":42}}\n");
+}
+
+#[tokio::test]
+async fn test_export_prefix() {
+    let engine = setup();
+    engine.put("user1".to_string(), Value::Number(1)).await.unwrap();
+    engine.put("user2".to_string(), Value::Number(2)).await.unwrap();
+    engine.put("order1".to_string(), Value::Number(3)).await.unwrap();
+
+    let records = read_records(export_all(&engine, Some("user")).await.as_slice()).unwrap();
+
+    assert_eq!(records, vec![
+        ("user1".to_string(), Value::Number(1)),
+        ("user2".to_string(), Value::Number(2)),
+    ]);
+}
+
+#[tokio::test]
+async fn test_round_trip() {
+    let source = setup();
+    let value = Value::Map(vec![
+        ("list".to_string(), Value::Array(vec![Value::Boolean(true), Value::Number(-1)])),
+        ("text".to_string(), Value::Text("line\nbreak".to_string())),
+    ]);
+    source.put("key".to_string(), value.clone()).await.unwrap();
+
+    let target = setup();
+    let records = read_records(export_all(&source, None).await.as_slice()).unwrap();
+    let report = target.import(records, ConflictPolicy::Fail).await.unwrap();
+
+    assert_eq!(report, ImportReport { imported: 1, skipped: 0 });
+    assert_eq!(target.get("key".to_string()).await.unwrap(), Some(value));
+}
+
+#[tokio::test]
+async fn test_import_overwrite() {
+    let engine = setup();
+    engine.put("a".to_string(), Value::Number(1)).await.unwrap();
+
+    let records = vec![("a".to_string(), Value::Number(2)), ("b".to_string(), Value::Number(3))];
+    let report = engine.import(records, ConflictPolicy::Overwrite).await.unwrap();
+
+    assert_eq!(report, ImportReport { imported: 2, skipped: 0 });
+    assert_eq!(engine.get("a".to_string()).await.unwrap(), Some(Value::Number(2)));
+}
+
+#[tokio::test]
+async fn test_import_skip() {
+    let engine = setup();
+    engine.put("a".to_string(), Value::Number(1)).await.unwrap();
+
+    let records = vec![("a".to_string(), Value::Number(2)), ("b".to_string(), Value::Number(3))];
+    let report = engine.import(records, ConflictPolicy::Skip).await.unwrap();
+
+    assert_eq!(report, ImportReport { imported: 1, skipped: 1 });
+    assert_eq!(engine.get("a".to_string()).await.unwrap(), Some(Value::Number(1)));
+    assert_eq!(engine.get("b".to_string()).await.unwrap(), Some(Value::Number(3)));
+}
+
+#[tokio::test]
+async fn test_import_fail() {
+    let engine = setup();
+    engine.put("b".to_string(), Value::Number(1)).await.unwrap();
+
+    let records = vec![("a".to_string(), Value::Number(2)), ("b".to_string(), Value::Number(3))];
+    engine.import(records, ConflictPolicy::Fail).await.expect_err("Key exists");
+
+    // Nothing is imported if any key exists.
+    assert_eq!(engine.get("a".to_string()).await.unwrap(), None);
+    assert_eq!(engine.get("b".to_string()).await.unwrap(), Some(Value::Number(1)));
+}
+
+#[tokio::test]
+async fn test_import_duplicates() {
+    let engine = setup();
+
+    // The second record of a key finds it created by the first one.
+    let records = vec![("a".to_string(), Value::Number(1)), ("a".to_string(), Value::Number(2))];
+    let report = engine.import(records, ConflictPolicy::Skip).await.unwrap();
+    assert_eq!(report, ImportReport { imported: 1, skipped: 1 });
+    assert_eq!(engine.get("a".to_string()).await.unwrap(), Some(Value::Number(1)));
+
+    let records = vec![("b".to_string(), Value::Number(1)), ("c".to_string(), Value::Number(2)), ("b".to_string(), Value::Number(3))];
+    let error = engine.import(records, ConflictPolicy::Fail).await.expect_err("Key created during the import");
+    let failure = ImportFailure::find(&error).unwrap();
+    assert_eq!(failure.report, ImportReport { imported: 2, skipped: 0 });
+    assert!(error.to_string().contains("after 2 entries were imported"));
+    assert_eq!(engine.get("b".to_string()).await.unwrap(), Some(Value::Number(1)));
+}
+
+#[tokio::test]
+async fn test_import_invalid_key() {
+    let engine = setup();
+
+    let records = vec![("a".to_string(), Value::Number(1)), ("not valid".to_string(), Value::Number(2))];
+    engine.import(records, ConflictPolicy::Overwrite).await.expect_err("Invalid key");
+
+    assert_eq!(engine.list().await.unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn test_read_records() {
+    let ndjson = "{\"key\":\"a\",\"value\":{\"Number\":1}}\n\n{\"key\":\"b\",\"value\":{\"Boolean\":false}}";
+
+    let records = read_records(ndjson.as_bytes()).unwrap();
+    assert_eq!(records, vec![
+        ("a".to_string(), Value::Number(1)),
+        ("b".to_string(), Value::Boolean(false)),
+    ]);
+
+    let error = read_records("{\"key\":\"a\",\"value\":{\"Number\":1}}\n{\"key\":\"b\"}\n".as_bytes()).unwrap_err();
+    assert!(error.to_string().contains("line 2"));
+}
@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use varia_db::store::{Engine, Memory, Value, CacheMode, CachePolicy, CacheRule, Primary};
+
+fn policy(rules: &str) -> CachePolicy {
+    let mut policy = CachePolicy::default();
+    policy.set_rules(CachePolicy::parse_rules(rules).unwrap());
+    policy
+}
+
+#[test]
+fn test_parse_rules() {
+    let rules = CachePolicy::parse_rules("config=pin; feed=ttl:30,tti:10,negative:false;").unwrap();
+
+    assert_eq!(rules, vec![
+        CacheRule {
+            prefix: "config".to_string(),
+            mode: CacheMode::Pin,
+            ..CacheRule::default()
+        },
+        CacheRule {
+            prefix: "feed".to_string(),
+            mode: CacheMode::Cache,
+            ttl: Some(Duration::from_secs(30)),
+            tti: Some(Duration::from_secs(10)),
+            negative: Some(false),
+        },
+    ]);
+}
+
+#[test]
+fn test_parse_invalid_rules() {
+    assert!("config".parse::<CacheRule>().is_err());
+    assert!("config=hold".parse::<CacheRule>().is_err());
+    assert!("config=ttl:soon".parse::<CacheRule>().is_err());
+    assert!("config=negative:maybe".parse::<CacheRule>().is_err());
+    assert!("config=pin,ttl:30".parse::<CacheRule>().is_err());
+}
+
+#[test]
+fn test_longest_prefix() {
+    let policy = policy("user=skip;userconfig=pin");
+
+    assert_eq!(policy.mode("user1"), CacheMode::Skip);
+    assert_eq!(policy.mode("userconfig1"), CacheMode::Pin);
+    assert_eq!(policy.mode("other"), CacheMode::Cache);
+}
+
+#[tokio::test]
+async fn test_skip() {
+    let primary = Primary::new(1024 * 1024, policy("session=skip"));
+
+    primary.insert("session1".to_string(), Some(Value::Number(1))).await;
+    primary.insert("other".to_string(), Some(Value::Number(1))).await;
+
+    assert_eq!(primary.get("session1").await, None);
+    assert_eq!(primary.get("other").await, Some(Some(Value::Number(1))));
+}
+
+#[tokio::test]
+async fn test_pin() {
+    let primary = Primary::with_pinned_capacity(1024, 1024 * 1024, policy("config=pin"));
+
+    primary.insert("config1".to_string(), Some(Value::Text("x".repeat(4096)))).await;
+    primary.insert("other".to_string(), Some(Value::Text("x".repeat(4096)))).await;
+    let stats = primary.stats().await;
+
+    assert!(primary.get("config1").await.is_some());
+    assert!(primary.get("other").await.is_none());
+    assert_eq!(stats.pinned, 1);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.pinned_evictions, 0);
+}
+
+#[tokio::test]
+async fn test_pin_capacity() {
+    let primary = Primary::with_pinned_capacity(1024 * 1024, 16 * 1024, policy("config=pin"));
+
+    for i in 0..8 {
+        primary.insert(format!("config{}", i), Some(Value::Text("x".repeat(4096)))).await;
+    }
+    let stats = primary.stats().await;
+
+    assert!(stats.pinned < 8);
+    assert!(stats.pinned_bytes <= 16 * 1024);
+    assert_eq!(stats.pinned_capacity, 16 * 1024);
+    assert_eq!(stats.pinned_evictions, 8 - stats.pinned);
+}
+
+#[tokio::test]
+async fn test_negative_caching() {
+    let primary = Primary::new(1024 * 1024, policy("feed=negative:false"));
+
+    primary.insert("feed1".to_string(), None).await;
+    primary.insert("other".to_string(), None).await;
+
+    assert_eq!(primary.get("feed1").await, None);
+    assert_eq!(primary.get("other").await, Some(None));
+
+    let mut policy = CachePolicy::default();
+    policy.set_negative(false);
+    let primary = Primary::new(1024 * 1024, policy);
+    let value = primary.get_with("other", async { Ok((None, None)) }).await.unwrap();
+
+    assert_eq!(value, None);
+    assert_eq!(primary.get("other").await, None);
+}
+
+#[tokio::test]
+async fn test_rule_ttl() {
+    let primary = Primary::new(1024 * 1024, policy("feed=ttl:1"));
+
+    primary.insert("feed1".to_string(), Some(Value::Number(1))).await;
+    primary.insert("other".to_string(), Some(Value::Number(1))).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    assert_eq!(primary.get("feed1").await, None);
+    assert_eq!(primary.get("other").await, Some(Some(Value::Number(1))));
+    assert_eq!(primary.stats().await.expirations, 1);
+}
+
+#[tokio::test]
+async fn test_engine_stats() {
+    let engine = Engine::new(Memory::new(), Primary::new(1024 * 1024, CachePolicy::default()));
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    engine.get("key".to_string()).await.unwrap();
+    engine.get("missing".to_string()).await.unwrap();
+    engine.get("missing".to_string()).await.unwrap();
+
+    let stats = engine.stats().await;
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.entries, 2);
+}
@@ -0,0 +1,221 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use moka::future::Cache;
+use varia_db::store::{Cipher, Disk, Engine, Lsm, Memory, StorageBackend, Value, restore_snapshot, verify_snapshot};
+use std::fs;
+
+fn setup(test_name: &str) -> Engine {
+    Engine::new(
+        Disk::new(Path::new(
+            format!("./target/tmp/snapshot_test_{}.bin", test_name).as_str(),
+        )).unwrap(), Cache::new(1000),
+    )
+}
+
+fn snapshot_path(test_name: &str) -> PathBuf {
+    PathBuf::from(format!("./target/tmp/snapshot_test_{}.snapshot.bin", test_name))
+}
+
+fn teardown(test_name: &str) {
+    for suffix in [".bin", ".bin.wal", ".snapshot.bin", ".snapshot.bin.wal"] {
+        let path = format!("./target/tmp/snapshot_test_{}{}", test_name, suffix);
+        if Path::new(&path).exists() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+fn cipher(digit: char) -> Cipher {
+    Cipher::from_hex(&digit.to_string().repeat(64)).unwrap()
+}
+
+#[tokio::test]
+async fn test_snapshot() {
+    let engine = setup("test_snapshot");
+
+    engine.put("key1".to_string(), Value::Text("value1".to_string())).await.unwrap();
+    engine.put("key2".to_string(), Value::Number(42)).await.unwrap();
+    engine.del("key1".to_string()).await.unwrap();
+
+    let keys = engine.snapshot(&snapshot_path("test_snapshot")).await.unwrap();
+    assert_eq!(keys, 1);
+    assert_eq!(verify_snapshot(&snapshot_path("test_snapshot"), None).unwrap(), 1);
+
+    let snapshot = Disk::new(&snapshot_path("test_snapshot")).unwrap();
+    assert_eq!(snapshot.get("key1".to_string()).unwrap(), None);
+    assert_eq!(snapshot.get("key2".to_string()).unwrap(), Some(Value::Number(42)));
+    drop(snapshot);
+
+    teardown("test_snapshot");
+}
+
+#[tokio::test]
+async fn test_snapshot_during_writes() {
+    let engine = setup("test_snapshot_during_writes");
+
+    for i in 0..1000 {
+        engine.put(format!("key{}", i), Value::Number(i)).await.unwrap();
+    }
+
+    // The snapshot yields after its first batch, so the writes run while it
+    // copies the rest.
+    let path = snapshot_path("test_snapshot_during_writes");
+    let writes = async {
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                engine.put(format!("key{}", i), Value::Number(-i)).await.unwrap();
+            } else {
+                engine.del(format!("key{}", i)).await.unwrap();
+            }
+            engine.put(format!("new{}", i), Value::Number(i)).await.unwrap();
+        }
+    };
+    let (keys, _) = tokio::join!(engine.snapshot(&path), writes);
+    assert_eq!(keys.unwrap(), 1000);
+
+    let snapshot = Disk::new(&path).unwrap();
+    assert_eq!(snapshot.len().unwrap(), 1000);
+    for i in 0..1000 {
+        assert_eq!(snapshot.get(format!("key{}", i)).unwrap(), Some(Value::Number(i)));
+    }
+    assert_eq!(snapshot.get("new0".to_string()).unwrap(), None);
+    drop(snapshot);
+
+    teardown("test_snapshot_during_writes");
+}
+
+#[tokio::test]
+async fn test_snapshot_exists() {
+    let engine = setup("test_snapshot_exists");
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    engine.snapshot(&snapshot_path("test_snapshot_exists")).await.unwrap();
+
+    assert!(engine.snapshot(&snapshot_path("test_snapshot_exists")).await.is_err());
+
+    // A failed snapshot does not keep the next one from running.
+    fs::remove_file(snapshot_path("test_snapshot_exists")).unwrap();
+    engine.snapshot(&snapshot_path("test_snapshot_exists")).await.unwrap();
+
+    teardown("test_snapshot_exists");
+}
+
+#[tokio::test]
+async fn test_snapshot_caller_dropped() {
+    let engine = setup("test_snapshot_caller_dropped");
+    let path = snapshot_path("test_snapshot_caller_dropped");
+
+    for i in 0..2000 {
+        engine.put(format!("key{}", i), Value::Number(i)).await.unwrap();
+    }
+
+    // Like a client that disconnects while the snapshot is copied.
+    let snapshot = engine.snapshot(&path);
+    tokio::select! {
+        biased;
+        _ = snapshot => {},
+        _ = tokio::task::yield_now() => {},
+    }
+
+    // The snapshot is finished all the same, and the next one can run.
+    for _ in 0..500 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(path.exists());
+    assert_eq!(verify_snapshot(&path, None).unwrap(), 2000);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(engine.snapshot(&path).await.unwrap(), 2000);
+
+    teardown("test_snapshot_caller_dropped");
+}
+
+#[tokio::test]
+async fn test_encrypted_snapshot() {
+    let engine = Engine::new(
+        Disk::new_encrypted(Path::new("./target/tmp/snapshot_test_test_encrypted_snapshot.bin"), cipher('a')).unwrap(),
+        Cache::new(1000),
+    );
+    let path = snapshot_path("test_encrypted_snapshot");
+
+    engine.put("key".to_string(), Value::Text("secret".to_string())).await.unwrap();
+    engine.snapshot(&path).await.unwrap();
+
+    let buf = fs::read(&path).unwrap();
+    assert!(!buf.windows(6).any(|window| window == b"secret"));
+
+    assert!(verify_snapshot(&path, None).is_err());
+    assert!(verify_snapshot(&path, Some(&cipher('b'))).is_err());
+    assert_eq!(verify_snapshot(&path, Some(&cipher('a'))).unwrap(), 1);
+
+    teardown("test_encrypted_snapshot");
+}
+
+#[tokio::test]
+async fn test_restore() {
+    let engine = setup("test_restore");
+    let path = snapshot_path("test_restore");
+
+    engine.put("key1".to_string(), Value::Text("value1".to_string())).await.unwrap();
+    engine.put("key2".to_string(), Value::Number(42)).await.unwrap();
+    engine.snapshot(&path).await.unwrap();
+
+    let mut memory = Memory::new();
+    memory.put("other".to_string(), Value::Boolean(true)).unwrap();
+
+    let entries = restore_snapshot(&path, None, &mut memory).unwrap();
+
+    assert_eq!(entries, 2);
+    assert_eq!(memory.len().unwrap(), 2);
+    assert_eq!(memory.get("other".to_string()).unwrap(), None);
+    assert_eq!(memory.get("key1".to_string()).unwrap(), Some(Value::Text("value1".to_string())));
+    assert_eq!(memory.get("key2".to_string()).unwrap(), Some(Value::Number(42)));
+
+    teardown("test_restore");
+}
+
+#[tokio::test]
+async fn test_restore_corrupted() {
+    let engine = setup("test_restore_corrupted");
+    let path = snapshot_path("test_restore_corrupted");
+
+    engine.put("key".to_string(), Value::Text("value".to_string())).await.unwrap();
+    engine.snapshot(&path).await.unwrap();
+
+    let mut buf = fs::read(&path).unwrap();
+    let last = buf.len() - 1;
+    buf[last] ^= 0xff;
+    fs::write(&path, buf).unwrap();
+
+    let mut memory = Memory::new();
+    memory.put("other".to_string(), Value::Boolean(true)).unwrap();
+
+    assert!(restore_snapshot(&path, None, &mut memory).is_err());
+    assert_eq!(memory.get("other".to_string()).unwrap(), Some(Value::Boolean(true)));
+
+    teardown("test_restore_corrupted");
+}
+
+#[tokio::test]
+async fn test_restore_expiring_into_lsm() {
+    let engine = setup("test_restore_expiring_into_lsm");
+    let path = snapshot_path("test_restore_expiring_into_lsm");
+    let lsm_path = Path::new("./target/tmp/snapshot_test_test_restore_expiring_into_lsm_lsm");
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    engine.put_with_ttl("expiring".to_string(), Value::Number(2), Some(Duration::from_secs(60))).await.unwrap();
+    engine.snapshot(&path).await.unwrap();
+
+    let mut lsm = Lsm::new(lsm_path).unwrap();
+    lsm.put("other".to_string(), Value::Boolean(true)).unwrap();
+
+    let error = restore_snapshot(&path, None, &mut lsm).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    assert_eq!(lsm.list().unwrap(), vec!["other".to_string()]);
+
+    drop(lsm);
+    fs::remove_dir_all(lsm_path).unwrap();
+    teardown("test_restore_expiring_into_lsm");
+}
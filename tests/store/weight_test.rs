@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use varia_db::{setup::setup_primary, store::{Value, CachePolicy, weight}};
+
+#[test]
+fn test_weight_text() {
+    let small = weight(&"key".to_string(), &Some(Value::Text("value".to_string())));
+    let large = weight(&"key".to_string(), &Some(Value::Text("x".repeat(5 * 1024 * 1024))));
+
+    assert!(small < 1024);
+    assert!(large >= 5 * 1024 * 1024);
+    assert!(large < 5 * 1024 * 1024 + 1024);
+}
+
+#[test]
+fn test_weight_nested() {
+    let text = Value::Text("x".repeat(1000));
+    let nested = Value::Map(vec![
+        ("list".to_string(), Value::Array(vec![text.clone(), text.clone(), Value::Number(1)])),
+        ("text".to_string(), text.clone()),
+    ]);
+
+    let weight_of_text = weight(&"key".to_string(), &Some(text));
+    let weight_of_nested = weight(&"key".to_string(), &Some(nested));
+    assert!(weight_of_nested > 3 * weight_of_text);
+}
+
+#[test]
+fn test_weight_key() {
+    let short = weight(&"k".to_string(), &None);
+    let long = weight(&"k".repeat(1000), &None);
+
+    assert!(long >= short + 999);
+}
+
+#[tokio::test]
+async fn test_cache_size_in_megabytes() {
+    let cache = setup_primary(1, 1, CachePolicy::new(Duration::from_secs(60), Duration::from_secs(60)));
+
+    cache.insert("small".to_string(), Some(Value::Text("x".repeat(1024)))).await;
+    cache.insert("large".to_string(), Some(Value::Text("x".repeat(2 * 1024 * 1024)))).await;
+    let stats = cache.stats().await;
+
+    assert!(cache.get("small").await.is_some());
+    assert!(cache.get("large").await.is_none());
+    assert!(stats.bytes <= 1024 * 1024);
+    assert_eq!(stats.evictions, 1);
+}
@@ -0,0 +1,206 @@
+use std::{fs, io::Error, path::Path, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+use moka::future::Cache;
+use varia_db::store::{Disk, Engine, Memory, StorageBackend, Value};
+
+/// Counts the writes that reach the secondary storage.
+struct Counting {
+    inner: Memory,
+    writes: Arc<AtomicUsize>,
+}
+
+impl StorageBackend for Counting {
+    fn put(&mut self, key: String, value: Value) -> Result<(), Error> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<Value>, Error> {
+        self.inner.get(key)
+    }
+
+    fn del(&mut self, key: String) -> Result<(), Error> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.del(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.inner.list()
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.inner.clear()
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        self.inner.len()
+    }
+}
+
+/// Builds an engine in write-back mode that only flushes when asked to, or
+/// when `limit` keys are dirty. Nothing is cached, so reads of unflushed
+/// writes cannot be served by the cache.
+fn setup(limit: usize) -> (Engine, Arc<AtomicUsize>) {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let mut engine = Engine::new(Counting { inner: Memory::new(), writes: writes.clone() }, Cache::new(0));
+    engine.set_write_back(Duration::from_secs(3600), limit);
+    (engine, writes)
+}
+
+#[tokio::test]
+async fn test_write_back() {
+    let (engine, writes) = setup(1024);
+
+    assert_eq!(engine.put("key".to_string(), Value::Number(1)).await.unwrap(), None);
+    assert_eq!(engine.put("key".to_string(), Value::Number(2)).await.unwrap(), Some(Value::Number(1)));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Number(2)));
+    assert_eq!(writes.load(Ordering::SeqCst), 0);
+
+    assert_eq!(engine.flush().await.unwrap(), 1);
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), Some(Value::Number(2)));
+
+    assert_eq!(engine.del("key".to_string()).await.unwrap(), Some(Value::Number(2)));
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+    assert_eq!(engine.del("key".to_string()).await.unwrap(), None);
+
+    assert_eq!(engine.flush().await.unwrap(), 1);
+    assert_eq!(writes.load(Ordering::SeqCst), 2);
+    assert_eq!(engine.flush().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_flush_on_limit() {
+    let (engine, writes) = setup(8);
+
+    for i in 0..8 {
+        engine.put(format!("key{}", i), Value::Number(i)).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(writes.load(Ordering::SeqCst), 8);
+    assert_eq!(engine.stats().await.unflushed, 0);
+}
+
+#[tokio::test]
+async fn test_flush_on_interval() {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let mut engine = Engine::new(Counting { inner: Memory::new(), writes: writes.clone() }, Cache::new(0));
+    engine.set_write_back(Duration::from_millis(50), 1024);
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_unflushed_stats() {
+    let (engine, _) = setup(1024);
+
+    engine.put("key1".to_string(), Value::Text("x".repeat(1024))).await.unwrap();
+    engine.put("key2".to_string(), Value::Number(1)).await.unwrap();
+
+    let stats = engine.stats().await;
+    assert_eq!(stats.unflushed, 2);
+    assert!(stats.unflushed_bytes >= 1024);
+
+    engine.flush().await.unwrap();
+
+    let stats = engine.stats().await;
+    assert_eq!(stats.unflushed, 0);
+    assert_eq!(stats.unflushed_bytes, 0);
+}
+
+#[tokio::test]
+async fn test_list_flushes() {
+    let (engine, _) = setup(1024);
+
+    engine.put("key1".to_string(), Value::Number(1)).await.unwrap();
+    engine.put("key2".to_string(), Value::Number(2)).await.unwrap();
+    engine.del("key1".to_string()).await.unwrap();
+
+    assert_eq!(engine.list().await.unwrap(), vec!["key2".to_string()]);
+    assert_eq!(engine.stats().await.unflushed, 0);
+}
+
+#[tokio::test]
+async fn test_clear_discards() {
+    let (engine, writes) = setup(1024);
+
+    engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+    engine.clear().await.unwrap();
+
+    assert_eq!(engine.get("key".to_string()).await.unwrap(), None);
+    assert_eq!(engine.flush().await.unwrap(), 0);
+    assert_eq!(writes.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_writes_during_flush() {
+    let (engine, _) = setup(16);
+
+    let mut handles = Vec::new();
+    for task in 0..4 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for i in 0..100 {
+                engine.put(format!("key{}", i % 10), Value::Number(task * 1000 + i)).await.unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    // Whatever the flusher wrote meanwhile, the last writes win.
+    let mut expected = Vec::new();
+    for i in 0..10 {
+        expected.push(engine.get(format!("key{}", i)).await.unwrap());
+    }
+    engine.flush().await.unwrap();
+    let export = engine.export(&(0..10).map(|i| format!("key{}", i)).collect::<Vec<String>>()).await.unwrap();
+    let stored: Vec<Option<Value>> = varia_db::store::read_records(export.as_slice()).unwrap()
+        .into_iter()
+        .map(|(_, value)| Some(value))
+        .collect();
+    assert_eq!(stored, expected);
+}
+
+#[tokio::test]
+async fn test_flush_survives_restart() {
+    let path = Path::new("./target/tmp/write_back_test_test_flush_survives_restart.bin");
+    {
+        let mut engine = Engine::new(Disk::new(path).unwrap(), Cache::new(1000));
+        engine.set_write_back(Duration::from_secs(3600), 1024);
+        engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+        engine.flush().await.unwrap();
+    }
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.get("key".to_string()).unwrap(), Some(Value::Number(1)));
+    drop(disk);
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(path.with_extension("bin.wal")).unwrap();
+}
+
+#[tokio::test]
+async fn test_flush_on_drop() {
+    let path = Path::new("./target/tmp/write_back_test_test_flush_on_drop.bin");
+    {
+        let mut engine = Engine::new(Disk::new(path).unwrap(), Cache::new(1000));
+        engine.set_write_back(Duration::from_secs(3600), 1024);
+        engine.put("key".to_string(), Value::Number(1)).await.unwrap();
+        engine.put("other".to_string(), Value::Number(2)).await.unwrap();
+        engine.del("other".to_string()).await.unwrap();
+    }
+
+    let disk = Disk::new(path).unwrap();
+    assert_eq!(disk.get("key".to_string()).unwrap(), Some(Value::Number(1)));
+    assert_eq!(disk.get("other".to_string()).unwrap(), None);
+    drop(disk);
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(path.with_extension("bin.wal")).unwrap();
+}